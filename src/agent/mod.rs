@@ -0,0 +1,372 @@
+//! Credential agent
+//!
+//! Similar to ssh-agent, the agent is a background process that keeps the
+//! unlocked vault key in memory and hands it out over a Unix domain socket,
+//! so the master passphrase only has to be typed once per session. The agent
+//! exits after a configurable idle period.
+//!
+//! The protocol is one request line per connection, answered with one line:
+//!
+//! - `GET` -> `OK <base64 key>` or `LOCKED`
+//! - `PUT <base64 key>` -> `OK`, or `LOCKED` after a `LOCK`
+//! - `UNLOCK <base64 key>` -> `OK` (take the key even after a `LOCK`)
+//! - `LOCK` -> `OK` (forget the key but keep running)
+//! - `STOP` -> `OK` (forget the key and exit)
+//!
+//! `PUT` is how other commands hand over a key they just unlocked; an agent
+//! the user locked stays locked until `ali-bastion agent` unlocks it.
+//!
+//! Only processes of the agent's own user are answered.
+
+use base64::{engine::general_purpose, Engine as _};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::utils::encryption::Key;
+
+/// How long a client waits for the agent to answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the server checks for the idle timeout while waiting for clients
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// State of the agent as seen by a client
+#[derive(Debug, PartialEq)]
+pub enum AgentStatus {
+    /// The agent holds the key
+    Unlocked(Key),
+    /// The agent is running but has no key
+    Locked,
+    /// No agent is listening on the socket
+    NotRunning,
+}
+
+pub fn get_socket_path() -> PathBuf {
+    let mut socket_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    socket_path.push(".ali-bastion");
+    socket_path.push("agent.sock");
+    socket_path
+}
+
+/// Serve requests on `socket_path` until stopped or idle for `idle_timeout`
+pub fn serve(socket_path: &Path, key: Option<Key>, idle_timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let listener = bind(socket_path)?;
+    listener.set_nonblocking(true)?;
+
+    let mut state = State { key, locked: false };
+    let mut last_activity = Instant::now();
+
+    loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                last_activity = Instant::now();
+                // A misbehaving client must not bring the agent down
+                match handle_client(stream, &mut state) {
+                    Ok(true) => break,
+                    Ok(false) => {}
+                    Err(e) => eprintln!("agent: {}", e),
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if last_activity.elapsed() >= idle_timeout {
+                    break;
+                }
+                thread::sleep(POLL_INTERVAL);
+            }
+            Err(e) => {
+                let _ = fs::remove_file(socket_path);
+                return Err(e.into());
+            }
+        }
+    }
+
+    let _ = fs::remove_file(socket_path);
+    Ok(())
+}
+
+/// What the agent holds
+struct State {
+    key: Option<Key>,
+    /// Locked by the user, so offered keys are refused
+    locked: bool,
+}
+
+/// Bind the listening socket, replacing a stale socket left by a dead agent
+fn bind(socket_path: &Path) -> Result<UnixListener, Box<dyn std::error::Error>> {
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(format!("An agent is already running on {}", socket_path.display()).into());
+        }
+        fs::remove_file(socket_path)?;
+    }
+
+    let parent = socket_path.parent().unwrap_or(Path::new("."));
+    fs::create_dir_all(parent)?;
+
+    // Bind inside a directory only we can enter and move the socket into
+    // place once it is private, so no one else can connect in between
+    let staging = parent.join(format!(".agent-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("agent.sock");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&staged, socket_path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    Ok(bound?)
+}
+
+/// User id of the process at the other end of `stream`
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred { pid: 0, uid: 0, gid: 0 };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&raw mut credentials).cast(),
+            &mut length,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(credentials.uid)
+}
+
+/// User id of the process at the other end of `stream`
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+/// Answer a single request. Returns true if the agent should stop.
+fn handle_client(stream: UnixStream, state: &mut State) -> Result<bool, Box<dyn std::error::Error>> {
+    let uid = peer_uid(&stream)?;
+    if uid != unsafe { libc::geteuid() } {
+        return Err(format!("refused a connection from uid {}", uid).into());
+    }
+
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let request = request.trim_end();
+
+    // Liveness probes connect and hang up without sending anything
+    if request.is_empty() {
+        return Ok(false);
+    }
+
+    let mut stop = false;
+    let response = match request.split_once(' ').unwrap_or((request, "")) {
+        ("GET", _) => match state.key {
+            Some(ref k) => format!("OK {}", encode_key(k)),
+            None => "LOCKED".to_string(),
+        },
+        ("PUT", _) if state.locked => "LOCKED".to_string(),
+        ("PUT" | "UNLOCK", encoded) => match decode_key(encoded) {
+            Ok(k) => {
+                *state = State { key: Some(k), locked: false };
+                "OK".to_string()
+            }
+            Err(e) => format!("ERR {}", e),
+        },
+        ("LOCK", _) => {
+            *state = State { key: None, locked: true };
+            "OK".to_string()
+        }
+        ("STOP", _) => {
+            state.key = None;
+            stop = true;
+            "OK".to_string()
+        }
+        _ => "ERR unknown request".to_string(),
+    };
+
+    let mut stream = &stream;
+    writeln!(stream, "{}", response)?;
+    Ok(stop)
+}
+
+/// Encode a key for transport over the socket or a pipe
+pub fn encode_key(key: &Key) -> String {
+    general_purpose::STANDARD.encode(key)
+}
+
+/// Decode a key produced by `encode_key`
+pub fn decode_key(encoded: &str) -> Result<Key, Box<dyn std::error::Error>> {
+    general_purpose::STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| "key has the wrong length".into())
+}
+
+/// Send one request to the agent and return its reply, or `None` if no
+/// agent is listening
+fn send_request(socket_path: &Path, request: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let mut stream = match UnixStream::connect(socket_path) {
+        Ok(stream) => stream,
+        Err(_) => return Ok(None),
+    };
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    writeln!(stream, "{}", request)?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    let response = response.trim_end().to_string();
+
+    if let Some(message) = response.strip_prefix("ERR ") {
+        return Err(format!("Agent error: {}", message).into());
+    }
+    Ok(Some(response))
+}
+
+/// Ask the agent for the vault key
+pub fn request_key(socket_path: &Path) -> Result<AgentStatus, Box<dyn std::error::Error>> {
+    match send_request(socket_path, "GET")? {
+        None => Ok(AgentStatus::NotRunning),
+        Some(response) if response == "LOCKED" => Ok(AgentStatus::Locked),
+        Some(response) => match response.strip_prefix("OK ") {
+            Some(encoded) => Ok(AgentStatus::Unlocked(decode_key(encoded)?)),
+            None => Err(format!("Unexpected agent response '{}'", response).into()),
+        },
+    }
+}
+
+/// Offer a key to a running agent. Returns false if no agent is running or
+/// the user locked it.
+pub fn store_key(socket_path: &Path, key: &Key) -> Result<bool, Box<dyn std::error::Error>> {
+    let request = format!("PUT {}", encode_key(key));
+    Ok(send_request(socket_path, &request)?.is_some_and(|response| response == "OK"))
+}
+
+/// Give a running agent the key, also if the user locked it. Returns false
+/// if no agent is running.
+pub fn unlock(socket_path: &Path, key: &Key) -> Result<bool, Box<dyn std::error::Error>> {
+    let request = format!("UNLOCK {}", encode_key(key));
+    Ok(send_request(socket_path, &request)?.is_some())
+}
+
+/// Make the agent forget its key. Returns false if no agent is running.
+pub fn lock(socket_path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(send_request(socket_path, "LOCK")?.is_some())
+}
+
+/// Stop the agent. Returns false if no agent is running.
+pub fn stop(socket_path: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    Ok(send_request(socket_path, "STOP")?.is_some())
+}
+
+/// Wait until an agent answers on `socket_path`
+pub fn wait_until_ready(socket_path: &Path, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if UnixStream::connect(socket_path).is_ok() {
+            return true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn_agent(socket_path: &Path, key: Option<Key>, idle_timeout: Duration) -> thread::JoinHandle<()> {
+        let path = socket_path.to_path_buf();
+        let handle = thread::spawn(move || serve(&path, key, idle_timeout).unwrap());
+        assert!(wait_until_ready(socket_path, Duration::from_secs(5)));
+        handle
+    }
+
+    #[test]
+    fn test_no_agent_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::NotRunning);
+        assert!(!lock(&socket_path).unwrap());
+    }
+
+    #[test]
+    fn test_get_lock_put_stop() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+        let handle = spawn_agent(&socket_path, Some([7; 32]), Duration::from_secs(60));
+
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::Unlocked([7; 32]));
+
+        assert!(store_key(&socket_path, &[8; 32]).unwrap());
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::Unlocked([8; 32]));
+
+        assert!(lock(&socket_path).unwrap());
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::Locked);
+
+        // A locked agent is not re-armed by keys other commands offer
+        assert!(!store_key(&socket_path, &[9; 32]).unwrap());
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::Locked);
+
+        assert!(unlock(&socket_path, &[9; 32]).unwrap());
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::Unlocked([9; 32]));
+
+        assert!(stop(&socket_path).unwrap());
+        handle.join().unwrap();
+        assert!(!socket_path.exists());
+        assert_eq!(request_key(&socket_path).unwrap(), AgentStatus::NotRunning);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+        let handle = spawn_agent(&socket_path, Some([7; 32]), Duration::from_millis(300));
+
+        handle.join().unwrap();
+        assert!(!socket_path.exists());
+    }
+
+    #[test]
+    fn test_socket_is_private() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+        let handle = spawn_agent(&socket_path, None, Duration::from_secs(60));
+
+        assert_eq!(fs::metadata(&socket_path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        stop(&socket_path).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_refuses_second_agent() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("agent.sock");
+        let handle = spawn_agent(&socket_path, None, Duration::from_secs(60));
+
+        let result = serve(&socket_path, None, Duration::from_secs(60));
+        assert!(result.unwrap_err().to_string().contains("already running"));
+
+        stop(&socket_path).unwrap();
+        handle.join().unwrap();
+    }
+}
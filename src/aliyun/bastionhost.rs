@@ -0,0 +1,269 @@
+//! The Bastionhost OpenAPI (version 2019-12-09): the hosts of an instance
+//! and the accounts they can be logged into with
+
+use serde_json::Value;
+
+use super::Client;
+
+pub const VERSION: &str = "2019-12-09";
+
+/// The largest page the List actions return
+const PAGE_SIZE: u64 = 100;
+
+/// Endpoint of the API in `region`
+pub fn endpoint(region: &str) -> String {
+    format!("https://yundun-bastionhost.{}.aliyuncs.com", region)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Host {
+    pub id: String,
+    pub name: String,
+    /// The address the bastion connects to: public or private, as the host
+    /// is configured
+    pub address: String,
+    pub os: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostAccount {
+    pub name: String,
+    /// `SSH` or `RDP`
+    pub protocol: String,
+}
+
+impl HostAccount {
+    pub fn is_ssh(&self) -> bool {
+        self.protocol.eq_ignore_ascii_case("SSH")
+    }
+}
+
+/// A host and the accounts it can be logged into with
+pub type HostWithAccounts = (Host, Vec<HostAccount>);
+
+/// One bastion instance
+pub struct Bastionhost {
+    client: Client,
+    instance: String,
+    region: String,
+}
+
+impl Bastionhost {
+    pub fn new(client: Client, instance: &str, region: &str) -> Self {
+        Bastionhost { client, instance: instance.to_string(), region: region.to_string() }
+    }
+
+    pub fn list_hosts(&self) -> Result<Vec<Host>, Box<dyn std::error::Error>> {
+        let hosts = self.list("ListHosts", &[], "Hosts")?;
+        Ok(hosts.iter().map(parse_host).collect())
+    }
+
+    pub fn list_host_accounts(&self, host_id: &str) -> Result<Vec<HostAccount>, Box<dyn std::error::Error>> {
+        let accounts = self.list("ListHostAccounts", &[("HostId", host_id)], "HostAccounts")?;
+        Ok(accounts
+            .iter()
+            .map(|account| HostAccount { name: text(&account["HostAccountName"]), protocol: text(&account["ProtocolName"]) })
+            .collect())
+    }
+
+    /// Every host with its accounts
+    pub fn hosts_with_accounts(&self) -> Result<Vec<HostWithAccounts>, Box<dyn std::error::Error>> {
+        self.list_hosts()?
+            .into_iter()
+            .map(|host| {
+                let accounts = self.list_host_accounts(&host.id)?;
+                Ok((host, accounts))
+            })
+            .collect()
+    }
+
+    /// The `field` items of every page of `action`
+    fn list(&self, action: &str, params: &[(&str, &str)], field: &str) -> Result<Vec<Value>, Box<dyn std::error::Error>> {
+        let mut items = Vec::new();
+        for page in 1.. {
+            let page = page.to_string();
+            let page_size = PAGE_SIZE.to_string();
+            let mut query = vec![
+                ("RegionId", self.region.as_str()),
+                ("InstanceId", self.instance.as_str()),
+                ("PageNumber", page.as_str()),
+                ("PageSize", page_size.as_str()),
+            ];
+            query.extend_from_slice(params);
+            let response = self.client.call(action, &query)?;
+            let Some(page_items) = response[field].as_array().filter(|page_items| !page_items.is_empty()) else {
+                break;
+            };
+            items.extend(page_items.iter().cloned());
+            let total = text(&response["TotalCount"]).parse::<usize>().unwrap_or(0);
+            if items.len() >= total {
+                break;
+            }
+        }
+        Ok(items)
+    }
+}
+
+fn parse_host(host: &Value) -> Host {
+    let private = text(&host["HostPrivateAddress"]);
+    let public = text(&host["HostPublicAddress"]);
+    let (active, other) = if text(&host["ActiveAddressType"]).eq_ignore_ascii_case("Public") { (public, private) } else { (private, public) };
+    Host {
+        id: text(&host["HostId"]),
+        name: text(&host["HostName"]),
+        address: if active.is_empty() { other } else { active },
+        os: text(&host["OSType"]),
+    }
+}
+
+/// A string or number field as text; empty when missing
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        Value::Number(value) => value.to_string(),
+        _ => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aliyun::{signature, Credentials};
+    use crate::utils::secret::Secret;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    type Requests = Arc<Mutex<Vec<HashMap<String, String>>>>;
+
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = Vec::new();
+        let mut index = 0;
+        while index < bytes.len() {
+            if bytes[index] == b'%' {
+                decoded.push(u8::from_str_radix(&value[index + 1..index + 3], 16).unwrap());
+                index += 3;
+            } else {
+                decoded.push(bytes[index]);
+                index += 1;
+            }
+        }
+        String::from_utf8(decoded).unwrap()
+    }
+
+    /// A local stand-in for the API that checks each request's signature
+    /// against `secret` and answers with `respond(params)`. Returns its URL
+    /// and the parameters of the requests it served.
+    fn mock_api(secret: &'static str, respond: fn(&HashMap<String, String>) -> Value) -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Requests::default();
+        let served = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+
+                let query = request_line.split_whitespace().nth(1).unwrap_or("").trim_start_matches("/?");
+                let mut params: Vec<(String, String)> = query
+                    .split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .map(|(name, value)| (percent_decode(name), percent_decode(value)))
+                    .collect();
+                let position = params.iter().position(|(name, _)| name == "Signature");
+                let signature = position.map(|position| params.remove(position).1);
+                let (status, body) = if signature.as_deref() == Some(signature::sign("GET", &params, secret).as_str()) {
+                    let params: HashMap<String, String> = params.into_iter().collect();
+                    let body = respond(&params);
+                    served.lock().unwrap().push(params);
+                    (200, body)
+                } else {
+                    (400, serde_json::json!({"Code": "SignatureDoesNotMatch", "Message": "The request signature does not conform to Aliyun standards."}))
+                };
+                let body = body.to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    fn bastionhost(url: &str, secret: &str) -> Bastionhost {
+        let credentials = Credentials { access_key_id: "LTAItest".to_string(), access_key_secret: Secret::from(secret), region: None };
+        Bastionhost::new(Client::new(url, VERSION, credentials), "bastionhost-cn-test", "cn-hangzhou")
+    }
+
+    /// Three hosts over two pages of two, and accounts for host 1
+    fn respond(params: &HashMap<String, String>) -> Value {
+        let page = &params["PageNumber"];
+        match (params["Action"].as_str(), page.as_str()) {
+            ("ListHosts", "1") => serde_json::json!({
+                "TotalCount": 3,
+                "Hosts": [
+                    {"HostId": "1", "HostName": "web-1", "ActiveAddressType": "Private", "HostPrivateAddress": "10.0.0.1", "HostPublicAddress": "47.0.0.1", "OSType": "Linux"},
+                    {"HostId": 2, "HostName": "web-2", "ActiveAddressType": "Public", "HostPrivateAddress": "10.0.0.2", "HostPublicAddress": "47.0.0.2", "OSType": "Linux"}
+                ]
+            }),
+            ("ListHosts", _) => serde_json::json!({
+                "TotalCount": 3,
+                "Hosts": [{"HostId": "3", "HostName": "win", "ActiveAddressType": "Public", "HostPrivateAddress": "10.0.0.3", "HostPublicAddress": "", "OSType": "Windows"}]
+            }),
+            ("ListHostAccounts", _) if params["HostId"] == "1" => serde_json::json!({
+                "TotalCount": "2",
+                "HostAccounts": [{"HostAccountName": "root", "ProtocolName": "SSH"}, {"HostAccountName": "Administrator", "ProtocolName": "RDP"}]
+            }),
+            _ => serde_json::json!({"TotalCount": 0, "HostAccounts": []}),
+        }
+    }
+
+    #[test]
+    fn test_list_hosts_pages_through_results() {
+        let (url, requests) = mock_api("s3cret", respond);
+        let hosts = bastionhost(&url, "s3cret").list_hosts().unwrap();
+
+        let addresses: Vec<(&str, &str)> = hosts.iter().map(|host| (host.id.as_str(), host.address.as_str())).collect();
+        assert_eq!(addresses, [("1", "10.0.0.1"), ("2", "47.0.0.2"), ("3", "10.0.0.3")]);
+        assert_eq!(hosts[2].os, "Windows");
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0]["InstanceId"], "bastionhost-cn-test");
+        assert_eq!(requests[0]["RegionId"], "cn-hangzhou");
+        assert_eq!(requests[0]["Version"], VERSION);
+        assert_eq!(requests[1]["PageNumber"], "2");
+    }
+
+    #[test]
+    fn test_hosts_with_accounts() {
+        let (url, _) = mock_api("s3cret", respond);
+        let hosts = bastionhost(&url, "s3cret").hosts_with_accounts().unwrap();
+
+        assert_eq!(hosts.len(), 3);
+        let accounts = &hosts[0].1;
+        assert_eq!(accounts[0], HostAccount { name: "root".to_string(), protocol: "SSH".to_string() });
+        assert!(!accounts[1].is_ssh());
+        assert!(hosts[1].1.is_empty());
+    }
+
+    #[test]
+    fn test_rejected_signature_is_reported() {
+        let (url, requests) = mock_api("s3cret", respond);
+        let error = bastionhost(&url, "wrong").list_hosts().unwrap_err();
+
+        assert!(error.to_string().starts_with("ListHosts failed with SignatureDoesNotMatch"), "{}", error);
+        assert!(requests.lock().unwrap().is_empty());
+    }
+}
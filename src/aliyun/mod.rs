@@ -0,0 +1,188 @@
+//! Alibaba Cloud OpenAPI access
+//!
+//! Requests are signed with an AccessKey taken from a profile of the aliyun
+//! CLI (`~/.aliyun/config.json`), or from the `ALIBABA_CLOUD_ACCESS_KEY_ID`
+//! and `ALIBABA_CLOUD_ACCESS_KEY_SECRET` variables when no profile is named.
+
+pub mod bastionhost;
+pub mod signature;
+pub mod sync;
+
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::utils::secret::Secret;
+
+const ACCESS_KEY_ID_ENV: &str = "ALIBABA_CLOUD_ACCESS_KEY_ID";
+const ACCESS_KEY_SECRET_ENV: &str = "ALIBABA_CLOUD_ACCESS_KEY_SECRET";
+
+/// How long a single API request may take
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Credentials {
+    pub access_key_id: String,
+    pub access_key_secret: Secret,
+    /// Default region of the profile
+    pub region: Option<String>,
+}
+
+/// The parts of the aliyun CLI configuration we read
+#[derive(Deserialize)]
+struct CliConfig {
+    #[serde(default)]
+    current: String,
+    #[serde(default)]
+    profiles: Vec<CliProfile>,
+}
+
+#[derive(Deserialize)]
+struct CliProfile {
+    name: String,
+    #[serde(default)]
+    mode: String,
+    #[serde(default)]
+    access_key_id: String,
+    #[serde(default)]
+    access_key_secret: String,
+    #[serde(default)]
+    region_id: String,
+}
+
+pub fn get_cli_config_path() -> PathBuf {
+    let mut config_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    config_path.push(".aliyun");
+    config_path.push("config.json");
+    config_path
+}
+
+/// The AccessKey of profile `name` in the aliyun CLI configuration at
+/// `path`. Without a name the environment is tried first, then the CLI's
+/// current profile.
+pub fn load_credentials(path: &Path, name: Option<&str>) -> Result<Credentials, Box<dyn std::error::Error>> {
+    if name.is_none()
+        && let (Ok(access_key_id), Ok(secret)) = (std::env::var(ACCESS_KEY_ID_ENV), std::env::var(ACCESS_KEY_SECRET_ENV))
+    {
+        return Ok(Credentials { access_key_id, access_key_secret: Secret::new(secret), region: None });
+    }
+
+    let content = fs::read_to_string(path).map_err(|e| {
+        format!("Failed to read {}: {}. Configure a profile with 'aliyun configure' or set {} and {}.", path.display(), e, ACCESS_KEY_ID_ENV, ACCESS_KEY_SECRET_ENV)
+    })?;
+    let config: CliConfig = serde_json::from_str(&content).map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+    let name = name.unwrap_or(&config.current);
+    let profile = config
+        .profiles
+        .into_iter()
+        .find(|profile| profile.name == name)
+        .ok_or_else(|| format!("No profile '{}' in {}", name, path.display()))?;
+
+    // Other modes (STS tokens, RAM roles, ...) need more than a signature
+    if !profile.mode.is_empty() && profile.mode != "AK" {
+        return Err(format!("Profile '{}' uses {} credentials; only AccessKey (AK) profiles are supported", name, profile.mode).into());
+    }
+    if profile.access_key_id.is_empty() || profile.access_key_secret.is_empty() {
+        return Err(format!("Profile '{}' has no AccessKey", name).into());
+    }
+    Ok(Credentials {
+        access_key_id: profile.access_key_id,
+        access_key_secret: Secret::new(profile.access_key_secret),
+        region: Some(profile.region_id).filter(|region| !region.is_empty()),
+    })
+}
+
+/// Client for one RPC-style API
+pub struct Client {
+    endpoint: String,
+    version: String,
+    credentials: Credentials,
+    agent: ureq::Agent,
+}
+
+impl Client {
+    /// `endpoint` is the base URL, such as
+    /// `https://yundun-bastionhost.cn-hangzhou.aliyuncs.com`
+    pub fn new(endpoint: &str, version: &str, credentials: Credentials) -> Self {
+        Client {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            version: version.to_string(),
+            credentials,
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        }
+    }
+
+    /// Call `action` with `params` and return the JSON response
+    pub fn call(&self, action: &str, params: &[(&str, &str)]) -> Result<serde_json::Value, Box<dyn std::error::Error>> {
+        let mut query: Vec<(String, String)> = vec![
+            ("Action".to_string(), action.to_string()),
+            ("Format".to_string(), "JSON".to_string()),
+            ("Version".to_string(), self.version.clone()),
+            ("AccessKeyId".to_string(), self.credentials.access_key_id.clone()),
+            ("SignatureMethod".to_string(), "HMAC-SHA1".to_string()),
+            ("SignatureVersion".to_string(), "1.0".to_string()),
+            ("SignatureNonce".to_string(), format!("{:032x}", rand::random::<u128>())),
+            ("Timestamp".to_string(), signature::format_timestamp(crate::utils::now())),
+        ];
+        query.extend(params.iter().map(|(name, value)| (name.to_string(), value.to_string())));
+        let signed = signature::sign("GET", &query, self.credentials.access_key_secret.expose());
+        query.push(("Signature".to_string(), signed));
+
+        let url = format!("{}/?{}", self.endpoint, signature::canonical_query(&query));
+        let body = match self.agent.get(&url).call() {
+            Ok(response) => response.into_string()?,
+            Err(ureq::Error::Status(status, response)) => {
+                let body = response.into_string().unwrap_or_default();
+                return Err(api_error(action, status, &body).into());
+            }
+            Err(e) => return Err(format!("{} failed: {}", action, e).into()),
+        };
+        serde_json::from_str(&body).map_err(|e| format!("{} returned invalid JSON: {}", action, e).into())
+    }
+}
+
+/// The API's error code and message, or the raw body if it has none
+fn api_error(action: &str, status: u16, body: &str) -> String {
+    let error: serde_json::Value = serde_json::from_str(body).unwrap_or_default();
+    match (error["Code"].as_str(), error["Message"].as_str()) {
+        (Some(code), Some(message)) => format!("{} failed with {}: {}", action, code, message),
+        _ => format!("{} failed with HTTP {}: {}", action, status, body.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLI_CONFIG: &str = r#"{
+        "current": "prod",
+        "profiles": [
+            {"name": "prod", "mode": "AK", "access_key_id": "LTAIprod", "access_key_secret": "s3cret", "region_id": "cn-hangzhou"},
+            {"name": "sso", "mode": "CloudSSO", "access_key_id": "", "access_key_secret": "", "region_id": ""}
+        ]
+    }"#;
+
+    #[test]
+    fn test_load_credentials_from_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        fs::write(&path, CLI_CONFIG).unwrap();
+
+        let credentials = load_credentials(&path, Some("prod")).unwrap();
+        assert_eq!(credentials.access_key_id, "LTAIprod");
+        assert_eq!(credentials.access_key_secret.expose(), "s3cret");
+        assert_eq!(credentials.region.as_deref(), Some("cn-hangzhou"));
+
+        assert!(load_credentials(&path, Some("sso")).err().unwrap().to_string().contains("CloudSSO"));
+        assert!(load_credentials(&path, Some("missing")).err().unwrap().to_string().contains("No profile 'missing'"));
+    }
+
+    #[test]
+    fn test_api_error() {
+        assert_eq!(
+            api_error("ListHosts", 404, r#"{"Code":"InstanceNotFound","Message":"The instance does not exist.","RequestId":"x"}"#),
+            "ListHosts failed with InstanceNotFound: The instance does not exist."
+        );
+        assert_eq!(api_error("ListHosts", 502, "Bad Gateway\n"), "ListHosts failed with HTTP 502: Bad Gateway");
+    }
+}
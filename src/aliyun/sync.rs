@@ -0,0 +1,305 @@
+//! Reconciling the configuration with a Bastionhost instance
+//!
+//! Every host of the instance with an SSH account is reached through the
+//! bastion itself by default: it becomes an asset of the configured host
+//! that logs into the bastion, so connecting to it logs in as
+//! `<bastion user>#<account>#<address>`. The bastion's assets then mirror
+//! the instance, as they do after `discover`.
+//!
+//! With `--direct` each such host instead becomes a host of its own tagged
+//! `aliyun:<instance>`, for networks that reach the hosts without going
+//! through the bastion. A later sync updates the address and account of the
+//! hosts carrying that tag and removes the ones the instance no longer has;
+//! everything else about them, such as credentials, jump host, forwards and
+//! notes, is left as it was edited locally. Hosts without the tag are never
+//! touched.
+
+use std::collections::HashSet;
+
+use super::bastionhost::{HostAccount, HostWithAccounts};
+use crate::config::{Asset, Config, HostConfig};
+
+/// Where synced hosts are reached
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    /// Port of hosts added by the sync
+    pub port: u16,
+    /// Jump host of hosts added by the sync
+    pub via: Option<String>,
+    /// Account used when a host has it, instead of its first SSH account
+    pub account: Option<String>,
+}
+
+/// Tag of the hosts synced from `instance`
+pub fn source_tag(instance: &str) -> String {
+    format!("aliyun:{}", instance)
+}
+
+/// Host names left out of a sync, and why
+pub type Skipped = Vec<(String, String)>;
+
+/// What a sync changes in the config
+#[derive(Debug, Default)]
+pub struct Plan {
+    pub added: Vec<HostConfig>,
+    /// Before and after
+    pub changed: Vec<(HostConfig, HostConfig)>,
+    pub removed: Vec<HostConfig>,
+    /// Host names left alone, and why
+    pub skipped: Skipped,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        for host in self.added.iter().chain(self.changed.iter().map(|(_, new)| new)) {
+            config.add_host(host.clone());
+        }
+        for host in &self.removed {
+            config.remove_host(&host.name);
+        }
+    }
+}
+
+/// Host name for a name from the API; `/` is reserved for assets
+fn host_name(name: &str) -> String {
+    name.trim().split(|c: char| c.is_whitespace() || c == '/').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+fn endpoint(host: &HostConfig) -> String {
+    format!("{}@{}:{}", host.username, host.hostname, host.port)
+}
+
+/// How each remote host is logged into: its name for the config, address
+/// and SSH account, as an asset. Hosts without either are skipped.
+pub fn logins(remote: &[HostWithAccounts], account: Option<&str>) -> (Vec<Asset>, Skipped) {
+    let mut logins: Vec<Asset> = Vec::new();
+    let mut skipped = Skipped::new();
+
+    for (host, accounts) in remote {
+        let mut name = host_name(&host.name);
+        if name.is_empty() {
+            name = format!("host-{}", host.id);
+        }
+        // Hosts of the instance may share a name
+        let taken = |candidate: &str| logins.iter().any(|login| login.name == candidate);
+        if taken(&name) {
+            let base = name.clone();
+            name = (2..).map(|n| format!("{}-{}", base, n)).find(|candidate| !taken(candidate)).unwrap_or_default();
+        }
+
+        let ssh_accounts: Vec<&HostAccount> = accounts.iter().filter(|account| account.is_ssh()).collect();
+        let chosen = account
+            .and_then(|preferred| ssh_accounts.iter().find(|account| account.name == preferred))
+            .or(ssh_accounts.first());
+        let Some(chosen) = chosen else {
+            skipped.push((name, "no SSH account".to_string()));
+            continue;
+        };
+        if host.address.is_empty() {
+            skipped.push((name, "no address".to_string()));
+            continue;
+        }
+        logins.push(Asset { name, address: host.address.clone(), account: chosen.name.clone() });
+    }
+    (logins, skipped)
+}
+
+/// Compare the hosts synced from `instance` before with `remote`, for a
+/// sync that adds them as hosts of their own
+pub fn plan(config: &Config, instance: &str, remote: &[HostWithAccounts], options: &Options) -> Plan {
+    let tag = source_tag(instance);
+    let (logins, skipped) = logins(remote, options.account.as_deref());
+    let mut plan = Plan { skipped, ..Default::default() };
+    let mut synced: HashSet<String> = HashSet::new();
+
+    for login in logins {
+        match config.get_host(&login.name) {
+            Some(existing) if existing.tags.contains(&tag) => {
+                let updated = HostConfig { hostname: login.address, username: login.account, ..existing.clone() };
+                if updated != *existing {
+                    plan.changed.push((existing.clone(), updated));
+                }
+            }
+            Some(_) => {
+                plan.skipped.push((login.name, format!("a host with this name exists and was not synced from {}", instance)));
+                continue;
+            }
+            None => plan.added.push(HostConfig {
+                name: login.name.clone(),
+                hostname: login.address,
+                port: options.port,
+                username: login.account,
+                via: options.via.clone(),
+                tags: vec![tag.clone()],
+                ..Default::default()
+            }),
+        }
+        synced.insert(login.name);
+    }
+
+    // Gone from the instance, unless a host that stays is reached through it
+    let mut removed: Vec<&HostConfig> = config.hosts_with_tag(&tag).into_iter().filter(|host| !synced.contains(&host.name)).collect();
+    loop {
+        let needed = removed.iter().position(|host| {
+            config.hosts_via(&host.name).iter().any(|dependent| !removed.iter().any(|gone| gone.name == dependent.name))
+        });
+        let Some(index) = needed else { break };
+        let host = removed.remove(index);
+        let dependents: Vec<&str> = config.hosts_via(&host.name).iter().map(|dependent| dependent.name.as_str()).collect();
+        plan.skipped.push((host.name.clone(), format!("gone from {} but still the jump host of {}", instance, dependents.join(", "))));
+    }
+    plan.removed = removed.into_iter().cloned().collect();
+    plan
+}
+
+/// One line per change: `+` added, `~` changed, `-` removed, `!` skipped
+pub fn format_plan(plan: &Plan) -> String {
+    let mut lines = String::new();
+    for host in &plan.added {
+        lines.push_str(&format!("  + {}: {}\n", host.name, endpoint(host)));
+    }
+    for (old, new) in &plan.changed {
+        lines.push_str(&format!("  ~ {}: {} -> {}\n", old.name, endpoint(old), endpoint(new)));
+    }
+    for host in &plan.removed {
+        lines.push_str(&format!("  - {}: {}\n", host.name, endpoint(host)));
+    }
+    lines.push_str(&format_skipped(&plan.skipped));
+    lines
+}
+
+/// One `!` line per skipped host
+pub fn format_skipped(skipped: &Skipped) -> String {
+    skipped.iter().map(|(name, reason)| format!("  ! {}: {}\n", name, reason)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aliyun::bastionhost::Host;
+
+    const INSTANCE: &str = "bastionhost-cn-test";
+
+    fn remote(id: &str, name: &str, address: &str, accounts: &[(&str, &str)]) -> HostWithAccounts {
+        let host = Host { id: id.to_string(), name: name.to_string(), address: address.to_string(), os: "Linux".to_string() };
+        let accounts = accounts
+            .iter()
+            .map(|(name, protocol)| HostAccount { name: name.to_string(), protocol: protocol.to_string() })
+            .collect();
+        (host, accounts)
+    }
+
+    fn synced(name: &str, hostname: &str, username: &str) -> HostConfig {
+        HostConfig {
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            port: 22,
+            username: username.to_string(),
+            tags: vec![source_tag(INSTANCE)],
+            ..Default::default()
+        }
+    }
+
+    fn options() -> Options {
+        Options { port: 22, ..Default::default() }
+    }
+
+    #[test]
+    fn test_plan_adds_hosts_with_ssh_accounts() {
+        let remote = [
+            remote("1", "web 1", "10.0.0.1", &[("Administrator", "RDP"), ("root", "SSH"), ("deploy", "SSH")]),
+            remote("2", "web/1", "10.0.0.2", &[("root", "SSH")]),
+            remote("3", "win", "10.0.0.3", &[("Administrator", "RDP")]),
+        ];
+        let options = Options { via: Some("jump".to_string()), account: Some("deploy".to_string()), ..options() };
+
+        let plan = plan(&Config::new(), INSTANCE, &remote, &options);
+        let names: Vec<&str> = plan.added.iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(names, ["web-1", "web-1-2"]);
+        assert_eq!(plan.added[0].username, "deploy");
+        assert_eq!(plan.added[1].username, "root");
+        assert_eq!(plan.added[0].via.as_deref(), Some("jump"));
+        assert_eq!(plan.added[0].tags, ["aliyun:bastionhost-cn-test"]);
+        assert_eq!(plan.skipped, [("win".to_string(), "no SSH account".to_string())]);
+    }
+
+    #[test]
+    fn test_plan_keeps_local_fields() {
+        let mut config = Config::new();
+        config.add_host(HostConfig {
+            port: 2222,
+            password_command: Some("pass show web".to_string()),
+            notes: Some("owned by the web team".to_string()),
+            tags: vec![source_tag(INSTANCE), "web".to_string()],
+            ..synced("web", "10.0.0.1", "root")
+        });
+        config.add_host(synced("db", "10.0.0.2", "dba"));
+
+        let remote = [remote("1", "web", "10.0.0.10", &[("root", "SSH")]), remote("2", "db", "10.0.0.2", &[("dba", "SSH")])];
+        let plan = plan(&config, INSTANCE, &remote, &options());
+        assert!(plan.added.is_empty() && plan.removed.is_empty());
+        assert_eq!(plan.changed.len(), 1);
+
+        let (_, web) = &plan.changed[0];
+        assert_eq!((web.hostname.as_str(), web.port), ("10.0.0.10", 2222));
+        assert_eq!(web.notes.as_deref(), Some("owned by the web team"));
+        assert_eq!(web.password_command.as_deref(), Some("pass show web"));
+        assert_eq!(web.tags.len(), 2);
+        assert_eq!(format_plan(&plan), "  ~ web: root@10.0.0.1:2222 -> root@10.0.0.10:2222\n");
+    }
+
+    #[test]
+    fn test_plan_removes_only_synced_hosts() {
+        let mut config = Config::new();
+        config.add_host(synced("old", "10.0.0.9", "root"));
+        config.add_host(synced("gateway", "10.0.0.8", "root"));
+        config.add_host(synced("other", "10.0.0.7", "root"));
+        config.hosts.get_mut("other").unwrap().tags = vec![source_tag("bastionhost-cn-other")];
+        config.add_host(HostConfig { name: "app".to_string(), via: Some("gateway".to_string()), ..Default::default() });
+        config.add_host(HostConfig { name: "web".to_string(), hostname: "192.168.0.1".to_string(), ..Default::default() });
+
+        let remote = [remote("1", "web", "10.0.0.1", &[("root", "SSH")])];
+        let mut plan = plan(&config, INSTANCE, &remote, &options());
+        plan.skipped.sort();
+        assert!(plan.added.is_empty());
+        let removed: Vec<&str> = plan.removed.iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(removed, ["old"]);
+        assert_eq!(plan.skipped[0], ("gateway".to_string(), "gone from bastionhost-cn-test but still the jump host of app".to_string()));
+        assert_eq!(plan.skipped[1].0, "web");
+
+        plan.apply(&mut config);
+        assert!(config.get_host("old").is_none());
+        assert!(config.get_host("other").is_some());
+        assert_eq!(config.get_host("web").unwrap().hostname, "192.168.0.1");
+    }
+
+    #[test]
+    fn test_logins_become_assets() {
+        let remote = [
+            remote("1", "web 1", "10.0.0.1", &[("Administrator", "RDP"), ("root", "SSH"), ("deploy", "SSH")]),
+            remote("2", "db", "", &[("dba", "SSH")]),
+            remote("3", "web/1", "10.0.0.3", &[("root", "SSH")]),
+        ];
+
+        let (assets, skipped) = logins(&remote, Some("deploy"));
+        let specs: Vec<String> = assets.iter().map(|asset| asset.to_string()).collect();
+        assert_eq!(specs, ["web-1: deploy@10.0.0.1", "web-1-2: root@10.0.0.3"]);
+        assert_eq!(assets[0].login_name("ops"), "ops#deploy#10.0.0.1");
+        assert_eq!(skipped, [("db".to_string(), "no address".to_string())]);
+    }
+
+    #[test]
+    fn test_format_plan() {
+        let plan = Plan {
+            added: vec![synced("web", "10.0.0.1", "root")],
+            removed: vec![synced("old", "10.0.0.9", "root")],
+            skipped: vec![("win".to_string(), "no SSH account".to_string())],
+            ..Default::default()
+        };
+        assert_eq!(format_plan(&plan), "  + web: root@10.0.0.1:22\n  - old: root@10.0.0.9:22\n  ! win: no SSH account\n");
+    }
+}
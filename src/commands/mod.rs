@@ -1,3 +1,3 @@
 pub mod types;
 
-pub use types::{Commands, AddArgs, RemoveArgs, ConnectArgs};
\ No newline at end of file
+pub use types::{Commands, AddArgs, RemoveArgs, ConnectArgs, ExecArgs, PutArgs, GetArgs, TunnelArgs, TunnelCommand, TunnelStopArgs, ProxyArgs, DiscoverArgs, SyncArgs, SyncCommand, SyncAliyunArgs, SshConfigArgs, SshConfigCommand, AgentArgs, AgentCommand, OtpArgs, ConfigArgs, ConfigCommand, ExportArgs, ImportArgs, ImportCommand, ImportSshConfigArgs, ConflictPolicy};
\ No newline at end of file
@@ -1,4 +1,7 @@
-use clap::{Args, Subcommand};
+use clap::{ArgGroup, Args, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+use crate::config::{Asset, Forward, MenuStep};
 
 #[derive(Subcommand)]
 pub enum Commands {
@@ -10,6 +13,36 @@ pub enum Commands {
     List,
     /// Connect to a host (if no host provided, interactive selection will be used)
     Connect(ConnectArgs),
+    /// Run a command on a host and exit with its exit code
+    Exec(ExecArgs),
+    /// Copy a local file or directory to a host
+    Put(PutArgs),
+    /// Copy a file or directory from a host
+    Get(GetArgs),
+    /// Open a host's port forwards without a remote shell, or manage background tunnels
+    Tunnel(TunnelArgs),
+    /// Relay stdin and stdout to a host, for use as an ssh ProxyCommand
+    Proxy(ProxyArgs),
+    /// Generate ssh configuration for the configured hosts
+    SshConfig(SshConfigArgs),
+    /// Read the assets a bastion's login menu lists into the host's assets
+    Discover(DiscoverArgs),
+    /// Reconcile hosts with a cloud bastion's inventory, as in `sync aliyun`
+    Sync(SyncArgs),
+    /// Generate a new vault key and re-encrypt all stored passwords with it
+    Rekey,
+    /// Start a background agent that keeps the vault key unlocked
+    Agent(AgentArgs),
+    /// Print the current MFA code for a host
+    Otp(OtpArgs),
+    /// Manage how the configuration file is stored
+    Config(ConfigArgs),
+    /// Show the public key teammates use to share hosts with you
+    Identity,
+    /// Export hosts as a bundle encrypted for one or more teammates
+    Export(ExportArgs),
+    /// Import hosts from a bundle encrypted for you, or from an ssh config with `import ssh-config`
+    Import(ImportArgs),
 }
 
 #[derive(Args)]
@@ -33,6 +66,45 @@ pub struct AddArgs {
     /// Password
     #[arg(short = 'P', long)]
     pub password: Option<String>,
+    
+    /// Command that prints the password on its first line (e.g. "pass show aliyun/prod")
+    #[arg(long, conflicts_with = "password")]
+    pub password_command: Option<String>,
+    
+    /// TOTP secret for MFA, as base32 or an otpauth:// URI
+    #[arg(long)]
+    pub totp: Option<String>,
+    
+    /// Private key file for public key authentication
+    #[arg(short = 'i', long)]
+    pub identity_file: Option<String>,
+
+    /// Reach the host through this configured host (a jump host)
+    #[arg(short = 'J', long)]
+    pub via: Option<String>,
+
+    /// Tag for `exec --group`; repeat or separate with commas for several
+    #[arg(short = 't', long = "tag", value_delimiter = ',')]
+    pub tags: Vec<String>,
+
+    /// Port forward for `tunnel`, as name=L:port:host:hostport, name=R:port:host:hostport
+    /// or name=D:port (repeatable)
+    #[arg(long = "forward", value_parser = parse_forward)]
+    pub forwards: Vec<Forward>,
+
+    /// Asset reached through this host as a bastion, as name=account@address
+    /// (repeatable); connect to it with `connect <host>/<asset>`
+    #[arg(long = "asset", value_parser = parse_asset)]
+    pub assets: Vec<Asset>,
+
+    /// Answer for the login menu, as 'prompt pattern=>reply' (repeatable, in
+    /// order); `{otp}` in the reply is replaced by the MFA code
+    #[arg(long = "menu", value_parser = parse_menu_step)]
+    pub menu: Vec<MenuStep>,
+
+    /// Free-form note shown by `list`
+    #[arg(long)]
+    pub note: Option<String>,
 }
 
 #[derive(Args)]
@@ -43,6 +115,333 @@ pub struct RemoveArgs {
 
 #[derive(Args)]
 pub struct ConnectArgs {
-    /// Host name to connect to (if not provided, interactive selection will be used)
+    /// Host name to connect to, or <bastion>/<asset> (if not provided, interactive selection will be used)
+    pub name: Option<String>,
+    /// Print the command that would be run, with secrets redacted, instead of connecting
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("targets").required(true).args(["name", "group", "hosts"])))]
+pub struct ExecArgs {
+    /// Host name to run the command on
     pub name: Option<String>,
-}
\ No newline at end of file
+    /// Run on every host with this tag
+    #[arg(short, long)]
+    pub group: Option<String>,
+    /// Run on these hosts (comma-separated)
+    #[arg(long, value_delimiter = ',')]
+    pub hosts: Vec<String>,
+    /// Maximum number of hosts to run on at the same time
+    #[arg(short = 'j', long, default_value_t = 10, value_parser = clap::value_parser!(u16).range(1..))]
+    pub concurrency: u16,
+    /// Print each distinct output once with the hosts that produced it,
+    /// instead of streaming prefixed lines
+    #[arg(long)]
+    pub collapse: bool,
+    /// Command to run, after `--` (e.g. `exec prod -- df -h`)
+    #[arg(last = true, required = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Args)]
+pub struct PutArgs {
+    /// Local file or directory
+    pub local: String,
+    /// Destination as <host>:<remote path>
+    pub remote: String,
+    /// Copy directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+}
+
+#[derive(Args)]
+pub struct GetArgs {
+    /// Source as <host>:<remote path>
+    pub remote: String,
+    /// Local destination
+    pub local: String,
+    /// Copy directories recursively
+    #[arg(short, long)]
+    pub recursive: bool,
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct TunnelArgs {
+    #[command(subcommand)]
+    pub command: Option<TunnelCommand>,
+
+    /// Host to forward through
+    #[arg(required = true)]
+    pub name: Option<String>,
+
+    /// Open only this saved forward (repeatable); all of the host's saved forwards by default
+    #[arg(short, long = "forward")]
+    pub forwards: Vec<String>,
+
+    /// Extra local forward, as [bind:]port:host:hostport (repeatable)
+    #[arg(short = 'L', value_name = "SPEC")]
+    pub local: Vec<String>,
+
+    /// Extra remote forward, as [bind:]port:host:hostport (repeatable)
+    #[arg(short = 'R', value_name = "SPEC")]
+    pub remote: Vec<String>,
+
+    /// Extra SOCKS proxy, as [bind:]port (repeatable)
+    #[arg(short = 'D', value_name = "SPEC")]
+    pub dynamic: Vec<String>,
+
+    /// Keep the tunnel running in the background
+    #[arg(long)]
+    pub detach: bool,
+}
+
+#[derive(Subcommand)]
+pub enum TunnelCommand {
+    /// List tunnels running in the background
+    Ls,
+    /// Stop a background tunnel
+    Stop(TunnelStopArgs),
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("tunnels").required(true).args(["id", "all"])))]
+pub struct TunnelStopArgs {
+    /// Tunnel id as shown by `tunnel ls`
+    pub id: Option<u32>,
+
+    /// Stop all background tunnels
+    #[arg(long)]
+    pub all: bool,
+}
+
+#[derive(Args)]
+pub struct DiscoverArgs {
+    /// Bastion host to log into
+    pub name: String,
+
+    /// Pattern the menu prompt matches [default: the prompt of the host's first
+    /// --menu step, or *>]
+    #[arg(long)]
+    pub prompt: Option<String>,
+
+    /// What to type for the next page of assets
+    #[arg(long, default_value = "n")]
+    pub next: String,
+
+    /// What to type to leave the menu
+    #[arg(long, default_value = "q")]
+    pub quit: String,
+
+    /// Account for assets the menu lists without one
+    #[arg(long)]
+    pub account: Option<String>,
+
+    /// Show what would change without saving it
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct SyncArgs {
+    #[command(subcommand)]
+    pub command: SyncCommand,
+}
+
+#[derive(Subcommand)]
+pub enum SyncCommand {
+    /// Sync the hosts of an Alibaba Cloud Bastionhost instance through its OpenAPI
+    Aliyun(SyncAliyunArgs),
+}
+
+#[derive(Args)]
+#[command(group(ArgGroup::new("into").required(true).args(["bastion", "direct"])))]
+pub struct SyncAliyunArgs {
+    /// Bastionhost instance ID, e.g. bastionhost-cn-78v1gh1xxxx
+    #[arg(long)]
+    pub instance: String,
+
+    /// Configured host that logs into the instance; its assets become the instance's hosts
+    #[arg(long)]
+    pub bastion: Option<String>,
+
+    /// Add the instance's hosts as hosts of their own instead, reached directly or through --via
+    #[arg(long)]
+    pub direct: bool,
+
+    /// Profile of the aliyun CLI to take the AccessKey from [default: the
+    /// ALIBABA_CLOUD_ACCESS_KEY_* variables, or the CLI's current profile]
+    #[arg(long)]
+    pub profile: Option<String>,
+
+    /// Region of the instance [default: the profile's region]
+    #[arg(long)]
+    pub region: Option<String>,
+
+    /// API endpoint [default: https://yundun-bastionhost.<region>.aliyuncs.com]
+    #[arg(long)]
+    pub endpoint: Option<String>,
+
+    /// Port of added hosts, with --direct
+    #[arg(short = 'p', long, default_value_t = 22, conflicts_with = "bastion")]
+    pub port: u16,
+
+    /// Reach added hosts through this configured host (a jump host), with --direct
+    #[arg(short = 'J', long, conflicts_with = "bastion")]
+    pub via: Option<String>,
+
+    /// Log in with this account on hosts that have it, instead of their first SSH account
+    #[arg(long)]
+    pub account: Option<String>,
+
+    /// Show what would change without saving it
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Args)]
+pub struct ProxyArgs {
+    /// Host to connect to
+    pub name: String,
+
+    /// Reach the host through this configured host instead of directly
+    #[arg(short = 'J', long)]
+    pub via: Option<String>,
+}
+
+#[derive(Args)]
+pub struct AgentArgs {
+    #[command(subcommand)]
+    pub command: Option<AgentCommand>,
+
+    /// Seconds of inactivity after which the agent forgets the key and exits
+    #[arg(short = 't', long, default_value_t = 900)]
+    pub timeout: u64,
+}
+
+#[derive(Subcommand)]
+pub enum AgentCommand {
+    /// Make the running agent forget the key
+    Lock,
+    /// Stop the running agent
+    Stop,
+    /// Run the agent in the foreground, reading the key from stdin
+    #[command(hide = true)]
+    Serve,
+}
+
+#[derive(Args)]
+pub struct OtpArgs {
+    /// Host name
+    pub name: String,
+}
+
+#[derive(Args)]
+pub struct ConfigArgs {
+    #[command(subcommand)]
+    pub command: ConfigCommand,
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Encrypt the whole configuration file at rest
+    Encrypt,
+    /// Store the configuration file as plaintext JSON again
+    Decrypt,
+}
+
+#[derive(Args)]
+pub struct SshConfigArgs {
+    #[command(subcommand)]
+    pub command: SshConfigCommand,
+}
+
+#[derive(Subcommand)]
+pub enum SshConfigCommand {
+    /// Write a Host block for every host to ~/.ali-bastion/ssh_config
+    Export {
+        /// Also add an Include line for it to ~/.ssh/config
+        #[arg(long)]
+        install: bool,
+
+        /// Write the file even though the configuration is stored encrypted
+        #[arg(long)]
+        allow_plaintext: bool,
+    },
+}
+
+#[derive(Args)]
+pub struct ExportArgs {
+    /// Public key of a teammate (as printed by `identity`); may be repeated
+    #[arg(short = 'r', long = "recipient", required = true)]
+    pub recipients: Vec<String>,
+    
+    /// Comma-separated host names to export (default: all hosts)
+    #[arg(long, value_delimiter = ',')]
+    pub hosts: Option<Vec<String>>,
+    
+    /// Write the bundle to this file instead of stdout
+    #[arg(short = 'o', long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+pub struct ImportArgs {
+    #[command(subcommand)]
+    pub command: Option<ImportCommand>,
+
+    /// Bundle file to import
+    #[arg(required = true)]
+    pub bundle: Option<PathBuf>,
+    
+    /// Replace existing hosts with the same name
+    #[arg(long)]
+    pub overwrite: bool,
+
+    /// Keep the password commands, port forwards and login-menu answers of
+    /// imported hosts. Bundles are not signed; only use this for a bundle
+    /// you know comes from a teammate.
+    #[arg(long)]
+    pub allow_commands: bool,
+}
+
+#[derive(Subcommand)]
+pub enum ImportCommand {
+    /// Import the Host entries of an OpenSSH client config
+    SshConfig(ImportSshConfigArgs),
+}
+
+#[derive(Args)]
+pub struct ImportSshConfigArgs {
+    /// Config file to read (default: ~/.ssh/config)
+    pub path: Option<PathBuf>,
+
+    /// What to do with a host whose name is already taken
+    #[arg(long, value_enum, default_value_t = ConflictPolicy::Skip)]
+    pub on_conflict: ConflictPolicy,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the existing host
+    Skip,
+    /// Replace the existing host
+    Overwrite,
+    /// Import under the next free name, e.g. prod-2
+    Rename,
+}
+
+fn parse_forward(value: &str) -> Result<Forward, String> {
+    Forward::parse(value).map_err(|e| e.to_string())
+}
+
+fn parse_asset(value: &str) -> Result<Asset, String> {
+    Asset::parse(value).map_err(|e| e.to_string())
+}
+
+fn parse_menu_step(value: &str) -> Result<MenuStep, String> {
+    MenuStep::parse(value).map_err(|e| e.to_string())
+}
@@ -0,0 +1,70 @@
+//! Assets behind an Aliyun Bastionhost
+//!
+//! A host with assets is a bastion. Each asset is reached through the
+//! bastion's endpoint by logging in with a composed user name,
+//! `<bastion user>#<asset account>#<asset address>`, which tells the bastion
+//! where to go and as whom. On the command line an asset is written as
+//! `name=account@address`, e.g. `web-1=root@10.0.0.1`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Asset {
+    pub name: String,
+    /// IP address or host name as the bastion knows it
+    pub address: String,
+    /// Account on the asset that the bastion logs in as
+    pub account: String,
+}
+
+impl Asset {
+    /// Parse `<name>=<account>@<address>`
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (name, rest) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("Asset '{}' must look like name=account@address", spec))?;
+        let (account, address) = rest
+            .rsplit_once('@')
+            .ok_or_else(|| format!("Asset '{}' must look like name=account@address", spec))?;
+        if name.is_empty() || name.contains('/') {
+            return Err(format!("Invalid asset name '{}'", name).into());
+        }
+        if account.is_empty() || address.is_empty() {
+            return Err(format!("Asset '{}' needs both an account and an address", spec).into());
+        }
+        Ok(Asset { name: name.to_string(), address: address.to_string(), account: account.to_string() })
+    }
+
+    /// User name that logs `bastion_user` into this asset through the bastion
+    pub fn login_name(&self, bastion_user: &str) -> String {
+        format!("{}#{}#{}", bastion_user, self.account, self.address)
+    }
+}
+
+/// `name: account@address`
+impl fmt::Display for Asset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}@{}", self.name, self.account, self.address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let asset = Asset::parse("web-1=root@10.0.0.1").unwrap();
+        assert_eq!(asset.name, "web-1");
+        assert_eq!(asset.account, "root");
+        assert_eq!(asset.address, "10.0.0.1");
+        assert_eq!(asset.login_name("alice"), "alice#root#10.0.0.1");
+        assert_eq!(asset.to_string(), "web-1: root@10.0.0.1");
+
+        assert!(Asset::parse("root@10.0.0.1").is_err());
+        assert!(Asset::parse("web-1=10.0.0.1").is_err());
+        assert!(Asset::parse("web/1=root@10.0.0.1").is_err());
+        assert!(Asset::parse("web-1=@10.0.0.1").is_err());
+    }
+}
@@ -0,0 +1,202 @@
+//! Port forwarding profiles
+//!
+//! On the command line a forward is written like the matching ssh option,
+//! prefixed with its name: `db=L:15432:rds.internal:3306`,
+//! `kibana=L:127.0.0.1:5601:kibana.internal:5601` or `socks=D:1080`.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardKind {
+    /// `-L`: a port on this machine reaches `destination` from the host
+    Local,
+    /// `-R`: a port on the host reaches `destination` from this machine
+    Remote,
+    /// `-D`: a SOCKS proxy on this machine
+    Dynamic,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Forward {
+    pub name: String,
+    pub kind: ForwardKind,
+    /// Address to listen on; ssh's default (loopback) when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_address: Option<String>,
+    /// Port to listen on
+    pub port: u16,
+    /// `host:port` to connect to; unused for dynamic forwards
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+impl Forward {
+    /// Parse `<name>=<L|R|D>:[bind:]port[:host:hostport]`
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (name, rest) = spec
+            .split_once('=')
+            .filter(|(name, _)| !name.is_empty())
+            .ok_or_else(|| format!("Forward '{}' must look like name=L:15432:db.internal:5432", spec))?;
+        let (kind, rest) = rest.split_once(':').ok_or_else(|| format!("Forward '{}' has no ports", spec))?;
+        let kind = match kind.to_ascii_uppercase().as_str() {
+            "L" => ForwardKind::Local,
+            "R" => ForwardKind::Remote,
+            "D" => ForwardKind::Dynamic,
+            other => return Err(format!("Unknown forward type '{}', expected L, R or D", other).into()),
+        };
+        Self::from_option(name, kind, rest)
+    }
+
+    /// Parse the value of an ssh `-L`, `-R` or `-D` option
+    pub fn from_option(name: &str, kind: ForwardKind, value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let fields = split_fields(value);
+        let (bind_address, port, destination) = match (kind, fields.as_slice()) {
+            (ForwardKind::Dynamic, [port]) => (None, port, None),
+            (ForwardKind::Dynamic, [bind, port]) => (Some(bind), port, None),
+            (ForwardKind::Local | ForwardKind::Remote, [port, host, host_port]) => (None, port, Some((host, host_port))),
+            (ForwardKind::Local | ForwardKind::Remote, [bind, port, host, host_port]) => (Some(bind), port, Some((host, host_port))),
+            _ => return Err(format!("Forward '{}' has the wrong number of fields", value).into()),
+        };
+
+        let port = parse_port(port)?;
+        let destination = match destination {
+            Some((host, host_port)) => Some(format!("{}:{}", host, parse_port(host_port)?)),
+            None => None,
+        };
+
+        Ok(Forward {
+            name: name.to_string(),
+            kind,
+            bind_address: bind_address.map(|bind| bind.to_string()),
+            port,
+            destination,
+        })
+    }
+
+    /// Whether the forward listens on this machine rather than on the host
+    pub fn listens_locally(&self) -> bool {
+        self.kind != ForwardKind::Remote
+    }
+
+    /// Whether both forwards need the same listening port. Remote forwards
+    /// listen on their host, so they only clash through the same host.
+    pub fn conflicts_with(&self, other: &Forward, same_host: bool) -> bool {
+        self.port == other.port
+            && self.listens_locally() == other.listens_locally()
+            && (self.listens_locally() || same_host)
+    }
+
+    /// The ssh option and its value, e.g. `("-L", "15432:db:5432")`
+    pub fn ssh_option(&self) -> (&'static str, String) {
+        let flag = match self.kind {
+            ForwardKind::Local => "-L",
+            ForwardKind::Remote => "-R",
+            ForwardKind::Dynamic => "-D",
+        };
+        let mut value = match &self.bind_address {
+            Some(bind) => format!("{}:{}", bind, self.port),
+            None => self.port.to_string(),
+        };
+        if let Some(destination) = &self.destination {
+            value.push(':');
+            value.push_str(destination);
+        }
+        (flag, value)
+    }
+
+    /// Where the forward listens, as `address:port`
+    pub fn listen_endpoint(&self) -> String {
+        let address = self.bind_address.as_deref().unwrap_or("localhost");
+        format!("{}:{}", address, self.port)
+    }
+}
+
+/// Human-readable description, e.g. `db: localhost:15432 -> rds.internal:3306`
+impl fmt::Display for Forward {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let destination = self.destination.as_deref().unwrap_or("");
+        match self.kind {
+            ForwardKind::Local => write!(f, "{}: {} -> {}", self.name, self.listen_endpoint(), destination),
+            ForwardKind::Remote => write!(f, "{}: remote {} -> {}", self.name, self.listen_endpoint(), destination),
+            ForwardKind::Dynamic => write!(f, "{}: SOCKS proxy on {}", self.name, self.listen_endpoint()),
+        }
+    }
+}
+
+fn parse_port(value: &str) -> Result<u16, Box<dyn std::error::Error>> {
+    value
+        .parse()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| format!("Invalid port '{}'", value).into())
+}
+
+/// Split on `:` outside of `[...]`, so IPv6 addresses can be bracketed
+fn split_fields(value: &str) -> Vec<&str> {
+    let mut fields = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in value.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            ':' if depth == 0 => {
+                fields.push(&value[start..index]);
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    fields.push(&value[start..]);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_local_forward() {
+        let forward = Forward::parse("db=L:15432:rds.internal:3306").unwrap();
+
+        assert_eq!(forward.kind, ForwardKind::Local);
+        assert_eq!(forward.bind_address, None);
+        assert_eq!(forward.port, 15432);
+        assert_eq!(forward.destination.as_deref(), Some("rds.internal:3306"));
+        assert_eq!(forward.ssh_option(), ("-L", "15432:rds.internal:3306".to_string()));
+        assert_eq!(forward.to_string(), "db: localhost:15432 -> rds.internal:3306");
+    }
+
+    #[test]
+    fn test_parse_bind_address_and_ipv6() {
+        let forward = Forward::parse("kibana=R:0.0.0.0:5601:[fd00::5]:5601").unwrap();
+        assert_eq!(forward.ssh_option(), ("-R", "0.0.0.0:5601:[fd00::5]:5601".to_string()));
+
+        let socks = Forward::parse("socks=D:1080").unwrap();
+        assert_eq!(socks.ssh_option(), ("-D", "1080".to_string()));
+        assert_eq!(socks.to_string(), "socks: SOCKS proxy on localhost:1080");
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_specs() {
+        assert!(Forward::parse("L:15432:db:5432").is_err());
+        assert!(Forward::parse("db=X:15432:db:5432").is_err());
+        assert!(Forward::parse("db=L:15432").is_err());
+        assert!(Forward::parse("db=L:99999:db:5432").is_err());
+        assert!(Forward::parse("socks=D:1080:db:5432").is_err());
+    }
+
+    #[test]
+    fn test_conflicts() {
+        let db = Forward::parse("db=L:15432:rds.internal:3306").unwrap();
+        let socks = Forward::parse("socks=D:15432").unwrap();
+        let remote = Forward::parse("web=R:15432:localhost:8080").unwrap();
+
+        assert!(db.conflicts_with(&socks, false));
+        assert!(!db.conflicts_with(&remote, true));
+        assert!(!remote.conflicts_with(&remote, false));
+        assert!(remote.conflicts_with(&remote, true));
+    }
+}
@@ -0,0 +1,65 @@
+//! Scripted answers for a bastion's interactive login menu
+//!
+//! A bastion that is logged into without naming an asset shows a text menu.
+//! Each step waits for a prompt matching its pattern and types its reply,
+//! e.g. `Opt>=>web-1` searches for `web-1` at the `Opt>` prompt and
+//! `[Host]>=>1` picks the first result. `{otp}` in a reply is replaced by
+//! the current MFA code.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Placeholder replaced by the current TOTP code
+pub const OTP_PLACEHOLDER: &str = "{otp}";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MenuStep {
+    /// Pattern the prompt line must match, where `*` is any run of
+    /// characters and `?` any one character
+    pub prompt: String,
+    /// Text typed at the prompt, followed by Enter
+    pub reply: String,
+}
+
+impl MenuStep {
+    /// Parse `<prompt pattern>=><reply>`
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let (prompt, reply) = spec
+            .split_once("=>")
+            .ok_or_else(|| format!("Menu step '{}' must look like 'Opt>=>web-1'", spec))?;
+        if prompt.trim().is_empty() {
+            return Err(format!("Menu step '{}' has no prompt pattern", spec).into());
+        }
+        Ok(MenuStep { prompt: prompt.trim().to_string(), reply: reply.to_string() })
+    }
+
+    /// Whether the reply is the MFA code
+    pub fn needs_otp(&self) -> bool {
+        self.reply.contains(OTP_PLACEHOLDER)
+    }
+}
+
+/// `prompt=>reply`, as it is written on the command line
+impl fmt::Display for MenuStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}=>{}", self.prompt, self.reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let step = MenuStep::parse("Opt>=>web-1").unwrap();
+        assert_eq!((step.prompt.as_str(), step.reply.as_str()), ("Opt>", "web-1"));
+        assert_eq!(step.to_string(), "Opt>=>web-1");
+        assert!(!step.needs_otp());
+
+        assert!(MenuStep::parse("*MFA*:=>{otp}").unwrap().needs_otp());
+        assert_eq!(MenuStep::parse("[Host]>=>").unwrap().reply, "");
+        assert!(MenuStep::parse("Opt> web-1").is_err());
+        assert!(MenuStep::parse("=>web-1").is_err());
+    }
+}
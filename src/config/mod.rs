@@ -1,27 +1,96 @@
+pub mod asset;
+pub mod forward;
+pub mod menu;
+
+pub use asset::Asset;
+pub use forward::{Forward, ForwardKind};
+pub use menu::MenuStep;
+
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+use crate::utils::encryption::{decrypt_password, encrypt_password, get_or_create_key, Key};
+use crate::utils::secret::Secret;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
 pub struct HostConfig {
     pub name: String,
     pub hostname: String,
     pub port: u16,
     pub username: String,
+    /// Encrypted password
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub password: Option<String>,
+    pub password: Option<Secret>,
+    /// Encrypted TOTP secret (base32 or `otpauth://` URI) for MFA prompts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<Secret>,
+    /// Command whose first line of output is the password, run at connect time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    /// Private key for public key authentication, passed to ssh as `-i`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// Host this one is reached through; that host may have a `via` of its own
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub via: Option<String>,
+    /// Group names for running commands on several hosts at once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Port forwards opened by `tunnel`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub forwards: Vec<Forward>,
+    /// Assets reached through this host when it is a bastion
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub assets: Vec<Asset>,
+    /// Answers for the login menu shown by `connect`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub menu: Vec<MenuStep>,
+    /// Free-form text shown by `list`; never overwritten by `sync`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub notes: Option<String>,
+}
+
+impl HostConfig {
+    /// This bastion as seen when logging into `asset`: the same endpoint and
+    /// credentials, with the user name composed for the asset
+    pub fn for_asset(&self, asset: &Asset) -> HostConfig {
+        HostConfig {
+            username: asset.login_name(&self.username),
+            forwards: Vec::new(),
+            assets: Vec::new(),
+            // Naming the asset in the login skips the menu
+            menu: Vec::new(),
+            ..self.clone()
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     pub hosts: HashMap<String, HostConfig>,
+    /// Key used to encrypt the whole file at rest. `None` means the config
+    /// is stored as plaintext JSON.
+    #[serde(skip)]
+    storage_key: Option<StorageKey>,
+}
+
+/// Wrapper that keeps the storage key out of Debug output
+struct StorageKey(Key);
+
+impl std::fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StorageKey(***)")
+    }
 }
 
 impl Config {
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Config {
             hosts: HashMap::new(),
+            storage_key: None,
         }
     }
 
@@ -32,37 +101,100 @@ impl Config {
         config_path
     }
 
+    /// Path of the encrypted variant of the config file at `path`
+    pub fn get_encrypted_path(path: &Path) -> PathBuf {
+        let mut encrypted_path = path.as_os_str().to_owned();
+        encrypted_path.push(".enc");
+        PathBuf::from(encrypted_path)
+    }
+
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
-        let config_path = Self::get_config_path();
-        
+        Self::load_from(&Self::get_config_path(), get_or_create_key)
+    }
+
+    /// Load the config from `path`, or from its encrypted variant if that
+    /// exists. `get_key` is only called for encrypted configs.
+    pub fn load_from(
+        config_path: &Path,
+        get_key: impl FnOnce() -> Result<Key, Box<dyn std::error::Error>>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let encrypted_path = Self::get_encrypted_path(config_path);
+        if encrypted_path.exists() {
+            let key = get_key()?;
+            let content = decrypt_password(fs::read_to_string(&encrypted_path)?.trim(), &key)
+                .map_err(|e| format!("Failed to decrypt {}: {}", encrypted_path.display(), e))?;
+            let mut config: Config = serde_json::from_str(content.expose())?;
+            config.validate().map_err(|e| format!("Invalid configuration in {}: {}", encrypted_path.display(), e))?;
+            config.storage_key = Some(StorageKey(key));
+            return Ok(config);
+        }
+
         if !config_path.exists() {
             // Create directory if it doesn't exist
             if let Some(parent) = config_path.parent() {
                 fs::create_dir_all(parent)?;
             }
             let config = Config::new();
-            config.save()?;
+            config.save_to(config_path)?;
             return Ok(config);
         }
 
         let content = fs::read_to_string(config_path)?;
         let config: Config = serde_json::from_str(&content)?;
+        config.validate().map_err(|e| format!("Invalid configuration in {}: {}", config_path.display(), e))?;
         Ok(config)
     }
 
     pub fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let config_path = Self::get_config_path();
-        
+        self.save_to(&Self::get_config_path())
+    }
+
+    /// Save the config to `path`, or to its encrypted variant if encryption
+    /// is enabled. The file for the other mode is removed.
+    pub fn save_to(&self, config_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
         // Create directory if it doesn't exist
         if let Some(parent) = config_path.parent() {
             fs::create_dir_all(parent)?;
         }
         
         let content = serde_json::to_string_pretty(self)?;
-        fs::write(config_path, content)?;
+        let encrypted_path = Self::get_encrypted_path(config_path);
+        match self.storage_key {
+            Some(StorageKey(ref key)) => {
+                fs::write(&encrypted_path, encrypt_password(&content, key)?)?;
+                if config_path.exists() {
+                    fs::remove_file(config_path)?;
+                }
+            }
+            None => {
+                fs::write(config_path, content)?;
+                if encrypted_path.exists() {
+                    fs::remove_file(&encrypted_path)?;
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Store the whole config encrypted under `key` from the next save on
+    pub fn enable_encryption(&mut self, key: Key) {
+        self.storage_key = Some(StorageKey(key));
+    }
+
+    /// Store the config as plaintext JSON from the next save on
+    pub fn disable_encryption(&mut self) {
+        self.storage_key = None;
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.storage_key.is_some()
+    }
+
+    /// The vault key the config was decrypted with, if it is encrypted
+    pub fn storage_key(&self) -> Option<&Key> {
+        self.storage_key.as_ref().map(|StorageKey(key)| key)
+    }
+
     pub fn add_host(&mut self, host: HostConfig) {
         self.hosts.insert(host.name.clone(), host);
     }
@@ -75,9 +207,80 @@ impl Config {
         self.hosts.get(name)
     }
 
+    /// Check that every `via` names a configured host, that no chain of
+    /// jump hosts leads back to where it started and that asset names are
+    /// unique within their bastion
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut names: Vec<&String> = self.hosts.keys().collect();
+        names.sort();
+        for name in names {
+            self.jump_chain(name)?;
+            let assets = &self.hosts[name].assets;
+            for (index, asset) in assets.iter().enumerate() {
+                if assets[..index].iter().any(|other| other.name == asset.name) {
+                    return Err(format!("Bastion '{}' has more than one asset named '{}'", name, asset.name).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The bastion and asset named by `<bastion>/<asset>`
+    pub fn find_asset(&self, spec: &str) -> Option<(&HostConfig, &Asset)> {
+        let (bastion, asset) = spec.split_once('/')?;
+        let bastion = self.hosts.get(bastion)?;
+        bastion.assets.iter().find(|candidate| candidate.name == asset).map(|asset| (bastion, asset))
+    }
+
+    /// The hosts `name` is reached through, first hop first
+    pub fn jump_chain(&self, name: &str) -> Result<Vec<&HostConfig>, Box<dyn std::error::Error>> {
+        let mut current = self.hosts.get(name).ok_or_else(|| format!("Host '{}' not found", name))?;
+        let mut visited = vec![current.name.as_str()];
+        let mut hops = Vec::new();
+        while let Some(via) = &current.via {
+            if visited.contains(&via.as_str()) {
+                visited.push(via);
+                return Err(format!("Jump hosts form a cycle: {}", visited.join(" -> ")).into());
+            }
+            current = self
+                .hosts
+                .get(via)
+                .ok_or_else(|| format!("Host '{}' goes via '{}', which is not configured", current.name, via))?;
+            visited.push(via);
+            hops.push(current);
+        }
+        hops.reverse();
+        Ok(hops)
+    }
+
+    /// Hosts that are reached through `name`, sorted by name
+    pub fn hosts_via(&self, name: &str) -> Vec<&HostConfig> {
+        let mut hosts: Vec<&HostConfig> = self.hosts.values().filter(|host| host.via.as_deref() == Some(name)).collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        hosts
+    }
+
+    /// `name`, or `name-2`, `name-3`, ... if it is already taken
+    pub fn unused_name(&self, name: &str) -> String {
+        if !self.hosts.contains_key(name) {
+            return name.to_string();
+        }
+        (2..)
+            .map(|n| format!("{}-{}", name, n))
+            .find(|candidate| !self.hosts.contains_key(candidate))
+            .unwrap_or_default()
+    }
+
     pub fn list_hosts(&self) -> Vec<&HostConfig> {
         self.hosts.values().collect()
     }
+
+    /// Hosts tagged with `tag`, sorted by name
+    pub fn hosts_with_tag(&self, tag: &str) -> Vec<&HostConfig> {
+        let mut hosts: Vec<&HostConfig> = self.hosts.values().filter(|host| host.tags.iter().any(|t| t == tag)).collect();
+        hosts.sort_by(|a, b| a.name.cmp(&b.name));
+        hosts
+    }
 }
 
 #[cfg(test)]
@@ -99,6 +302,7 @@ mod tests {
             port: 60022,
             username: "testuser".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host.clone());
@@ -115,6 +319,7 @@ mod tests {
             port: 60022,
             username: "testuser".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host);
@@ -138,6 +343,7 @@ mod tests {
             port: 60022,
             username: "testuser".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host.clone());
@@ -154,6 +360,7 @@ mod tests {
             port: 60022,
             username: "user1".to_string(),
             password: None,
+            ..Default::default()
         };
         let host2 = HostConfig {
             name: "host2".to_string(),
@@ -161,6 +368,7 @@ mod tests {
             port: 60022,
             username: "user2".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host1);
@@ -179,6 +387,7 @@ mod tests {
             port: 60022,
             username: "user1".to_string(),
             password: None,
+            ..Default::default()
         };
         let host2 = HostConfig {
             name: "test_host".to_string(), // Same name as host1
@@ -186,6 +395,7 @@ mod tests {
             port: 60022,
             username: "user2".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host1.clone());
@@ -197,4 +407,183 @@ mod tests {
         assert_eq!(config.hosts.len(), 1);
         assert_eq!(config.get_host("test_host"), Some(&host2));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_debug_hides_secrets() {
+        let host = HostConfig {
+            name: "test_host".to_string(),
+            hostname: "192.168.1.1".to_string(),
+            port: 60022,
+            username: "testuser".to_string(),
+            password: Some(Secret::from("v2:c2VjcmV0")),
+            totp_secret: Some(Secret::from("v2:dG90cA==")),
+            ..Default::default()
+        };
+
+        let debug = format!("{:?}", host);
+        assert!(!debug.contains("v2:"));
+        assert!(debug.contains("password: Some(***)"));
+    }
+
+    #[test]
+    fn test_plaintext_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+
+        let mut config = Config::new();
+        config.add_host(HostConfig {
+            name: "test_host".to_string(),
+            hostname: "192.168.1.1".to_string(),
+            port: 60022,
+            username: "testuser".to_string(),
+            ..Default::default()
+        });
+        config.save_to(&path).unwrap();
+
+        let loaded = Config::load_from(&path, || panic!("plaintext config must not ask for a key")).unwrap();
+        assert_eq!(loaded.hosts, config.hosts);
+        assert!(!loaded.is_encrypted());
+    }
+
+    #[test]
+    fn test_encrypted_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        let encrypted_path = Config::get_encrypted_path(&path);
+
+        let mut config = Config::new();
+        config.add_host(HostConfig {
+            name: "secret_host".to_string(),
+            hostname: "10.1.2.3".to_string(),
+            port: 60022,
+            username: "testuser".to_string(),
+            ..Default::default()
+        });
+        config.save_to(&path).unwrap();
+
+        // Switching to encrypted storage replaces the plaintext file
+        config.enable_encryption([5; 32]);
+        config.save_to(&path).unwrap();
+        assert!(!path.exists());
+        assert!(!fs::read_to_string(&encrypted_path).unwrap().contains("10.1.2.3"));
+
+        let loaded = Config::load_from(&path, || Ok([5; 32])).unwrap();
+        assert_eq!(loaded.hosts, config.hosts);
+        assert!(loaded.is_encrypted());
+        assert!(Config::load_from(&path, || Ok([6; 32])).is_err());
+
+        // And switching back restores it
+        let mut loaded = loaded;
+        loaded.disable_encryption();
+        loaded.save_to(&path).unwrap();
+        assert!(path.exists());
+        assert!(!encrypted_path.exists());
+    }
+
+    #[test]
+    fn test_hosts_with_tag() {
+        let mut config = Config::new();
+        for (name, tags) in [("web-2", vec!["web"]), ("db-1", vec!["db"]), ("web-1", vec!["web", "prod"])] {
+            config.add_host(HostConfig {
+                name: name.to_string(),
+                tags: tags.into_iter().map(String::from).collect(),
+                ..Default::default()
+            });
+        }
+
+        let names: Vec<&str> = config.hosts_with_tag("web").iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(names, ["web-1", "web-2"]);
+        assert!(config.hosts_with_tag("staging").is_empty());
+    }
+
+    fn host_via(name: &str, via: Option<&str>) -> HostConfig {
+        HostConfig {
+            name: name.to_string(),
+            via: via.map(String::from),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_jump_chain() {
+        let mut config = Config::new();
+        config.add_host(host_via("bastion", None));
+        config.add_host(host_via("gateway", Some("bastion")));
+        config.add_host(host_via("db", Some("gateway")));
+
+        let chain: Vec<&str> = config.jump_chain("db").unwrap().iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(chain, ["bastion", "gateway"]);
+        assert!(config.jump_chain("bastion").unwrap().is_empty());
+        assert_eq!(config.hosts_via("bastion")[0].name, "gateway");
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_reports_missing_hops_and_cycles() {
+        let mut config = Config::new();
+        config.add_host(host_via("db", Some("gateway")));
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Host 'db' goes via 'gateway', which is not configured"
+        );
+
+        config.add_host(host_via("gateway", Some("bastion")));
+        config.add_host(host_via("bastion", Some("db")));
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Jump hosts form a cycle: bastion -> db -> gateway -> bastion"
+        );
+    }
+
+    #[test]
+    fn test_load_rejects_invalid_via() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        let mut config = Config::new();
+        config.add_host(host_via("db", Some("missing")));
+        config.save_to(&config_path).unwrap();
+
+        let error = Config::load_from(&config_path, || unreachable!()).unwrap_err();
+        assert!(error.to_string().contains("Host 'db' goes via 'missing'"));
+    }
+
+    #[test]
+    fn test_unused_name() {
+        let mut config = Config::new();
+        assert_eq!(config.unused_name("prod"), "prod");
+
+        for name in ["prod", "prod-2"] {
+            config.add_host(HostConfig { name: name.to_string(), ..Default::default() });
+        }
+        assert_eq!(config.unused_name("prod"), "prod-3");
+    }
+
+    #[test]
+    fn test_find_asset() {
+        let mut config = Config::new();
+        config.add_host(HostConfig {
+            name: "bastion".to_string(),
+            hostname: "47.1.2.3".to_string(),
+            port: 60022,
+            username: "alice".to_string(),
+            assets: vec![Asset::parse("web-1=root@10.0.0.1").unwrap()],
+            ..Default::default()
+        });
+
+        let (bastion, asset) = config.find_asset("bastion/web-1").unwrap();
+        let host = bastion.for_asset(asset);
+        assert_eq!((host.hostname.as_str(), host.port), ("47.1.2.3", 60022));
+        assert_eq!(host.username, "alice#root#10.0.0.1");
+        assert!(host.assets.is_empty());
+
+        assert!(config.find_asset("bastion/web-2").is_none());
+        assert!(config.find_asset("bastion").is_none());
+        assert!(config.validate().is_ok());
+
+        config.hosts.get_mut("bastion").unwrap().assets.push(Asset::parse("web-1=admin@10.0.0.9").unwrap());
+        assert_eq!(
+            config.validate().unwrap_err().to_string(),
+            "Bastion 'bastion' has more than one asset named 'web-1'"
+        );
+    }
+}
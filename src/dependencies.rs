@@ -24,7 +24,8 @@ fn command_exists_windows(command: &str) -> bool {
         .unwrap_or(false)
 }
 
-/// Attempt to install sshpass on Unix systems
+/// Attempt to install sshpass on Unix systems. Installer output goes to
+/// stderr, as stdout may be an ssh connection when run as a ProxyCommand.
 #[cfg(unix)]
 fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
     // Try different package managers
@@ -35,6 +36,7 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
             .arg("install")
             .arg("-y")
             .arg("sshpass")
+            .stdout(std::io::stderr())
             .status()?;
         if status.success() {
             return Ok(());
@@ -46,6 +48,7 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
             .arg("install")
             .arg("-y")
             .arg("sshpass")
+            .stdout(std::io::stderr())
             .status()?;
         if status.success() {
             return Ok(());
@@ -57,6 +60,7 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
             .arg("install")
             .arg("-y")
             .arg("sshpass")
+            .stdout(std::io::stderr())
             .status()?;
         if status.success() {
             return Ok(());
@@ -66,6 +70,7 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
         let status = Command::new("brew")
             .arg("install")
             .arg("sshpass")
+            .stdout(std::io::stderr())
             .status()?;
         if status.success() {
             return Ok(());
@@ -77,6 +82,7 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
             .arg("-S")
             .arg("--noconfirm")
             .arg("sshpass")
+            .stdout(std::io::stderr())
             .status()?;
         if status.success() {
             return Ok(());
@@ -86,16 +92,34 @@ fn install_sshpass() -> Result<(), Box<dyn std::error::Error>> {
     Err("Unable to install sshpass. Please install it manually.".into())
 }
 
+/// Whether the external SSH tools needed for a connection are installed,
+/// without trying to install anything
+pub fn external_ssh_available(password_auth: bool) -> bool {
+    #[cfg(unix)]
+    {
+        command_exists("ssh") && (!password_auth || command_exists("sshpass"))
+    }
+
+    #[cfg(windows)]
+    {
+        if password_auth {
+            command_exists_windows("plink.exe")
+        } else {
+            command_exists_windows("ssh.exe")
+        }
+    }
+}
+
 /// Check if required SSH tools are available
 pub fn check_ssh_dependencies(password_auth: bool) -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(unix)]
     {
         if password_auth && !command_exists("sshpass") {
-            println!("sshpass is required for password-based authentication but not found.");
-            println!("Attempting to install sshpass...");
+            eprintln!("sshpass is required for password-based authentication but not found.");
+            eprintln!("Attempting to install sshpass...");
             
             match install_sshpass() {
-                Ok(_) => println!("sshpass installed successfully."),
+                Ok(_) => eprintln!("sshpass installed successfully."),
                 Err(e) => {
                     eprintln!("Failed to install sshpass: {}", e);
                     eprintln!("Please install sshpass manually:");
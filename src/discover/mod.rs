@@ -0,0 +1,327 @@
+//! Asset discovery from a bastion's login menu
+//!
+//! `discover` logs into the bastion like `connect` does, but on a
+//! pseudo-terminal of ours. The asset list shown before the menu prompt is
+//! the first page; further pages are requested until one shows no new
+//! assets. Rows are read from the table under a header line naming at least
+//! the host name and address columns.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use crate::config::Asset;
+
+/// Column titles of the asset name, in lower case
+const NAME_HEADERS: &[&str] = &["name", "hostname", "host name", "host", "asset", "asset name", "主机名", "主机名称", "资产名称", "名称"];
+
+/// Column titles of the asset address, in lower case
+const ADDRESS_HEADERS: &[&str] = &["ip", "address", "ip address", "host ip", "ip地址", "地址", "主机ip", "主机地址"];
+
+/// Column titles of the asset accounts, in lower case
+const ACCOUNT_HEADERS: &[&str] = &["account", "accounts", "user", "username", "system user", "账号", "账户", "主机账号", "主机账户", "系统用户"];
+
+/// Menu prompt used when neither the command line nor the host's menu
+/// steps give one
+pub const DEFAULT_PROMPT: &str = "*>";
+
+/// More pages than any menu has; guards against a pager that never ends
+#[cfg(unix)]
+const MAX_PAGES: usize = 100;
+
+/// How the menu is driven
+#[derive(Debug, Clone)]
+pub struct MenuCommands {
+    /// Pattern the menu prompt matches
+    pub prompt: String,
+    /// Typed to show the next page
+    pub next: String,
+    /// Typed to leave the menu
+    pub quit: String,
+    /// Account for assets listed without one
+    pub default_account: Option<String>,
+}
+
+/// Where each field is in a table row
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Columns {
+    count: usize,
+    name: usize,
+    address: usize,
+    account: Option<usize>,
+}
+
+/// Split a table line into trimmed cells: on `|` when the table has borders,
+/// otherwise on runs of two or more spaces
+fn split_cells(line: &str) -> Vec<&str> {
+    let line = line.trim();
+    if line.contains('|') {
+        return line.trim_matches('|').split('|').map(str::trim).collect();
+    }
+    let mut cells = Vec::new();
+    let mut start = 0;
+    // Where the current run of whitespace began, and its length
+    let mut gap = (0, 0);
+    for (index, c) in line.char_indices() {
+        if !c.is_whitespace() {
+            if gap.1 >= 2 {
+                cells.push(&line[start..gap.0]);
+                start = index;
+            }
+            gap = (index, 0);
+        } else {
+            if gap.1 == 0 {
+                gap.0 = index;
+            }
+            gap.1 += 1;
+        }
+    }
+    cells.push(&line[start..]);
+    cells
+}
+
+fn find_column(cells: &[&str], titles: &[&str]) -> Option<usize> {
+    cells.iter().position(|cell| titles.contains(&cell.to_lowercase().as_str()))
+}
+
+fn header_columns(cells: &[&str]) -> Option<Columns> {
+    Some(Columns {
+        count: cells.len(),
+        name: find_column(cells, NAME_HEADERS)?,
+        address: find_column(cells, ADDRESS_HEADERS)?,
+        account: find_column(cells, ACCOUNT_HEADERS),
+    })
+}
+
+/// Assets in the tables of `text`. Rows whose address is not an IP address
+/// are skipped, as are rows without an account when there is no default.
+pub fn parse_assets(text: &str, default_account: Option<&str>) -> Vec<Asset> {
+    let mut assets = Vec::new();
+    let mut columns = None;
+    for line in text.lines() {
+        let cells = split_cells(line);
+        if let Some(header) = header_columns(&cells) {
+            columns = Some(header);
+            continue;
+        }
+        let Some(columns) = columns.filter(|columns| cells.len() == columns.count) else {
+            continue;
+        };
+
+        let address = cells[columns.address];
+        if address.parse::<IpAddr>().is_err() {
+            continue;
+        }
+        // Several accounts are listed comma-separated; the first is used
+        let account = columns
+            .account
+            .and_then(|index| cells[index].split(',').map(str::trim).find(|account| !account.is_empty()))
+            .or(default_account);
+        let (name, Some(account)) = (cells[columns.name], account) else {
+            continue;
+        };
+        if name.is_empty() || name.contains('/') {
+            continue;
+        }
+        assets.push(Asset { name: name.to_string(), address: address.to_string(), account: account.to_string() });
+    }
+    assets
+}
+
+/// Add `found` to `assets`, skipping ones whose address and account are
+/// already there. Different assets with the same name are told apart by
+/// suffixes, as in `web-2`. Returns how many were added.
+pub fn merge_assets(assets: &mut Vec<Asset>, found: Vec<Asset>) -> usize {
+    let mut added = 0;
+    for mut asset in found {
+        if assets.iter().any(|known| known.address == asset.address && known.account == asset.account) {
+            continue;
+        }
+        if assets.iter().any(|known| known.name == asset.name) {
+            let base = asset.name.clone();
+            asset.name = (2..)
+                .map(|n| format!("{}-{}", base, n))
+                .find(|candidate| !assets.iter().any(|known| known.name == *candidate))
+                .unwrap_or_default();
+        }
+        assets.push(asset);
+        added += 1;
+    }
+    added
+}
+
+/// What discovery changed in a bastion's assets
+#[derive(Debug, Default, PartialEq)]
+pub struct Changes {
+    pub added: Vec<Asset>,
+    /// Before and after
+    pub changed: Vec<(Asset, Asset)>,
+    pub removed: Vec<Asset>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// One line per change: `+` added, `~` changed, `-` removed
+pub fn format_changes(changes: &Changes) -> String {
+    let mut lines = String::new();
+    for asset in &changes.added {
+        lines.push_str(&format!("  + {}\n", asset));
+    }
+    for (old, new) in &changes.changed {
+        lines.push_str(&format!("  ~ {} -> {}@{}\n", old, new.account, new.address));
+    }
+    for asset in &changes.removed {
+        lines.push_str(&format!("  - {}\n", asset));
+    }
+    lines
+}
+
+/// Compare the assets known before with the ones discovered, by name
+pub fn diff(before: &[Asset], after: &[Asset]) -> Changes {
+    let known: HashMap<&str, &Asset> = before.iter().map(|asset| (asset.name.as_str(), asset)).collect();
+    let mut changes = Changes::default();
+    for asset in after {
+        match known.get(asset.name.as_str()) {
+            None => changes.added.push(asset.clone()),
+            Some(&old) if old != asset => changes.changed.push((old.clone(), asset.clone())),
+            Some(_) => {}
+        }
+    }
+    changes.removed = before.iter().filter(|old| !after.iter().any(|asset| asset.name == old.name)).cloned().collect();
+    changes
+}
+
+/// Log in with `invocation` and read every page of the asset menu
+#[cfg(unix)]
+pub fn scrape(invocation: &crate::ssh::SshInvocation, commands: &MenuCommands, timeout: std::time::Duration) -> Result<Vec<Asset>, Box<dyn std::error::Error>> {
+    use crate::ssh::expect;
+
+    let mut pty = expect::spawn(invocation, None)?;
+    let result = (|| {
+        let mut assets = Vec::new();
+        let mut page = pty.expect(&commands.prompt, timeout)?;
+        for _ in 0..MAX_PAGES {
+            if merge_assets(&mut assets, parse_assets(&page, commands.default_account.as_deref())) == 0 {
+                break;
+            }
+            pty.send(&commands.next)?;
+            page = pty.expect(&commands.prompt, timeout)?;
+        }
+        Ok(assets)
+    })();
+    let _ = pty.send(&commands.quit);
+    pty.close()?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn asset(name: &str, address: &str, account: &str) -> Asset {
+        Asset { name: name.to_string(), address: address.to_string(), account: account.to_string() }
+    }
+
+    #[test]
+    fn test_parse_bordered_table() {
+        let text = "\
+Welcome, alice
++----+--------+-----------+-------------+
+| ID | Name   | IP        | Account     |
++----+--------+-----------+-------------+
+| 1  | web-1  | 10.0.0.1  | root, admin |
+| 2  | db-1   | 10.0.0.2  |             |
+| 3  | legacy | n/a       | root        |
++----+--------+-----------+-------------+
+Page 1/2
+";
+        assert_eq!(parse_assets(text, None), [asset("web-1", "10.0.0.1", "root")]);
+        assert_eq!(parse_assets(text, Some("ops"))[1], asset("db-1", "10.0.0.2", "ops"));
+    }
+
+    #[test]
+    fn test_parse_space_aligned_table() {
+        let text = "\
+  ID   主机名        IP地址         系统用户
+  1    web server    10.0.0.1       root
+  2    db-1          fd00::2        dba
+Opt> ";
+        assert_eq!(
+            parse_assets(text, None),
+            [asset("web server", "10.0.0.1", "root"), asset("db-1", "fd00::2", "dba")]
+        );
+        assert!(parse_assets("  1    web-1    10.0.0.1\n", Some("root")).is_empty());
+    }
+
+    #[test]
+    fn test_merge_assets() {
+        let mut assets = vec![asset("web", "10.0.0.1", "root")];
+        let found = vec![asset("web", "10.0.0.1", "root"), asset("web", "10.0.0.9", "root"), asset("db", "10.0.0.2", "dba")];
+
+        assert_eq!(merge_assets(&mut assets, found.clone()), 2);
+        assert_eq!(assets[1].name, "web-2");
+        assert_eq!(merge_assets(&mut assets, found), 0);
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = [asset("web", "10.0.0.1", "root"), asset("db", "10.0.0.2", "dba"), asset("old", "10.0.0.3", "root")];
+        let after = [asset("web", "10.0.0.1", "root"), asset("db", "10.0.0.20", "dba"), asset("cache", "10.0.0.4", "root")];
+
+        let changes = diff(&before, &after);
+        assert_eq!(changes.added, [asset("cache", "10.0.0.4", "root")]);
+        assert_eq!(changes.changed, [(asset("db", "10.0.0.2", "dba"), asset("db", "10.0.0.20", "dba"))]);
+        assert_eq!(changes.removed, [asset("old", "10.0.0.3", "root")]);
+        assert!(diff(&after, &after).is_empty());
+        assert_eq!(
+            format_changes(&changes),
+            "  + cache: root@10.0.0.4\n  ~ db: dba@10.0.0.2 -> dba@10.0.0.20\n  - old: root@10.0.0.3\n"
+        );
+    }
+
+    /// A paging menu like the bastion's: two pages of assets, after which
+    /// `n` shows the last page again
+    #[cfg(unix)]
+    const FAKE_MENU: &str = r#"
+        page=1
+        while :; do
+            printf '| ID | Hostname | IP | Account |\r\n'
+            if [ $page = 1 ]; then
+                printf '| 1 | web-1 | 10.0.0.1 | root |\r\n| 2 | web-2 | 10.0.0.2 | root |\r\n'
+            else
+                printf '| 3 | db-1 | 10.0.0.3 | dba |\r\n'
+            fi
+            printf 'Page %s/2  n: next  q: quit\r\nOpt> ' $page
+            read command
+            case $command in
+                n) page=2 ;;
+                q) exit 0 ;;
+            esac
+        done
+    "#;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scrape_pages_through_fake_menu() {
+        let invocation = crate::ssh::SshInvocation::new("sh").arg("-c").arg(FAKE_MENU);
+        let commands = MenuCommands { prompt: "Opt>".to_string(), next: "n".to_string(), quit: "q".to_string(), default_account: None };
+
+        let assets = scrape(&invocation, &commands, std::time::Duration::from_secs(10)).unwrap();
+        let names: Vec<&str> = assets.iter().map(|asset| asset.name.as_str()).collect();
+        assert_eq!(names, ["web-1", "web-2", "db-1"]);
+        assert_eq!(assets[2].account, "dba");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scrape_fails_without_prompt() {
+        let invocation = crate::ssh::SshInvocation::new("sh").arg("-c").arg("echo 'Permission denied'; exit 255");
+        let commands = MenuCommands { prompt: "Opt>".to_string(), next: "n".to_string(), quit: "q".to_string(), default_account: None };
+
+        let error = scrape(&invocation, &commands, std::time::Duration::from_secs(10)).unwrap_err();
+        assert!(error.to_string().contains("Permission denied"), "{}", error);
+    }
+}
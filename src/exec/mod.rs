@@ -0,0 +1,261 @@
+//! Running one command on many hosts
+//!
+//! Each host's command is started from a prepared `SshInvocation` on a
+//! bounded pool of threads. Output is either streamed line by line with the
+//! host name in front, or collected so that hosts with identical output can
+//! be shown together.
+
+use std::io::{BufRead, BufReader, Read};
+use std::process::Stdio;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::ssh::SshInvocation;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Line {
+    pub stream: Stream,
+    pub text: String,
+}
+
+#[derive(Debug)]
+pub struct HostResult {
+    pub name: String,
+    /// Exit code, or why the command could not be run
+    pub status: Result<i32, String>,
+    pub duration: Duration,
+    pub output: Vec<Line>,
+}
+
+impl HostResult {
+    /// A host on which the command was never started
+    pub fn failed(name: &str, error: String) -> Self {
+        HostResult {
+            name: name.to_string(),
+            status: Err(error),
+            duration: Duration::ZERO,
+            output: Vec::new(),
+        }
+    }
+
+    pub fn success(&self) -> bool {
+        self.status == Ok(0)
+    }
+}
+
+/// Run `job` on every item using at most `concurrency` threads. Results are
+/// returned in the order of `items`.
+pub fn run_parallel<T, R, F>(items: Vec<T>, concurrency: usize, job: F) -> Vec<R>
+where
+    T: Send,
+    R: Send,
+    F: Fn(T) -> R + Sync,
+{
+    let workers = concurrency.clamp(1, items.len().max(1));
+    let queue = Mutex::new(items.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| loop {
+                let next = queue.lock().unwrap().next();
+                let Some((index, item)) = next else {
+                    break;
+                };
+                let result = job(item);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Run `invocation` with stdin closed and collect its output. When `prefix`
+/// is given, each line is also printed as it arrives with the prefix in
+/// front.
+pub fn run_invocation(name: &str, invocation: &SshInvocation, prefix: Option<&str>) -> HostResult {
+    let start = Instant::now();
+    let mut output = Vec::new();
+    let status = capture(invocation, |line| {
+        if let Some(prefix) = prefix {
+            print_line(prefix, &line);
+        }
+        output.push(line);
+    });
+
+    HostResult {
+        name: name.to_string(),
+        status: status.map_err(|e| e.to_string()),
+        duration: start.elapsed(),
+        output,
+    }
+}
+
+fn capture(invocation: &SshInvocation, mut on_line: impl FnMut(Line)) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut child = invocation
+        .to_command()
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to execute SSH command: {}", e))?;
+
+    let (sender, receiver) = mpsc::channel();
+    let stdout = child.stdout.take().ok_or("Failed to capture command output")?;
+    let stderr = child.stderr.take().ok_or("Failed to capture command output")?;
+    forward_lines(stdout, Stream::Stdout, sender.clone());
+    forward_lines(stderr, Stream::Stderr, sender);
+
+    // Ends once both pipes are closed
+    for line in receiver {
+        on_line(line);
+    }
+
+    let status = child.wait()?;
+    Ok(status.code().unwrap_or(255))
+}
+
+fn forward_lines<R: Read + Send + 'static>(pipe: R, stream: Stream, sender: mpsc::Sender<Line>) {
+    thread::spawn(move || {
+        for chunk in BufReader::new(pipe).split(b'\n') {
+            let Ok(chunk) = chunk else {
+                break;
+            };
+            let text = String::from_utf8_lossy(&chunk).trim_end_matches('\r').to_string();
+            if sender.send(Line { stream, text }).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+/// Print a line to stdout or stderr, matching where the host wrote it
+pub fn print_line(prefix: &str, line: &Line) {
+    match line.stream {
+        Stream::Stdout => println!("{}{}", prefix, line.text),
+        Stream::Stderr => eprintln!("{}{}", prefix, line.text),
+    }
+}
+
+/// Hosts with the same output and exit code
+pub struct Group<'a> {
+    pub names: Vec<&'a str>,
+    pub code: i32,
+    pub output: &'a [Line],
+}
+
+/// Group hosts whose output and exit code are identical, in order of first
+/// appearance
+pub fn group_identical(results: &[HostResult]) -> Vec<Group<'_>> {
+    let mut groups: Vec<Group> = Vec::new();
+    for result in results {
+        let Ok(code) = result.status else { continue };
+        match groups.iter_mut().find(|group| group.code == code && group.output == result.output.as_slice()) {
+            Some(group) => group.names.push(&result.name),
+            None => groups.push(Group { names: vec![&result.name], code, output: &result.output }),
+        }
+    }
+    groups
+}
+
+/// Table of exit codes and durations, one row per host
+pub fn format_summary(results: &[HostResult]) -> String {
+    let width = results.iter().map(|result| result.name.len()).chain(["HOST".len()]).max().unwrap_or(0);
+    let mut summary = format!("{:<width$}  {:>4}  {:>8}\n", "HOST", "EXIT", "DURATION");
+    for result in results {
+        let (exit, note) = match &result.status {
+            Ok(code) => (code.to_string(), String::new()),
+            Err(e) => ("-".to_string(), format!("  {}", e)),
+        };
+        summary.push_str(&format!(
+            "{:<width$}  {:>4}  {:>7.1}s{}\n",
+            result.name, exit, result.duration.as_secs_f64(), note
+        ));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn result(name: &str, status: Result<i32, String>, secs: u64, output: &[&str]) -> HostResult {
+        HostResult {
+            name: name.to_string(),
+            status,
+            duration: Duration::from_secs(secs),
+            output: output.iter().map(|text| Line { stream: Stream::Stdout, text: text.to_string() }).collect(),
+        }
+    }
+
+    #[test]
+    fn test_run_parallel_respects_concurrency_and_order() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let results = run_parallel((0..12).collect(), 3, |n: u32| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(20));
+            running.fetch_sub(1, Ordering::SeqCst);
+            n * 2
+        });
+
+        assert_eq!(results, (0..12).map(|n| n * 2).collect::<Vec<_>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_invocation_captures_streams_and_exit_code() {
+        let invocation = SshInvocation::new("sh").arg("-c").arg("echo out; echo err >&2; exit 3");
+
+        let result = run_invocation("web-1", &invocation, None);
+        assert_eq!(result.status, Ok(3));
+        assert!(result.output.contains(&Line { stream: Stream::Stdout, text: "out".to_string() }));
+        assert!(result.output.contains(&Line { stream: Stream::Stderr, text: "err".to_string() }));
+    }
+
+    #[test]
+    fn test_group_identical() {
+        let results = [
+            result("web-1", Ok(0), 1, &["ok"]),
+            result("web-2", Ok(0), 1, &["disk full"]),
+            result("web-3", Ok(0), 1, &["ok"]),
+            result("web-4", Err("Host key verification failed".to_string()), 0, &[]),
+            result("web-5", Ok(1), 1, &["ok"]),
+        ];
+
+        let groups = group_identical(&results);
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].names, ["web-1", "web-3"]);
+        assert_eq!(groups[1].names, ["web-2"]);
+        assert_eq!((groups[2].names.as_slice(), groups[2].code), (["web-5"].as_slice(), 1));
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let results = [
+            result("web-1", Ok(0), 2, &[]),
+            result("database", Err("Failed to decrypt password".to_string()), 0, &[]),
+        ];
+
+        assert_eq!(
+            format_summary(&results),
+            "HOST      EXIT  DURATION\n\
+             web-1        0      2.0s\n\
+             database     -      0.0s  Failed to decrypt password\n"
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crossterm::{
 };
 use std::io::{self, Write};
 
+#[allow(clippy::collapsible_match, clippy::implicit_saturating_sub)]
 pub fn select_host_interactively(hosts: Vec<&crate::config::HostConfig>) -> Option<String> {
     if hosts.is_empty() {
         println!("No hosts configured");
@@ -1,7 +1,19 @@
-use crate::config::{Config, HostConfig};
+use crate::config::{Config, Forward, ForwardKind, HostConfig};
 use crate::ssh;
-use crate::commands::types::{AddArgs, RemoveArgs, ConnectArgs};
-use crate::utils::encryption::{encrypt_password, decrypt_password};
+use crate::ssh::transfer::{self, Direction};
+use crate::aliyun::{self, bastionhost};
+use crate::bundle;
+use crate::discover;
+use crate::exec;
+use crate::ssh_config;
+use crate::tunnel;
+use crate::commands::types::{AddArgs, RemoveArgs, ConnectArgs, ExecArgs, PutArgs, GetArgs, TunnelArgs, TunnelCommand, TunnelStopArgs, ProxyArgs, DiscoverArgs, SyncArgs, SyncCommand, SyncAliyunArgs, SshConfigArgs, SshConfigCommand, AgentArgs, AgentCommand, OtpArgs, ConfigArgs, ConfigCommand, ExportArgs, ImportArgs, ImportCommand, ImportSshConfigArgs, ConflictPolicy};
+use crate::utils::encryption::{encrypt_secret, decrypt_secret, is_legacy_ciphertext, get_or_create_key, key_from_agent, offer_key_to_agent, Key};
+use crate::utils::secret::Secret;
+use crate::utils::keystore;
+use crate::utils::password_command::{self, run_password_command};
+use crate::utils::totp::Totp;
+use std::fs;
 mod interactive;
 
 pub fn handle_add(args: AddArgs) -> Result<(), Box<dyn std::error::Error>> {
@@ -13,10 +25,27 @@ pub fn handle_add(args: AddArgs) -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
     
+    if let Some(via) = args.via.as_deref().filter(|via| config.get_host(via).is_none()) {
+        println!("Error: Jump host '{}' not found. Add it first.", via);
+        return Ok(());
+    }
+    
+    // Reject malformed TOTP secrets before asking for the passphrase
+    if let Some(Err(e)) = args.totp.as_deref().map(Totp::parse) {
+        println!("Error: Invalid TOTP secret: {}", e);
+        return Ok(());
+    }
+    
+    let key = if args.password.is_some() || args.totp.is_some() {
+        Some(unlock_vault(&config)?)
+    } else {
+        None
+    };
+    
     // Encrypt password if provided
-    let encrypted_password = match args.password {
-        Some(password) => {
-            match encrypt_password(&password) {
+    let encrypted_password = match (args.password, key.as_ref()) {
+        (Some(password), Some(key)) => {
+            match encrypt_secret(&Secret::new(password), key) {
                 Ok(encrypted) => Some(encrypted),
                 Err(e) => {
                     println!("Error encrypting password: {}", e);
@@ -24,7 +53,13 @@ pub fn handle_add(args: AddArgs) -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
         }
-        None => None,
+        _ => None,
+    };
+    
+    // Encrypt TOTP secret if provided
+    let encrypted_totp = match (args.totp, key.as_ref()) {
+        (Some(totp), Some(key)) => Some(encrypt_secret(&Secret::from(totp.trim()), key)?),
+        _ => None,
     };
     
     let host = HostConfig {
@@ -33,17 +68,37 @@ pub fn handle_add(args: AddArgs) -> Result<(), Box<dyn std::error::Error>> {
         port: args.port,
         username: args.username,
         password: encrypted_password,
+        totp_secret: encrypted_totp,
+        password_command: args.password_command,
+        identity_file: args.identity_file,
+        via: args.via,
+        tags: args.tags,
+        forwards: args.forwards,
+        assets: args.assets,
+        menu: args.menu,
+        notes: args.note,
     };
     config.add_host(host);
+    if let Err(e) = config.validate() {
+        println!("Error: {}", e);
+        return Ok(());
+    }
     config.save()?;
+    refresh_ssh_config(&config);
     println!("Host '{}' added successfully", args.name);
     Ok(())
 }
 
 pub fn handle_remove(args: RemoveArgs) -> Result<(), Box<dyn std::error::Error>> {
     let mut config = Config::load()?;
+    let dependents: Vec<&str> = config.hosts_via(&args.name).iter().map(|host| host.name.as_str()).collect();
+    if !dependents.is_empty() {
+        println!("Host '{}' is the jump host of {}. Remove those first or change their jump host.", args.name, dependents.join(", "));
+        return Ok(());
+    }
     if config.remove_host(&args.name) {
         config.save()?;
+        refresh_ssh_config(&config);
         println!("Host '{}' removed successfully", args.name);
         Ok(())
     } else {
@@ -61,15 +116,34 @@ pub fn handle_list() -> Result<(), Box<dyn std::error::Error>> {
     } else {
         println!("Configured hosts:");
         for host in hosts {
-            let password_status = if host.password.is_some() { "(encrypted password)" } else { "" };
-            println!("  - {}: {}@{}:{} {}", host.name, host.username, host.hostname, host.port, password_status);
+            let password_status = if host.password.is_some() {
+                "(encrypted password)"
+            } else if host.password_command.is_some() {
+                "(password command)"
+            } else {
+                ""
+            };
+            let mfa_status = if host.totp_secret.is_some() { "(MFA)" } else { "" };
+            let tags = if host.tags.is_empty() { String::new() } else { format!("[{}]", host.tags.join(", ")) };
+            let via = host.via.as_ref().map(|via| format!("(via {})", via)).unwrap_or_default();
+            println!("  - {}: {}@{}:{} {} {} {} {}", host.name, host.username, host.hostname, host.port, password_status, mfa_status, via, tags);
+            if let Some(notes) = &host.notes {
+                println!("      note: {}", notes);
+            }
+            for forward in &host.forwards {
+                println!("      forward {}", forward);
+            }
+            for (index, asset) in host.assets.iter().enumerate() {
+                let branch = if index + 1 == host.assets.len() { "└──" } else { "├──" };
+                println!("      {} {}", branch, asset);
+            }
         }
     }
     Ok(())
 }
 
 pub fn handle_connect(args: ConnectArgs) -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::load()?;
+    let mut config = Config::load()?;
     let hosts = config.list_hosts();
     
     // If no hosts configured, show message and return
@@ -83,10 +157,13 @@ pub fn handle_connect(args: ConnectArgs) -> Result<(), Box<dyn std::error::Error
         // Direct mode - user provided host name
         match config.get_host(&host_name) {
             Some(host) => host.clone(),
-            None => {
-                println!("Host '{}' not found", host_name);
-                return Ok(());
-            }
+            None => match config.find_asset(&host_name) {
+                Some((bastion, asset)) => bastion.for_asset(asset),
+                None => {
+                    println!("Host '{}' not found", host_name);
+                    return Ok(());
+                }
+            },
         }
     } else {
         // Interactive mode - let user select from list
@@ -107,28 +184,1150 @@ pub fn handle_connect(args: ConnectArgs) -> Result<(), Box<dyn std::error::Error
         }
     };
     
-    // Decrypt password if it exists
-    let decrypted_password = match host_to_connect.password {
-        Some(ref encrypted_password) => {
-            match decrypt_password(encrypted_password) {
-                Ok(decrypted) => Some(decrypted),
+    if args.dry_run {
+        print_invocation(&config, &host_to_connect);
+        return Ok(());
+    }
+    
+    let jump = jump_for(&config, &host_to_connect)?;
+    let (decrypted_password, decrypted_totp) = resolve_credentials(&mut config, &host_to_connect)?;
+    
+    // Connect to the selected host
+    ssh::connect_to_host(&ssh_target(&host_to_connect, jump.as_ref(), decrypted_password.as_ref(), decrypted_totp.as_ref()))?;
+    
+    Ok(())
+}
+
+/// Run a command on one or more hosts and return the exit code to exit with
+pub fn handle_exec(args: ExecArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+
+    let Some(group) = select_exec_hosts(&config, &args) else {
+        return Ok(1);
+    };
+    let hosts: Vec<HostConfig> = group.into_iter().cloned().collect();
+
+    // A single named host gets the terminal to itself, like `connect`
+    if args.name.is_some() {
+        let host = &hosts[0];
+        let jump = jump_for(&config, host)?;
+        let (password, totp) = resolve_credentials(&mut config, host)?;
+        let target = ssh_target(host, jump.as_ref(), password.as_ref(), totp.as_ref());
+        return ssh::run_command(&target, &args.command);
+    }
+
+    // Jump hosts reached through `ali-bastion proxy` unlock the vault in
+    // each proxy, which would all ask for the passphrase at once
+    let proxied: Vec<&str> = hosts.iter().filter(|host| hops_have_stored_secrets(&config, host)).map(|host| host.name.as_str()).collect();
+    if args.concurrency > 1 && proxied.len() > 1 && key_from_agent().is_none() {
+        println!(
+            "Error: {} are reached through jump hosts with stored secrets, so every connection would ask for the master passphrase at the same time. Unlock it once with 'ali-bastion agent', or run one host at a time with -j 1.",
+            proxied.join(", ")
+        );
+        return Ok(1);
+    }
+
+    // Unlock once for all hosts rather than once per host
+    let key = if hosts.iter().any(has_stored_secrets) {
+        Some(unlock_vault(&config)?)
+    } else {
+        None
+    };
+
+    let mut resolved = Vec::with_capacity(hosts.len());
+    for host in &hosts {
+        let credentials = jump_for(&config, host).and_then(|jump| {
+            let (password, totp) = decrypt_credentials(&mut config, host, key.as_ref())?;
+            Ok((jump, password, totp))
+        });
+        resolved.push((host, credentials.map_err(|e| e.to_string())));
+    }
+    let targets: Vec<(&str, Result<ssh::Target, String>)> = resolved
+        .iter()
+        .map(|(host, credentials)| {
+            let target = credentials.as_ref().map(|(jump, password, totp)| ssh_target(host, jump.as_ref(), password.as_ref(), totp.as_ref()));
+            (host.name.as_str(), target.map_err(Clone::clone))
+        })
+        .collect();
+
+    // Checked once up front rather than for every host
+    ssh::check_dependencies(targets.iter().filter_map(|(_, target)| target.as_ref().ok()))?;
+    let jobs: Vec<_> = targets
+        .into_iter()
+        .map(|(name, target)| {
+            let invocation = target.and_then(|target| ssh::connector(&target).command_invocation(&target, &args.command).map_err(|e| e.to_string()));
+            (name, invocation)
+        })
+        .collect();
+
+    let width = hosts.iter().map(|host| host.name.len()).max().unwrap_or(0);
+    let results = exec::run_parallel(jobs, args.concurrency.into(), |(name, invocation)| match invocation {
+        Ok(invocation) => {
+            let prefix = format!("{:width$} | ", name);
+            exec::run_invocation(name, &invocation, (!args.collapse).then_some(prefix.as_str()))
+        }
+        Err(e) => exec::HostResult::failed(name, e),
+    });
+
+    if args.collapse {
+        for group in exec::group_identical(&results) {
+            let names = &group.names;
+            println!("==> {} ({} host{}, exit {})", names.join(", "), names.len(), if names.len() == 1 { "" } else { "s" }, group.code);
+            for line in group.output {
+                exec::print_line("", line);
+            }
+        }
+    }
+
+    println!();
+    print!("{}", exec::format_summary(&results));
+    Ok(if results.iter().all(exec::HostResult::success) { 0 } else { 1 })
+}
+
+/// Upload a file or directory and return scp's exit code
+pub fn handle_put(args: PutArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    transfer_files(&args.remote, &args.local, Direction::Upload, args.recursive)
+}
+
+/// Download a file or directory and return scp's exit code
+pub fn handle_get(args: GetArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    transfer_files(&args.remote, &args.local, Direction::Download, args.recursive)
+}
+
+fn transfer_files(remote_spec: &str, local: &str, direction: Direction, recursive: bool) -> Result<i32, Box<dyn std::error::Error>> {
+    let Some((name, remote)) = transfer::parse_remote(remote_spec) else {
+        println!("Error: Remote path must be given as <host>:<path>, got '{}'", remote_spec);
+        return Ok(1);
+    };
+
+    let mut config = Config::load()?;
+    let host = match config.get_host(name) {
+        Some(host) => host.clone(),
+        None => {
+            println!("Host '{}' not found", name);
+            return Ok(1);
+        }
+    };
+
+    let jump = jump_for(&config, &host)?;
+    let (password, totp) = resolve_credentials(&mut config, &host)?;
+    let target = ssh_target(&host, jump.as_ref(), password.as_ref(), totp.as_ref());
+    let transfer = ssh::Transfer { direction, local, remote, recursive };
+
+    match direction {
+        Direction::Upload => println!("Copying {} to {}:{}...", local, host.name, remote),
+        Direction::Download => println!("Copying {}:{} to {}...", host.name, remote, local),
+    }
+    ssh::copy(&target, &transfer)
+}
+
+pub fn handle_tunnel(args: TunnelArgs) -> Result<(), Box<dyn std::error::Error>> {
+    match args.command {
+        Some(TunnelCommand::Ls) => list_tunnels(),
+        Some(TunnelCommand::Stop(stop_args)) => stop_tunnels(stop_args),
+        None => open_tunnel(args),
+    }
+}
+
+fn open_tunnel(args: TunnelArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let name = args.name.as_deref().unwrap_or_default();
+    let host = match config.get_host(name) {
+        Some(host) => host.clone(),
+        None => {
+            println!("Host '{}' not found", name);
+            return Ok(());
+        }
+    };
+    let Some(forwards) = select_forwards(&host, &args) else {
+        return Ok(());
+    };
+
+    // Refuse before connecting rather than let ssh fail on a busy port
+    let run_dir = tunnel::get_run_dir();
+    let (running, _) = tunnel::load(&run_dir)?;
+    if let Some((record, open)) = tunnel::find_conflict(&running, &host.name, &forwards) {
+        println!(
+            "Forward '{}' is already open in tunnel {} through '{}'. Stop it first with 'tunnel stop {}'.",
+            open, record.id, record.host, record.id
+        );
+        return Ok(());
+    }
+    if let Err(e) = tunnel::check_ports_free(&forwards) {
+        println!("{}", e);
+        return Ok(());
+    }
+
+    let jump = jump_for(&config, &host)?;
+    let (password, totp) = resolve_credentials(&mut config, &host)?;
+    let target = ssh_target(&host, jump.as_ref(), password.as_ref(), totp.as_ref());
+
+    if !args.detach {
+        println!("Opening tunnel through {}:", host.name);
+        for forward in &forwards {
+            println!("  {}", forward);
+        }
+        println!("Press Ctrl+C to close.");
+        return ssh::open_tunnel(&target, &forwards);
+    }
+
+    let invocation = ssh::tunnel_invocation(&target, &forwards)?;
+    let id = tunnel::reserve_id(&run_dir, &running)?;
+    let log_path = tunnel::log_path(&run_dir, id);
+    let spawned = fs::File::create(&log_path).map_err(Into::into).and_then(|log| invocation.spawn_detached(log));
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            tunnel::remove(&run_dir, id)?;
+            return Err(e);
+        }
+    };
+
+    if let Err(e) = tunnel::wait_until_ready(&mut child, &forwards, std::time::Duration::from_secs(15)) {
+        tunnel::stop_group(child.id());
+        let _ = child.wait();
+        let output = fs::read_to_string(&log_path).unwrap_or_default();
+        tunnel::remove(&run_dir, id)?;
+        println!("Failed to start tunnel through '{}': {}", host.name, e);
+        if !output.trim().is_empty() {
+            println!("{}", output.trim_end());
+        }
+        return Ok(());
+    }
+
+    tunnel::save(&run_dir, &tunnel::TunnelRecord::new(id, &host.name, child.id(), forwards.clone()))?;
+    println!("Tunnel {} open through {} (pid {}):", id, host.name, child.id());
+    for forward in &forwards {
+        println!("  {}", forward);
+    }
+    println!("Stop it with 'tunnel stop {}'.", id);
+    Ok(())
+}
+
+/// Saved forwards named with `--forward` plus ad hoc `-L`/`-R`/`-D` ones, or
+/// all saved forwards when none are given. `None` after reporting a problem.
+fn select_forwards(host: &HostConfig, args: &TunnelArgs) -> Option<Vec<Forward>> {
+    let mut forwards = Vec::new();
+    for name in &args.forwards {
+        match host.forwards.iter().find(|forward| forward.name == *name) {
+            Some(forward) => forwards.push(forward.clone()),
+            None => {
+                let available: Vec<&str> = host.forwards.iter().map(|forward| forward.name.as_str()).collect();
+                println!("Host '{}' has no forward '{}'. Available: {}", host.name, name, available.join(", "));
+                return None;
+            }
+        }
+    }
+
+    let ad_hoc = [(ForwardKind::Local, &args.local), (ForwardKind::Remote, &args.remote), (ForwardKind::Dynamic, &args.dynamic)];
+    for (kind, specs) in ad_hoc {
+        for spec in specs {
+            match Forward::from_option("", kind, spec) {
+                // Named after the port, as they are not saved
+                Ok(forward) => forwards.push(Forward { name: forward.port.to_string(), ..forward }),
                 Err(e) => {
-                    println!("Warning: Failed to decrypt password: {}", e);
-                    None
+                    println!("Error: {}", e);
+                    return None;
                 }
             }
         }
-        None => None,
+    }
+
+    if forwards.is_empty() {
+        if host.forwards.is_empty() {
+            println!("Host '{}' has no forwards. Add them with 'add --forward name=L:port:host:hostport' or pass -L, -R or -D.", host.name);
+            return None;
+        }
+        forwards = host.forwards.clone();
+    }
+    Some(forwards)
+}
+
+fn list_tunnels() -> Result<(), Box<dyn std::error::Error>> {
+    let (running, stale) = tunnel::load(&tunnel::get_run_dir())?;
+    for record in &stale {
+        println!("Tunnel {} through '{}' is no longer running; removed it", record.id, record.host);
+    }
+    if running.is_empty() {
+        println!("No tunnels running");
+        return Ok(());
+    }
+    print!("{}", tunnel::format_list(&running, crate::utils::now()));
+    Ok(())
+}
+
+fn stop_tunnels(args: TunnelStopArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let run_dir = tunnel::get_run_dir();
+    let (running, _) = tunnel::load(&run_dir)?;
+    let selected: Vec<&tunnel::TunnelRecord> = match args.id {
+        Some(id) => running.iter().filter(|record| record.id == id).collect(),
+        None => running.iter().collect(),
+    };
+    if selected.is_empty() {
+        match args.id {
+            Some(id) => println!("No tunnel {} is running", id),
+            None => println!("No tunnels running"),
+        }
+        return Ok(());
+    }
+
+    for record in selected {
+        if tunnel::stop(record) {
+            tunnel::remove(&run_dir, record.id)?;
+            println!("Stopped tunnel {} through '{}'", record.id, record.host);
+        } else {
+            println!("Failed to stop tunnel {} (pid {})", record.id, record.pid);
+        }
+    }
+    Ok(())
+}
+
+/// Relay stdin and stdout to a host for ssh's ProxyCommand. stdout carries
+/// the connection, so problems are reported on stderr.
+pub fn handle_proxy(args: ProxyArgs) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let Some(host) = config.get_host(&args.name).cloned() else {
+        eprintln!("Host '{}' not found", args.name);
+        return Ok(1);
+    };
+
+    // The configured jump host is used unless another one is given
+    let Some(via) = args.via.as_ref().or(host.via.as_ref()) else {
+        ssh::proxy::relay_stdio(&host.hostname, host.port)?;
+        return Ok(0);
+    };
+    let Some(via_host) = config.get_host(via).cloned() else {
+        eprintln!("Host '{}' not found", via);
+        return Ok(1);
+    };
+    let jump = jump_for(&config, &via_host)?;
+    let (password, totp) = resolve_credentials(&mut config, &via_host)?;
+    let target = ssh_target(&via_host, jump.as_ref(), password.as_ref(), totp.as_ref());
+    ssh::proxy_through(&target, &ssh::proxy::destination(&host.hostname, host.port))
+}
+
+/// Log into a bastion, read the assets its menu lists and store them as the
+/// host's assets
+#[cfg(unix)]
+pub fn handle_discover(args: DiscoverArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let Some(host) = config.get_host(&args.name).cloned() else {
+        println!("Host '{}' not found", args.name);
+        return Ok(());
+    };
+    let commands = discover::MenuCommands {
+        prompt: args
+            .prompt
+            .or_else(|| host.menu.first().map(|step| step.prompt.clone()))
+            .unwrap_or_else(|| discover::DEFAULT_PROMPT.to_string()),
+        next: args.next,
+        quit: args.quit,
+        default_account: args.account,
+    };
+
+    // Logged in like `connect`, minus the menu steps, which would pick an asset
+    let jump = jump_for(&config, &host)?;
+    let (password, totp) = resolve_credentials(&mut config, &host)?;
+    let target = ssh::Target { menu: &[], ..ssh_target(&host, jump.as_ref(), password.as_ref(), totp.as_ref()) };
+    let connector = ssh::connector(&target);
+    connector.check_dependencies(&target)?;
+    let invocation = connector.invocation(&target)?;
+
+    println!("Reading the asset menu of '{}'...", host.name);
+    let found = discover::scrape(&invocation, &commands, std::time::Duration::from_secs(30))?;
+    if found.is_empty() {
+        println!(
+            "No assets found. Check that the menu prompt matches '{}' (--prompt) and that assets are listed with a header naming the host name and IP columns.",
+            commands.prompt
+        );
+        return Ok(());
+    }
+
+    let changes = discover::diff(&host.assets, &found);
+    print!("{}", discover::format_changes(&changes));
+    println!(
+        "Found {} asset(s) on '{}': {} added, {} changed, {} removed",
+        found.len(),
+        host.name,
+        changes.added.len(),
+        changes.changed.len(),
+        changes.removed.len()
+    );
+    if args.dry_run {
+        println!("Dry run; nothing was saved");
+        return Ok(());
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(bastion) = config.hosts.get_mut(&host.name) {
+        bastion.assets = found;
+    }
+    config.validate()?;
+    config.save()?;
+    println!("Saved. Connect with 'connect {}/<asset>'.", host.name);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn handle_discover(_args: DiscoverArgs) -> Result<(), Box<dyn std::error::Error>> {
+    Err("Asset discovery is only supported on Unix systems".into())
+}
+
+pub fn handle_sync(args: SyncArgs) -> Result<(), Box<dyn std::error::Error>> {
+    match args.command {
+        SyncCommand::Aliyun(args) => sync_aliyun(args),
+    }
+}
+
+/// Bring the assets of the bastion, or with `--direct` the hosts tagged
+/// with the instance, in line with the hosts its OpenAPI lists
+fn sync_aliyun(args: SyncAliyunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    if let Some(bastion) = args.bastion.as_deref().filter(|bastion| config.get_host(bastion).is_none()) {
+        println!("Error: Bastion host '{}' not found. Add the host that logs into the instance first.", bastion);
+        return Ok(());
+    }
+    if let Some(via) = args.via.as_deref().filter(|via| config.get_host(via).is_none()) {
+        println!("Error: Jump host '{}' not found. Add it first.", via);
+        return Ok(());
+    }
+
+    let credentials = aliyun::load_credentials(&aliyun::get_cli_config_path(), args.profile.as_deref())?;
+    let Some(region) = args.region.clone().or_else(|| credentials.region.clone()) else {
+        println!("Error: No region given. Pass --region or set a region in the aliyun profile.");
+        return Ok(());
+    };
+    let endpoint = args.endpoint.clone().unwrap_or_else(|| bastionhost::endpoint(&region));
+    let api = bastionhost::Bastionhost::new(aliyun::Client::new(&endpoint, bastionhost::VERSION, credentials), &args.instance, &region);
+
+    println!("Reading the hosts of '{}'...", args.instance);
+    let remote = api.hosts_with_accounts()?;
+    match args.bastion.clone() {
+        Some(bastion) => sync_aliyun_assets(&mut config, &bastion, &remote, &args),
+        None => sync_aliyun_hosts(&mut config, &remote, args),
+    }
+}
+
+/// Replace the assets of `bastion` with the hosts of the instance
+fn sync_aliyun_assets(
+    config: &mut Config,
+    bastion: &str,
+    remote: &[bastionhost::HostWithAccounts],
+    args: &SyncAliyunArgs,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (assets, skipped) = aliyun::sync::logins(remote, args.account.as_deref());
+    let changes = discover::diff(&config.hosts[bastion].assets, &assets);
+    print!("{}{}", discover::format_changes(&changes), aliyun::sync::format_skipped(&skipped));
+    println!(
+        "Found {} host(s) on '{}': {} added, {} changed, {} removed",
+        remote.len(),
+        args.instance,
+        changes.added.len(),
+        changes.changed.len(),
+        changes.removed.len()
+    );
+    if args.dry_run {
+        println!("Dry run; nothing was saved");
+        return Ok(());
+    }
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    if let Some(host) = config.hosts.get_mut(bastion) {
+        host.assets = assets;
+    }
+    config.validate()?;
+    config.save()?;
+    println!("Saved. Connect with 'connect {}/<asset>'.", bastion);
+    Ok(())
+}
+
+/// Add, update and remove the hosts tagged with the instance
+fn sync_aliyun_hosts(config: &mut Config, remote: &[bastionhost::HostWithAccounts], args: SyncAliyunArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let options = aliyun::sync::Options { port: args.port, via: args.via, account: args.account };
+    let plan = aliyun::sync::plan(config, &args.instance, remote, &options);
+    print!("{}", aliyun::sync::format_plan(&plan));
+    println!(
+        "Found {} host(s) on '{}': {} added, {} changed, {} removed",
+        remote.len(),
+        args.instance,
+        plan.added.len(),
+        plan.changed.len(),
+        plan.removed.len()
+    );
+    if args.dry_run {
+        println!("Dry run; nothing was saved");
+        return Ok(());
+    }
+    if plan.is_empty() {
+        return Ok(());
+    }
+
+    plan.apply(config);
+    config.validate()?;
+    config.save()?;
+    refresh_ssh_config(config);
+    println!("Saved. Synced hosts are tagged '{}'.", aliyun::sync::source_tag(&args.instance));
+    Ok(())
+}
+
+/// Hosts named by `exec` arguments, or `None` after reporting unknown names
+fn select_exec_hosts<'a>(config: &'a Config, args: &ExecArgs) -> Option<Vec<&'a HostConfig>> {
+    if let Some(tag) = &args.group {
+        let hosts = config.hosts_with_tag(tag);
+        if hosts.is_empty() {
+            println!("No hosts are tagged '{}'", tag);
+            return None;
+        }
+        return Some(hosts);
+    }
+
+    let names = args.name.iter().chain(&args.hosts);
+    let missing: Vec<&str> = names.clone().filter(|name| config.get_host(name).is_none()).map(String::as_str).collect();
+    if !missing.is_empty() {
+        println!("Host(s) not found: {}", missing.join(", "));
+        return None;
+    }
+    Some(names.filter_map(|name| config.get_host(name)).collect())
+}
+
+/// Decrypt (or run the command for) the password of `host`, and decrypt its
+/// TOTP secret. Passwords in the legacy format are migrated on the way.
+fn resolve_credentials(config: &mut Config, host: &HostConfig) -> Result<(Option<Secret>, Option<Secret>), Box<dyn std::error::Error>> {
+    // Unlock the vault only if the host has stored secrets
+    let key = if has_stored_secrets(host) {
+        Some(unlock_vault(config)?)
+    } else {
+        None
+    };
+    decrypt_credentials(config, host, key.as_ref())
+}
+
+/// Where and as whom `host` is reached, with its decrypted secrets
+fn ssh_target<'a>(host: &'a HostConfig, jump: Option<&'a ssh::Jump>, password: Option<&'a Secret>, totp: Option<&'a Secret>) -> ssh::Target<'a> {
+    ssh::Target {
+        hostname: &host.hostname,
+        port: host.port,
+        username: &host.username,
+        identity_file: host.identity_file.as_deref(),
+        jump,
+        menu: &host.menu,
+        password,
+        totp,
+    }
+}
+
+/// How to get through the jump hosts of `host`. ssh's ProxyJump cannot
+/// answer password or MFA prompts for the hops, so when a hop needs
+/// credentials of ours the connection goes through `ali-bastion proxy`
+/// instead, which reaches the last hop the same way in turn.
+fn jump_for(config: &Config, host: &HostConfig) -> Result<Option<ssh::Jump>, Box<dyn std::error::Error>> {
+    let chain = config.jump_chain(&host.name)?;
+    if chain.is_empty() {
+        return Ok(None);
+    }
+
+    let needs_credentials = |hop: &&HostConfig| has_stored_secrets(hop) || hop.password_command.is_some() || hop.identity_file.is_some();
+    if !chain.iter().any(needs_credentials) {
+        let hops = chain
+            .iter()
+            .map(|hop| format!("{}@{}:{}", hop.username, transfer::bracket_ipv6(&hop.hostname), hop.port))
+            .collect();
+        return Ok(Some(ssh::Jump::Hops(hops)));
+    }
+
+    // ssh expands % sequences in ProxyCommand
+    let exe = std::env::current_exe()?.display().to_string().replace('%', "%%");
+    Ok(Some(ssh::Jump::Command(format!(
+        "{} proxy {}",
+        ssh::invocation::shell_quote(&exe),
+        ssh::invocation::shell_quote(&host.name)
+    ))))
+}
+
+/// Whether a jump host of `host` needs the vault, which its proxy unlocks
+/// on its own
+fn hops_have_stored_secrets(config: &Config, host: &HostConfig) -> bool {
+    config.jump_chain(&host.name).is_ok_and(|chain| chain.iter().any(|hop| has_stored_secrets(hop)))
+}
+
+fn has_stored_secrets(host: &HostConfig) -> bool {
+    host.password.is_some() || host.totp_secret.is_some()
+}
+
+/// `resolve_credentials` with an already unlocked vault key
+fn decrypt_credentials(config: &mut Config, host: &HostConfig, key: Option<&Key>) -> Result<(Option<Secret>, Option<Secret>), Box<dyn std::error::Error>> {
+    // Run the password command, or decrypt the stored password if it exists
+    let decrypted_password = if let Some(ref command) = host.password_command {
+        Some(run_password_command(command, password_command::DEFAULT_TIMEOUT)?)
+    } else {
+        match (&host.password, key) {
+            (Some(encrypted_password), Some(key)) => {
+                let decrypted = decrypt_secret(encrypted_password, key)
+                    .map_err(|e| format!("Failed to decrypt password for '{}': {}", host.name, e))?;
+
+                // Re-encrypt passwords stored in the legacy format
+                if is_legacy_ciphertext(encrypted_password.expose()) {
+                    migrate_password(config, &host.name, &decrypted, key);
+                }
+                Some(decrypted)
+            }
+            _ => None,
+        }
     };
     
-    // Connect to the selected host
-    let password = decrypted_password.as_deref();
-    ssh::connect_to_host(
-        &host_to_connect.hostname, 
-        host_to_connect.port, 
-        &host_to_connect.username, 
-        password
-    )?;
+    // Decrypt TOTP secret if it exists
+    let decrypted_totp = match (&host.totp_secret, key) {
+        (Some(encrypted_totp), Some(key)) => Some(
+            decrypt_secret(encrypted_totp, key)
+                .map_err(|e| format!("Failed to decrypt TOTP secret for '{}': {}", host.name, e))?,
+        ),
+        _ => None,
+    };
+
+    Ok((decrypted_password, decrypted_totp))
+}
+
+/// Print the command `connect` would run. Secrets are redacted in the
+/// output, so they are not decrypted and password commands are not run.
+fn print_invocation(config: &Config, host: &HostConfig) {
+    let jump = match jump_for(config, host) {
+        Ok(jump) => jump,
+        Err(e) => {
+            println!("Error: {}", e);
+            return;
+        }
+    };
+    let placeholder = Secret::default();
+    let has_password = host.password.is_some() || host.password_command.is_some();
+    let target = ssh_target(host, jump.as_ref(), has_password.then_some(&placeholder), host.totp_secret.as_ref().map(|_| &placeholder));
+
+    match ssh::connector(&target).invocation(&target) {
+        Ok(invocation) => println!("{}", invocation),
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+pub fn handle_otp(args: OtpArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let host = match config.get_host(&args.name) {
+        Some(host) => host,
+        None => {
+            println!("Host '{}' not found", args.name);
+            return Ok(());
+        }
+    };
+    
+    let encrypted_totp = match host.totp_secret {
+        Some(ref encrypted_totp) => encrypted_totp,
+        None => {
+            println!("Host '{}' has no TOTP secret configured", args.name);
+            return Ok(());
+        }
+    };
+    
+    let key = unlock_vault(&config)?;
+    let totp = Totp::parse(decrypt_secret(encrypted_totp, &key)?.expose())?;
+    
+    // Only the code goes to stdout so it can be piped
+    println!("{}", totp.now());
+    eprintln!("(valid for {} more seconds)", totp.seconds_remaining());
+    Ok(())
+}
+
+pub fn handle_rekey() -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    let key_path = keystore::get_key_path();
+
+    if !key_path.exists() {
+        println!("No vault key exists yet. One is created when the first password is stored.");
+        return Ok(());
+    }
+
+    let passphrase = keystore::prompt_passphrase("Master passphrase: ")?;
+    let old_key = keystore::unlock_key_file(&key_path, &passphrase)?;
+    let new_key = keystore::generate_key();
+
+    // Re-encrypt everything in memory first so a failure leaves nothing half-done
+    let mut rekeyed = 0;
+    for host in config.hosts.values_mut() {
+        if let Some(ref encrypted_password) = host.password {
+            let password = decrypt_secret(encrypted_password, &old_key)
+                .map_err(|e| format!("Failed to decrypt password for '{}': {}", host.name, e))?;
+            host.password = Some(encrypt_secret(&password, &new_key)?);
+            rekeyed += 1;
+        }
+        if let Some(ref encrypted_totp) = host.totp_secret {
+            let totp = decrypt_secret(encrypted_totp, &old_key)
+                .map_err(|e| format!("Failed to decrypt TOTP secret for '{}': {}", host.name, e))?;
+            host.totp_secret = Some(encrypt_secret(&totp, &new_key)?);
+            rekeyed += 1;
+        }
+    }
+
+    if config.is_encrypted() {
+        config.enable_encryption(new_key);
+    }
+    let identity_path = bundle::get_identity_path();
+    let identity = if identity_path.exists() {
+        Some(bundle::load_identity(&identity_path, &old_key)?)
+    } else {
+        None
+    };
+
+    // Stage the new key and identity and keep the old key until everything
+    // is in place, so a failure at any step can be undone
+    let staged_key_path = key_path.with_extension("new");
+    let old_key_path = key_path.with_extension("old");
+    let staged_identity_path = identity_path.with_extension("new");
+    keystore::write_rekeyed_key_file(&key_path, &staged_key_path, &passphrase, &new_key)?;
+    if let Some(ref identity) = identity
+        && let Err(e) = bundle::write_identity(&staged_identity_path, identity, &new_key)
+    {
+        let _ = fs::remove_file(&staged_key_path);
+        return Err(e);
+    }
+    let config_path = Config::get_config_path();
+    let config_files = snapshot_files(&[config_path.clone(), Config::get_encrypted_path(&config_path)])?;
+    fs::copy(&key_path, &old_key_path)?;
+
+    let swapped = (|| -> Result<(), Box<dyn std::error::Error>> {
+        config.save()?;
+        fs::rename(&staged_key_path, &key_path)?;
+        if identity.is_some() {
+            fs::rename(&staged_identity_path, &identity_path)?;
+        }
+        Ok(())
+    })();
+    if let Err(e) = swapped {
+        restore_files(&config_files);
+        let _ = fs::remove_file(&staged_key_path);
+        let _ = fs::remove_file(&staged_identity_path);
+        if let Err(restore) = fs::rename(&old_key_path, &key_path) {
+            return Err(format!("Rekeying failed: {}. Restoring the old key also failed ({}); it is kept at {}.", e, restore, old_key_path.display()).into());
+        }
+        return Err(format!("Rekeying failed and nothing was changed: {}", e).into());
+    }
+    let _ = fs::remove_file(&old_key_path);
+    offer_key_to_agent(&new_key);
+
+    println!("Generated a new vault key and re-encrypted {} stored secret(s)", rekeyed);
+    Ok(())
+}
+
+/// A file's path and its contents, `None` when it does not exist
+type FileSnapshot = (std::path::PathBuf, Option<Vec<u8>>);
+
+/// Contents of `paths` as they are now
+fn snapshot_files(paths: &[std::path::PathBuf]) -> Result<Vec<FileSnapshot>, Box<dyn std::error::Error>> {
+    paths
+        .iter()
+        .map(|path| match fs::read(path) {
+            Ok(content) => Ok((path.clone(), Some(content))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok((path.clone(), None)),
+            Err(e) => Err(e.into()),
+        })
+        .collect()
+}
+
+/// Put files back as `snapshot_files` found them
+fn restore_files(snapshot: &[FileSnapshot]) {
+    for (path, content) in snapshot {
+        let _ = match content {
+            Some(content) => fs::write(path, content),
+            None => fs::remove_file(path),
+        };
+    }
+}
+
+pub fn handle_config(args: ConfigArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = Config::load()?;
+    match args.command {
+        ConfigCommand::Encrypt => {
+            if config.is_encrypted() {
+                println!("Configuration is already encrypted");
+                return Ok(());
+            }
+            config.enable_encryption(get_or_create_key()?);
+            config.save()?;
+            println!("Configuration is now stored encrypted in {}", Config::get_encrypted_path(&Config::get_config_path()).display());
+            let include_path = ssh_config::get_include_path();
+            if include_path.exists() {
+                println!(
+                    "Warning: {} still lists the current hosts in plaintext. It is no longer updated; delete it if the host list should stay private.",
+                    include_path.display()
+                );
+            }
+        }
+        ConfigCommand::Decrypt => {
+            if !config.is_encrypted() {
+                println!("Configuration is already stored as plaintext");
+                return Ok(());
+            }
+            config.disable_encryption();
+            config.save()?;
+            println!("Configuration is now stored as plaintext in {}", Config::get_config_path().display());
+        }
+    }
+    Ok(())
+}
+
+pub fn handle_ssh_config(args: SshConfigArgs) -> Result<(), Box<dyn std::error::Error>> {
+    match args.command {
+        SshConfigCommand::Export { install, allow_plaintext } => {
+            let config = Config::load()?;
+            if config.is_encrypted() && !allow_plaintext {
+                println!(
+                    "Error: The configuration is stored encrypted, but {} would list every host in plaintext. Pass --allow-plaintext to write it anyway.",
+                    ssh_config::get_include_path().display()
+                );
+                return Ok(());
+            }
+            let (content, skipped) = ssh_config::render(&config);
+            for name in &skipped {
+                println!("Skipping '{}': ssh would read the name as a pattern", name);
+            }
+
+            let include_path = ssh_config::get_include_path();
+            let count = config.hosts.len() - skipped.len();
+            if ssh_config::write_if_changed(&include_path, &content)? {
+                println!("Wrote {} host(s) to {}", count, include_path.display());
+            } else {
+                println!("{} is up to date ({} host(s))", include_path.display(), count);
+            }
+
+            let user_config = ssh_config::get_user_config_path();
+            if install {
+                if ssh_config::install_include(&user_config, &include_path)? {
+                    println!("Added 'Include {}' to {}", ssh_config::include_reference(&include_path), user_config.display());
+                } else {
+                    println!("{} already includes it", user_config.display());
+                }
+            } else if !ssh_config::is_included(&user_config, &include_path)? {
+                println!("Run 'ssh-config export --install' to include it from {}", user_config.display());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Keep an exported ssh config in step with the host list
+fn refresh_ssh_config(config: &Config) {
+    match ssh_config::refresh(config) {
+        Ok(true) => {}
+        Ok(false) => println!(
+            "Warning: {} was not updated because the configuration is stored encrypted. Run 'ssh-config export --allow-plaintext' to update it anyway.",
+            ssh_config::get_include_path().display()
+        ),
+        Err(e) => println!("Warning: Failed to update {}: {}", ssh_config::get_include_path().display(), e),
+    }
+}
+
+pub fn handle_identity() -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let key = unlock_vault(&config)?;
+    let identity = bundle::load_or_create_identity(&bundle::get_identity_path(), &key)?;
+    
+    println!("{}", bundle::format_public_key(&x25519_dalek::PublicKey::from(&identity)));
+    Ok(())
+}
+
+pub fn handle_export(args: ExportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load()?;
+    let recipients = args.recipients
+        .iter()
+        .map(|recipient| bundle::parse_public_key(recipient))
+        .collect::<Result<Vec<_>, _>>()?;
+    
+    let selected: Vec<&HostConfig> = match args.hosts {
+        Some(ref names) => {
+            let mut selected = Vec::new();
+            for name in names {
+                match config.get_host(name) {
+                    Some(host) => selected.push(host),
+                    None => {
+                        println!("Host '{}' not found", name);
+                        return Ok(());
+                    }
+                }
+            }
+            selected
+        }
+        None => config.list_hosts(),
+    };
+    
+    if selected.is_empty() {
+        println!("No hosts to export");
+        return Ok(());
+    }
+    
+    // Bundles carry cleartext secrets inside the encrypted payload
+    let needs_key = selected.iter().any(|host| host.password.is_some() || host.totp_secret.is_some());
+    let key = if needs_key { Some(unlock_vault(&config)?) } else { None };
+    let mut hosts = Vec::with_capacity(selected.len());
+    for host in selected {
+        let mut host = host.clone();
+        if let Some(key) = key.as_ref() {
+            host.password = host.password.map(|p| decrypt_secret(&p, key)).transpose()
+                .map_err(|e| format!("Failed to decrypt password for '{}': {}", host.name, e))?;
+            host.totp_secret = host.totp_secret.map(|t| decrypt_secret(&t, key)).transpose()
+                .map_err(|e| format!("Failed to decrypt TOTP secret for '{}': {}", host.name, e))?;
+        }
+        hosts.push(host);
+    }
+    
+    let sealed = bundle::seal(&hosts, &recipients)?;
+    match args.output {
+        Some(path) => {
+            fs::write(&path, sealed)?;
+            eprintln!("Exported {} host(s) for {} recipient(s) to {}", hosts.len(), recipients.len(), path.display());
+        }
+        None => println!("{}", sealed),
+    }
+    Ok(())
+}
+
+pub fn handle_import(args: ImportArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let bundle_path = match (args.command, args.bundle) {
+        (Some(ImportCommand::SshConfig(ssh_args)), _) => return import_ssh_config(ssh_args),
+        (None, Some(bundle_path)) => bundle_path,
+        (None, None) => return Err("No bundle file given".into()),
+    };
+    let mut config = Config::load()?;
+    let content = fs::read_to_string(&bundle_path)?;
+    let key = unlock_vault(&config)?;
+    let identity = bundle::load_identity(&bundle::get_identity_path(), &key)?;
+    let hosts = bundle::open(&content, &identity)?;
     
+    let mut imported = 0;
+    let mut dropped = 0;
+    for mut host in hosts {
+        if let Some(reason) = bundle::unsafe_field(&host) {
+            println!("Refusing '{}': {}", host.name.escape_debug(), reason);
+            continue;
+        }
+        if config.get_host(&host.name).is_some() && !args.overwrite {
+            println!("Skipping '{}': a host with that name already exists (use --overwrite to replace it)", host.name);
+            continue;
+        }
+        
+        // Re-encrypt the secrets under the local vault key
+        host.password = host.password.map(|p| encrypt_secret(&p, &key)).transpose()?;
+        host.totp_secret = host.totp_secret.map(|t| encrypt_secret(&t, &key)).transpose()?;
+        println!("Imported '{}'", host.name);
+        // Anyone can make a bundle for us, so nothing in it may run unasked
+        let settings = bundle::active_settings(&host);
+        if !settings.is_empty() {
+            let action = if args.allow_commands { "Kept" } else { "Dropped" };
+            for setting in &settings {
+                println!("    {} {}", action, setting);
+            }
+            if !args.allow_commands {
+                bundle::drop_active_settings(&mut host);
+                dropped += settings.len();
+            }
+        }
+        config.add_host(host);
+        imported += 1;
+    }
+    
+    // A bundle may name jump hosts it does not carry
+    if let Err(e) = config.validate() {
+        println!("Error: {}. Nothing was imported.", e);
+        return Ok(());
+    }
+    config.save()?;
+    refresh_ssh_config(&config);
+    println!("Imported {} host(s)", imported);
+    if dropped > 0 {
+        println!("Dropped {} password command(s), forward(s) and menu step(s). Bundles are not signed; re-import with --allow-commands only if you trust the sender.", dropped);
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn import_ssh_config(args: ImportSshConfigArgs) -> Result<(), Box<dyn std::error::Error>> {
+    let user_config = ssh_config::get_user_config_path();
+    let path = args.path.unwrap_or_else(|| user_config.clone());
+    let ssh_dir = user_config.parent().unwrap_or(std::path::Path::new("."));
+    let ssh_hosts = ssh_config::parse::parse_file(&path, ssh_dir, Some(&ssh_config::get_include_path()))?;
+    if ssh_hosts.is_empty() {
+        println!("No hosts found in {}", path.display());
+        return Ok(());
+    }
+
+    // ssh logs in as the local user when no User is set
+    let default_user = std::env::var("USER").or_else(|_| std::env::var("USERNAME")).unwrap_or_default();
+    let mut config = Config::load()?;
+    let mut imported = 0;
+    // Aliases and the names they were imported under, for resolving ProxyJump
+    let mut names = std::collections::HashMap::new();
+    // Hosts replaced by --on-conflict overwrite, restored if the import is taken out again
+    let mut replaced = std::collections::HashMap::new();
+    for ssh_host in &ssh_hosts {
+        let mut host = ssh_host.to_host_config(&default_user);
+        if host.username.is_empty() {
+            println!("Skipping '{}': no User set and the local user name is unknown", host.name);
+            continue;
+        }
+        if config.get_host(&host.name).is_some() {
+            match args.on_conflict {
+                ConflictPolicy::Skip => {
+                    println!("Skipping '{}': a host with that name already exists (see --on-conflict)", host.name);
+                    continue;
+                }
+                ConflictPolicy::Overwrite => {
+                    println!("Replacing existing host '{}'", host.name);
+                    replaced.extend(config.get_host(&host.name).cloned().map(|old| (host.name.clone(), old)));
+                }
+                ConflictPolicy::Rename => {
+                    let name = config.unused_name(&host.name);
+                    println!("Importing '{}' as '{}': a host with that name already exists", host.name, name);
+                    host.name = name;
+                }
+            }
+        }
+        println!("Imported '{}' ({}@{}:{})", host.name, host.username, host.hostname, host.port);
+        names.insert(ssh_host.alias.as_str(), host.name.clone());
+        config.add_host(host);
+        imported += 1;
+    }
+
+    for (name, reason) in ssh_config::parse::link_jump_hosts(&mut config, &ssh_hosts, &names) {
+        println!("Not importing '{}': {}", name, reason);
+        imported -= 1;
+        if let Some(host) = replaced.remove(&name) {
+            config.add_host(host);
+        }
+    }
+    // Restored hosts may go through hosts that were replaced
+    if let Err(e) = config.validate() {
+        println!("Error: {}. Nothing was imported.", e);
+        return Ok(());
+    }
+
+    config.save()?;
+    refresh_ssh_config(&config);
+    println!("Imported {} of {} host(s) from {}", imported, ssh_hosts.len(), path.display());
+    Ok(())
+}
+
+#[cfg(unix)]
+pub fn handle_agent(args: AgentArgs) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::agent;
+
+    let socket_path = agent::get_socket_path();
+    match args.command {
+        None => start_agent(&socket_path, args.timeout),
+        Some(AgentCommand::Lock) => {
+            if agent::lock(&socket_path)? {
+                println!("Agent locked");
+            } else {
+                println!("No agent is running");
+            }
+            Ok(())
+        }
+        Some(AgentCommand::Stop) => {
+            if agent::stop(&socket_path)? {
+                println!("Agent stopped");
+            } else {
+                println!("No agent is running");
+            }
+            Ok(())
+        }
+        Some(AgentCommand::Serve) => {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line)?;
+            let key = agent::decode_key(line.trim())?;
+            agent::serve(&socket_path, Some(key), std::time::Duration::from_secs(args.timeout))
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn handle_agent(_args: AgentArgs) -> Result<(), Box<dyn std::error::Error>> {
+    Err("The credential agent is only supported on Unix systems".into())
+}
+
+/// Unlock the vault key and hand it to a detached `agent serve` process
+#[cfg(unix)]
+fn start_agent(socket_path: &std::path::Path, timeout: u64) -> Result<(), Box<dyn std::error::Error>> {
+    use crate::agent;
+    use std::io::Write;
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+
+    let status = agent::request_key(socket_path)?;
+    if let agent::AgentStatus::Unlocked(_) = status {
+        println!("An agent is already running");
+        return Ok(());
+    }
+
+    let key_path = keystore::get_key_path();
+    if !key_path.exists() {
+        println!("No vault key exists yet. Store a password with 'add' first.");
+        return Ok(());
+    }
+    let passphrase = keystore::prompt_passphrase("Master passphrase: ")?;
+    let key = keystore::unlock_key_file(&key_path, &passphrase)?;
+
+    // A locked agent is unlocked in place
+    if status == agent::AgentStatus::Locked {
+        agent::unlock(socket_path, &key)?;
+        println!("Agent unlocked");
+        return Ok(());
+    }
+
+    // Run in its own process group so Ctrl+C in this terminal does not stop it
+    let mut child = Command::new(std::env::current_exe()?)
+        .arg("agent")
+        .arg("--timeout").arg(timeout.to_string())
+        .arg("serve")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .process_group(0)
+        .spawn()?;
+
+    // Pass the key through a pipe so it never appears in argv or the environment
+    if let Some(mut stdin) = child.stdin.take() {
+        writeln!(stdin, "{}", agent::encode_key(&key))?;
+    }
+
+    if !agent::wait_until_ready(socket_path, std::time::Duration::from_secs(5)) {
+        return Err("Agent did not start in time".into());
+    }
+    println!("Agent started (pid {}), idle timeout {} seconds", child.id(), timeout);
+    Ok(())
+}
+
+/// Get the vault key, reusing the one an encrypted config was opened with
+fn unlock_vault(config: &Config) -> Result<Key, Box<dyn std::error::Error>> {
+    match config.storage_key() {
+        Some(key) => Ok(*key),
+        None => get_or_create_key(),
+    }
+}
+
+/// Rewrite a host's stored password using the current encryption scheme.
+/// Reports go to stderr, as this also runs for `proxy`, whose stdout is an
+/// ssh connection.
+fn migrate_password(config: &mut Config, host_name: &str, password: &Secret, key: &Key) {
+    let encrypted = match encrypt_secret(password, key) {
+        Ok(encrypted) => encrypted,
+        Err(e) => {
+            eprintln!("Warning: Failed to re-encrypt password: {}", e);
+            return;
+        }
+    };
+
+    if let Some(host) = config.hosts.get_mut(host_name) {
+        host.password = Some(encrypted);
+        match config.save() {
+            Ok(()) => eprintln!("Migrated stored password for '{}' to the new encryption format", host_name),
+            Err(e) => eprintln!("Warning: Failed to save migrated password: {}", e),
+        }
+    }
+}
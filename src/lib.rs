@@ -4,6 +4,14 @@ pub mod ssh;
 pub mod utils;
 pub mod handlers;
 pub mod dependencies;
+pub mod bundle;
+pub mod exec;
+pub mod tunnel;
+pub mod ssh_config;
+pub mod discover;
+pub mod aliyun;
+#[cfg(unix)]
+pub mod agent;
 
 #[cfg(test)]
 mod tests {
@@ -20,6 +28,7 @@ mod tests {
             port: 60022,
             username: "testuser".to_string(),
             password: None,
+            ..Default::default()
         };
         
         config.add_host(host.clone());
@@ -1,6 +1,7 @@
 use clap::Parser;
 use ali_bastion::commands::Commands;
 use ali_bastion::handlers;
+use ali_bastion::ssh::askpass;
 
 #[derive(Parser)]
 #[command(name = "ali-bastion")]
@@ -11,6 +12,12 @@ struct Cli {
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // ssh runs this executable through the askpass link with the prompt as
+    // argument
+    if let Some(prompt) = askpass::invocation() {
+        return askpass::run(&prompt);
+    }
+
     let cli = Cli::parse();
 
     match cli.command {
@@ -26,6 +33,63 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         Commands::Connect(args) => {
             handlers::handle_connect(args)?;
         }
+        Commands::Exec(args) => {
+            let code = handlers::handle_exec(args)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::Put(args) => {
+            let code = handlers::handle_put(args)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::Get(args) => {
+            let code = handlers::handle_get(args)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::Tunnel(args) => {
+            handlers::handle_tunnel(args)?;
+        }
+        Commands::Proxy(args) => {
+            let code = handlers::handle_proxy(args)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }
+        Commands::SshConfig(args) => {
+            handlers::handle_ssh_config(args)?;
+        }
+        Commands::Discover(args) => {
+            handlers::handle_discover(args)?;
+        }
+        Commands::Sync(args) => {
+            handlers::handle_sync(args)?;
+        }
+        Commands::Rekey => {
+            handlers::handle_rekey()?;
+        }
+        Commands::Agent(args) => {
+            handlers::handle_agent(args)?;
+        }
+        Commands::Otp(args) => {
+            handlers::handle_otp(args)?;
+        }
+        Commands::Config(args) => {
+            handlers::handle_config(args)?;
+        }
+        Commands::Identity => {
+            handlers::handle_identity()?;
+        }
+        Commands::Export(args) => {
+            handlers::handle_export(args)?;
+        }
+        Commands::Import(args) => {
+            handlers::handle_import(args)?;
+        }
     }
 
     Ok(())
@@ -0,0 +1,216 @@
+//! `SSH_ASKPASS` helper
+//!
+//! Hosts that ask for more than a password, such as Aliyun Bastionhost
+//! accounts that want an MFA code after the password, cannot be driven by
+//! sshpass. Instead ssh is started with `SSH_ASKPASS` pointing at this
+//! executable; ssh then runs it once per prompt with the prompt text as the
+//! only argument and reads the answer from stdout.
+//!
+//! ssh runs the program through a link named [`PROGRAM_NAME`], which is how
+//! the binary tells a prompt from a command line. The TOTP secret itself
+//! never leaves this process: ssh only gets the codes of the next few
+//! minutes.
+
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::utils::secret::Secret;
+use crate::utils::totp::Totp;
+
+/// Name of the link ssh runs as `SSH_ASKPASS`
+pub const PROGRAM_NAME: &str = "ali-bastion-askpass";
+
+/// Password to answer password prompts with
+pub const PASSWORD_ENV: &str = "ALI_BASTION_PASSWORD";
+
+/// One-time codes to answer MFA prompts with, as `<from>-<until>:<code>`
+/// entries separated by spaces
+pub const CODES_ENV: &str = "ALI_BASTION_TOTP_CODES";
+
+/// How many periods of codes ssh gets, enough to cover the password prompt
+/// and a slow login
+const CODE_PERIODS: u64 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum PromptKind {
+    Password,
+    OneTimeCode,
+    Other,
+}
+
+/// Words that identify an MFA prompt. Checked before the password rule since
+/// prompts like "One-time password:" contain both.
+const OTP_KEYWORDS: &[&str] = &[
+    "mfa", "otp", "one-time", "one time", "verification code", "2fa",
+    "two-factor", "authenticator", "token", "验证码", "动态码", "动态口令",
+];
+
+pub fn classify_prompt(prompt: &str) -> PromptKind {
+    let prompt = prompt.to_lowercase();
+    if OTP_KEYWORDS.iter().any(|keyword| prompt.contains(keyword)) {
+        PromptKind::OneTimeCode
+    } else if prompt.contains("password") || prompt.contains("密码") {
+        PromptKind::Password
+    } else {
+        PromptKind::Other
+    }
+}
+
+/// Returns the prompt if this process was started by ssh as the askpass program
+pub fn invocation() -> Option<String> {
+    let mut args = std::env::args();
+    let program = args.next()?;
+    if Path::new(&program).file_name()? != PROGRAM_NAME {
+        return None;
+    }
+    match (args.next(), args.next()) {
+        (Some(prompt), None) => Some(prompt),
+        _ => None,
+    }
+}
+
+/// The link to this executable to give ssh as `SSH_ASKPASS`
+pub fn program_path() -> PathBuf {
+    let mut link = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    link.push(".ali-bastion");
+    link.push(PROGRAM_NAME);
+    link
+}
+
+/// Create the link at [`program_path`], or point it at the current
+/// executable if it moved
+#[cfg(unix)]
+pub fn install_program() -> Result<(), Box<dyn std::error::Error>> {
+    let link = program_path();
+    let exe = std::env::current_exe()?;
+    if std::fs::read_link(&link).ok().as_deref() == Some(exe.as_path()) {
+        return Ok(());
+    }
+    if let Some(parent) = link.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Replace the link in one step so a concurrent ssh never misses it
+    let staged = link.with_extension(format!("{}.tmp", std::process::id()));
+    let _ = std::fs::remove_file(&staged);
+    std::os::unix::fs::symlink(&exe, &staged)?;
+    if let Err(e) = std::fs::rename(&staged, &link) {
+        let _ = std::fs::remove_file(&staged);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// The codes of `totp` from the period containing `unix_time` on, for
+/// [`CODES_ENV`]
+pub fn prepare_codes(totp: &Totp, unix_time: u64) -> Secret {
+    let start = unix_time - unix_time % totp.period;
+    let codes: Vec<String> = (0..CODE_PERIODS)
+        .map(|index| {
+            let from = start + index * totp.period;
+            format!("{}-{}:{}", from, from + totp.period, totp.generate(from))
+        })
+        .collect();
+    Secret::new(codes.join(" "))
+}
+
+/// The code valid at `unix_time` out of prepared `codes`
+fn pick_code(codes: &str, unix_time: u64) -> Option<String> {
+    codes.split_whitespace().find_map(|entry| {
+        let (range, code) = entry.split_once(':')?;
+        let (from, until) = range.split_once('-')?;
+        let valid = from.parse::<u64>().ok()? <= unix_time && unix_time < until.parse::<u64>().ok()?;
+        valid.then(|| code.to_string())
+    })
+}
+
+/// Answer a single prompt on stdout
+pub fn run(prompt: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let password = std::env::var(PASSWORD_ENV).ok().map(Secret::new);
+    let code = std::env::var(CODES_ENV).ok().and_then(|codes| pick_code(&codes, crate::utils::now()));
+
+    let answer = match (classify_prompt(prompt), code) {
+        (PromptKind::OneTimeCode, Some(code)) => Secret::new(code),
+        _ => answer_prompt(prompt, password.as_ref(), None)?,
+    };
+    println!("{}", answer.expose());
+    Ok(())
+}
+
+/// Answer a prompt with the stored password or a code generated from the
+/// TOTP secret, asking the user when neither applies
+pub fn answer_prompt(prompt: &str, password: Option<&Secret>, totp: Option<&Secret>) -> Result<Secret, Box<dyn std::error::Error>> {
+    let answer = match classify_prompt(prompt) {
+        PromptKind::OneTimeCode => match totp {
+            Some(secret) => Totp::parse(secret.expose())?.now(),
+            None => ask_terminal(prompt, true)?,
+        },
+        PromptKind::Password => match password {
+            Some(password) => return Ok(password.clone()),
+            None => ask_terminal(prompt, false)?,
+        },
+        // Host key confirmations and anything else go to the user
+        PromptKind::Other => ask_terminal(prompt, true)?,
+    };
+    Ok(Secret::new(answer))
+}
+
+/// Ask the user directly on the controlling terminal
+pub(crate) fn ask_terminal(prompt: &str, echo: bool) -> Result<String, Box<dyn std::error::Error>> {
+    if !echo {
+        return Ok(rpassword::prompt_password(prompt)?);
+    }
+
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    write!(tty, "{}", prompt)?;
+    tty.flush()?;
+
+    let mut answer = String::new();
+    BufReader::new(tty).read_line(&mut answer)?;
+    Ok(answer.trim_end_matches(['\r', '\n']).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_prompt() {
+        assert_eq!(classify_prompt("alice@10.0.0.1's password: "), PromptKind::Password);
+        assert_eq!(classify_prompt("Password: "), PromptKind::Password);
+        assert_eq!(classify_prompt("MFA code: "), PromptKind::OneTimeCode);
+        assert_eq!(classify_prompt("One-time password (OTP): "), PromptKind::OneTimeCode);
+        assert_eq!(classify_prompt("请输入验证码: "), PromptKind::OneTimeCode);
+        assert_eq!(
+            classify_prompt("Are you sure you want to continue connecting (yes/no/[fingerprint])? "),
+            PromptKind::Other
+        );
+    }
+
+    #[test]
+    fn test_answer_prompt_uses_stored_secrets() {
+        let password = Secret::from("hunter2");
+        let totp = Secret::from("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+
+        let answer = answer_prompt("Password: ", Some(&password), Some(&totp)).unwrap();
+        assert_eq!(answer.expose(), "hunter2");
+
+        let generator = Totp::parse(totp.expose()).unwrap();
+        let before = generator.now();
+        let code = answer_prompt("MFA code: ", Some(&password), Some(&totp)).unwrap();
+        assert!(code.expose() == before || code.expose() == generator.now());
+    }
+
+    #[test]
+    fn test_prepared_codes_follow_the_clock() {
+        let totp = Totp::parse("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+        let codes = prepare_codes(&totp, 1_000_010);
+
+        assert!(!codes.expose().contains("GEZDGNBVGY3TQOJQ"));
+        assert_eq!(pick_code(codes.expose(), 1_000_010), Some(totp.generate(1_000_010)));
+        assert_eq!(pick_code(codes.expose(), 1_000_045), Some(totp.generate(1_000_045)));
+        assert_eq!(pick_code(codes.expose(), 999_980), None);
+        assert_eq!(pick_code(codes.expose(), 1_000_200), None);
+    }
+}
@@ -0,0 +1,402 @@
+//! Expect-style driver for a bastion's login menu
+//!
+//! ssh runs on a pseudo-terminal of ours rather than on the user's
+//! terminal. Its output is passed through to the user while the line being
+//! printed is checked against the menu steps; when a step's prompt shows,
+//! its reply is typed. Keys the user presses are passed on throughout, and
+//! once the last step is answered the session is plain relaying.
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Stdio};
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use crate::config::menu::{MenuStep, OTP_PLACEHOLDER};
+use crate::ssh::SshInvocation;
+use crate::ssh_config::parse::wildcard_match;
+use crate::utils::secret::Secret;
+use crate::utils::totp::Totp;
+
+/// Longest line kept for matching prompts
+const MAX_LINE: usize = 1024;
+
+/// How long to wait for output or key presses before checking the window size
+const POLL_TIMEOUT_MS: i32 = 200;
+
+/// Where the output is within a terminal escape sequence
+#[derive(Clone, Copy, PartialEq, Default)]
+enum Escape {
+    #[default]
+    None,
+    /// After ESC
+    Start,
+    /// Control sequence, `ESC [ ... final`
+    Csi,
+    /// Operating system command, `ESC ] ... BEL` or `ESC ] ... ESC \`
+    Osc,
+    /// ESC inside an operating system command, the start of `ESC \`
+    OscTerminator,
+}
+
+/// Drops terminal escape sequences and control characters from output,
+/// keeping text, line breaks and backspaces
+#[derive(Default)]
+pub struct Plain {
+    escape: Escape,
+}
+
+impl Plain {
+    /// The byte if it is part of the text
+    pub fn filter(&mut self, byte: u8) -> Option<u8> {
+        let (escape, text) = match (self.escape, byte) {
+            (Escape::None, 0x1b) => (Escape::Start, None),
+            (Escape::None, b'\r' | b'\n' | 0x08) => (Escape::None, Some(byte)),
+            (Escape::None, 0x00..=0x1f | 0x7f) => (Escape::None, None),
+            (Escape::None, byte) => (Escape::None, Some(byte)),
+            (Escape::Start, b'[') => (Escape::Csi, None),
+            (Escape::Start, b']') => (Escape::Osc, None),
+            (Escape::Csi, 0x40..=0x7e) => (Escape::None, None),
+            (Escape::Osc, 0x07) => (Escape::None, None),
+            (Escape::Osc, 0x1b) => (Escape::OscTerminator, None),
+            (Escape::Csi | Escape::Osc, _) => (self.escape, None),
+            // Two-byte sequences, and the end of `ESC \`
+            (Escape::Start | Escape::OscTerminator, _) => (Escape::None, None),
+        };
+        self.escape = escape;
+        text
+    }
+}
+
+/// The line being printed, as text
+#[derive(Default)]
+pub struct Line {
+    plain: Plain,
+    bytes: Vec<u8>,
+}
+
+impl Line {
+    pub fn push(&mut self, output: &[u8]) {
+        for &byte in output {
+            match self.plain.filter(byte) {
+                Some(b'\r' | b'\n') => self.bytes.clear(),
+                Some(0x08) => {
+                    self.bytes.pop();
+                }
+                Some(byte) => {
+                    if self.bytes.len() == MAX_LINE {
+                        self.bytes.remove(0);
+                    }
+                    self.bytes.push(byte);
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Whether the line, without surrounding whitespace, matches `pattern`
+    pub fn matches(&self, pattern: &str) -> bool {
+        wildcard_match(pattern, String::from_utf8_lossy(&self.bytes).trim())
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+    }
+}
+
+/// Progress through the menu steps
+pub struct Menu<'a> {
+    steps: &'a [MenuStep],
+    next: usize,
+    line: Line,
+}
+
+impl<'a> Menu<'a> {
+    pub fn new(steps: &'a [MenuStep]) -> Self {
+        Menu { steps, next: 0, line: Line::default() }
+    }
+
+    /// Whether every step has been answered
+    pub fn is_done(&self) -> bool {
+        self.next >= self.steps.len()
+    }
+
+    /// Take in output, and return the step to answer if the output ends in
+    /// its prompt
+    pub fn feed(&mut self, output: &[u8]) -> Option<&'a MenuStep> {
+        if self.is_done() {
+            return None;
+        }
+        self.line.push(output);
+
+        // A prompt waits for input, so it is the last thing printed
+        let step = &self.steps[self.next];
+        if !self.line.matches(&step.prompt) {
+            return None;
+        }
+        self.next += 1;
+        self.line.clear();
+        Some(step)
+    }
+}
+
+/// A command running on a pseudo-terminal
+pub struct Pty {
+    pub master: File,
+    pub child: Child,
+}
+
+impl Pty {
+    /// Read output until the line being printed matches `prompt`, and return
+    /// the text read, one `\n` per line. Fails if ssh exits or the prompt
+    /// does not show within `timeout`.
+    pub fn expect(&mut self, prompt: &str, timeout: Duration) -> Result<String, Box<dyn std::error::Error>> {
+        let deadline = Instant::now() + timeout;
+        let mut line = Line::default();
+        let mut plain = Plain::default();
+        let mut text = Vec::new();
+        let mut buffer = [0u8; 16 * 1024];
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(format!("No '{}' prompt within {} seconds", prompt, timeout.as_secs()).into());
+            }
+            let mut fds = [libc::pollfd { fd: self.master.as_raw_fd(), events: libc::POLLIN, revents: 0 }];
+            // SAFETY: `fds` is a valid array of one pollfd
+            if unsafe { libc::poll(fds.as_mut_ptr(), 1, remaining.as_millis().min(i32::MAX as u128) as i32) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(error.into());
+            }
+            if fds[0].revents == 0 {
+                continue;
+            }
+
+            let n = match self.master.read(&mut buffer) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // EIO once ssh has exited and the terminal is closed
+                Err(_) => 0,
+            };
+            if n == 0 {
+                let output = String::from_utf8_lossy(&text);
+                return Err(format!("ssh exited before the '{}' prompt: {}", prompt, output.trim()).into());
+            }
+            text.extend(buffer[..n].iter().filter_map(|&byte| plain.filter(byte)).filter(|&byte| byte != b'\r' && byte != 0x08));
+            line.push(&buffer[..n]);
+            if line.matches(prompt) {
+                return Ok(String::from_utf8_lossy(&text).into_owned());
+            }
+        }
+    }
+
+    /// Type `text` followed by Enter
+    pub fn send(&mut self, text: &str) -> io::Result<()> {
+        self.master.write_all(text.as_bytes())?;
+        self.master.write_all(b"\r")
+    }
+
+    /// Stop the command and wait for it
+    pub fn close(mut self) -> io::Result<()> {
+        let _ = self.child.kill();
+        self.child.wait().map(|_| ())
+    }
+}
+
+/// Start `invocation` on a new pseudo-terminal of `size`, as the session
+/// leader with the terminal as its controlling terminal
+pub fn spawn(invocation: &SshInvocation, size: Option<libc::winsize>) -> io::Result<Pty> {
+    let (mut master, mut slave) = (-1, -1);
+    let mut size = size.unwrap_or(libc::winsize { ws_row: 24, ws_col: 80, ws_xpixel: 0, ws_ypixel: 0 });
+    // SAFETY: the pointers are valid for the duration of the call
+    if unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null_mut(), &raw mut size) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: openpty returned two new descriptors that nothing else owns
+    let (master, slave) = unsafe { (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave)) };
+    // ssh has no use for our end of the terminal
+    // SAFETY: plain fcntl on a descriptor we own
+    unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) };
+
+    let mut command = invocation.to_command();
+    command
+        .stdin(Stdio::from(slave.try_clone()?))
+        .stdout(Stdio::from(slave.try_clone()?))
+        .stderr(Stdio::from(slave));
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn().map_err(|e| io::Error::new(e.kind(), format!("Failed to execute SSH command: {}", e)))?;
+    // `command` holds the last copies of the other end, which must be
+    // closed for reads to fail once the child exits
+    drop(command);
+    Ok(Pty { master: File::from(master), child })
+}
+
+/// Window size of the terminal `fd` refers to
+pub fn window_size(fd: RawFd) -> Option<libc::winsize> {
+    let mut size = libc::winsize { ws_row: 0, ws_col: 0, ws_xpixel: 0, ws_ypixel: 0 };
+    // SAFETY: TIOCGWINSZ writes a winsize to a valid pointer
+    let ok = unsafe { libc::ioctl(fd, libc::TIOCGWINSZ as _, &mut size) } == 0;
+    (ok && size.ws_col > 0).then_some(size)
+}
+
+/// Relay between `input` and the pseudo-terminal, with ssh's output written
+/// to `output`, answering the menu on the way. The window size of `input`
+/// is passed on as it changes. Returns ssh's exit code.
+pub fn interact(pty: &mut Pty, menu: &mut Menu, totp: Option<&Secret>, input: Option<RawFd>, output: &mut dyn Write) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut input = input;
+    let mut size = input.and_then(window_size).map(|size| (size.ws_row, size.ws_col));
+    let mut buffer = [0u8; 16 * 1024];
+
+    loop {
+        let mut fds = [
+            libc::pollfd { fd: pty.master.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            // poll skips negative descriptors
+            libc::pollfd { fd: input.unwrap_or(-1), events: libc::POLLIN, revents: 0 },
+        ];
+        // SAFETY: `fds` is a valid array of two pollfds
+        if unsafe { libc::poll(fds.as_mut_ptr(), 2, POLL_TIMEOUT_MS) } < 0 {
+            let error = io::Error::last_os_error();
+            if error.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(error.into());
+        }
+
+        if let Some(new_size) = input.and_then(window_size)
+            && size != Some((new_size.ws_row, new_size.ws_col))
+        {
+            size = Some((new_size.ws_row, new_size.ws_col));
+            // SAFETY: TIOCSWINSZ reads a winsize from a valid pointer
+            unsafe { libc::ioctl(pty.master.as_raw_fd(), libc::TIOCSWINSZ as _, &new_size) };
+        }
+
+        if fds[0].revents != 0 {
+            match pty.master.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => {
+                    output.write_all(&buffer[..n])?;
+                    output.flush()?;
+                    if let Some(step) = menu.feed(&buffer[..n]) {
+                        pty.master.write_all(reply(step, totp)?.as_bytes())?;
+                        pty.master.write_all(b"\r")?;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // EIO once ssh has exited and the terminal is closed
+                Err(_) => break,
+            }
+        }
+
+        if let Some(fd) = input.filter(|_| fds[1].revents != 0) {
+            // SAFETY: the descriptor stays open; ManuallyDrop keeps it from
+            // being closed here
+            let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+            match file.read(&mut buffer) {
+                Ok(0) | Err(_) => input = None,
+                Ok(n) => pty.master.write_all(&buffer[..n])?,
+            }
+        }
+    }
+
+    let status = pty.child.wait()?;
+    Ok(status.code().unwrap_or(255))
+}
+
+/// Run `invocation` on a pseudo-terminal, answering `steps` before handing
+/// the terminal over to the user. Returns the exit code.
+pub fn run(invocation: &SshInvocation, steps: &[MenuStep], totp: Option<&Secret>) -> Result<i32, Box<dyn std::error::Error>> {
+    if totp.is_none() && steps.iter().any(MenuStep::needs_otp) {
+        return Err(format!("The login menu needs an MFA code ({}), but the host has no TOTP secret", OTP_PLACEHOLDER).into());
+    }
+
+    let stdin = io::stdin().as_raw_fd();
+    let mut pty = spawn(invocation, window_size(stdin))?;
+
+    // Keys go to the bastion as they are pressed, like with plain ssh
+    // SAFETY: isatty only inspects the descriptor
+    let terminal = unsafe { libc::isatty(stdin) } == 1;
+    if terminal {
+        crossterm::terminal::enable_raw_mode()?;
+    }
+    let result = interact(&mut pty, &mut Menu::new(steps), totp, Some(stdin), &mut io::stdout());
+    if terminal {
+        let _ = crossterm::terminal::disable_raw_mode();
+    }
+    result
+}
+
+fn reply(step: &MenuStep, totp: Option<&Secret>) -> Result<String, Box<dyn std::error::Error>> {
+    match totp {
+        Some(secret) if step.needs_otp() => Ok(step.reply.replace(OTP_PLACEHOLDER, &Totp::parse(secret.expose())?.now())),
+        _ => Ok(step.reply.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(specs: &[&str]) -> Vec<MenuStep> {
+        specs.iter().map(|spec| MenuStep::parse(spec).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_menu_matches_prompt_at_end_of_output() {
+        let steps = steps(&["Opt>=>web-1", "[Host]>=>2"]);
+        let mut menu = Menu::new(&steps);
+
+        assert!(menu.feed(b"\x1b[1;32mWelcome\x1b[0m\r\n  Opt> means search\r\n").is_none());
+        assert_eq!(menu.feed(b"\x1b]0;bastion\x07\x1b[33mOpt>\x1b[0m ").unwrap().reply, "web-1");
+        assert!(menu.feed(b"web-1\r\n  1  web-1a\r\n").is_none());
+        assert_eq!(menu.feed(b"  2  web-1b\r\n[Host]> ").unwrap().reply, "2");
+        assert!(menu.is_done());
+        assert!(menu.feed(b"Opt> ").is_none());
+    }
+
+    /// A stand-in for the bastion: asks for a search term, lists matches,
+    /// asks for a number, then for an MFA code, and reports the choices
+    const FAKE_MENU: &str = r#"
+        printf '\033[1mWelcome to the bastion\033[0m\r\n'
+        printf 'Opt> '; read search
+        printf '  1  %s-a\r\n  2  %s-b\r\n[Host]> ' "$search" "$search"; read pick
+        printf 'MFA code: '; read code
+        echo "connecting to $search #$pick with ${#code}-digit code"
+        exit 3
+    "#;
+
+    #[test]
+    fn test_interact_drives_fake_menu() {
+        let invocation = SshInvocation::new("sh").arg("-c").arg(FAKE_MENU);
+        let steps = steps(&["Opt>=>web", "[Host]>=>2", "MFA code:=>{otp}"]);
+        let totp = Secret::from("JBSWY3DPEHPK3PXP");
+
+        let mut pty = spawn(&invocation, None).unwrap();
+        let mut output = Vec::new();
+        let code = interact(&mut pty, &mut Menu::new(&steps), Some(&totp), None, &mut output).unwrap();
+
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("connecting to web #2 with 6-digit code"), "{}", output);
+        assert_eq!(code, 3);
+    }
+
+    #[test]
+    fn test_run_requires_totp_for_otp_steps() {
+        let invocation = SshInvocation::new("true");
+        let error = run(&invocation, &steps(&["MFA:=>{otp}"]), None).unwrap_err();
+        assert!(error.to_string().contains("no TOTP secret"));
+    }
+}
@@ -0,0 +1,228 @@
+//! Description of an external command before it is run
+//!
+//! Backends describe the program, arguments and environment they need as an
+//! `SshInvocation`, which can be inspected, tested and printed with its
+//! secrets redacted before anything is executed.
+
+use std::fmt;
+use std::fs::File;
+use std::process::{Child, Command, Stdio};
+
+use crate::utils::secret::Secret;
+
+/// An argument or environment value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Plain(String),
+    /// Printed as `***`
+    Secret(Secret),
+}
+
+impl Value {
+    fn expose(&self) -> &str {
+        match self {
+            Value::Plain(value) => value,
+            Value::Secret(secret) => secret.expose(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SshInvocation {
+    pub program: String,
+    pub args: Vec<Value>,
+    /// Variables added to the inherited environment
+    pub env: Vec<(String, Value)>,
+    /// Variables removed from the inherited environment
+    pub env_remove: Vec<String>,
+}
+
+impl SshInvocation {
+    pub fn new(program: impl Into<String>) -> Self {
+        SshInvocation {
+            program: program.into(),
+            args: Vec::new(),
+            env: Vec::new(),
+            env_remove: Vec::new(),
+        }
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(Value::Plain(arg.into()));
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.args.extend(args.into_iter().map(|arg| Value::Plain(arg.into())));
+        self
+    }
+
+    pub fn secret_arg(mut self, secret: &Secret) -> Self {
+        self.args.push(Value::Secret(secret.clone()));
+        self
+    }
+
+    pub fn env(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.env.push((name.into(), Value::Plain(value.into())));
+        self
+    }
+
+    pub fn secret_env(mut self, name: impl Into<String>, secret: &Secret) -> Self {
+        self.env.push((name.into(), Value::Secret(secret.clone())));
+        self
+    }
+
+    /// Keep `name` from being inherited from our environment
+    pub fn env_remove(mut self, name: impl Into<String>) -> Self {
+        self.env_remove.push(name.into());
+        self
+    }
+
+    /// Whether `arg` appears as a plain argument
+    pub fn has_arg(&self, arg: &str) -> bool {
+        self.args.iter().any(|value| *value == Value::Plain(arg.to_string()))
+    }
+
+    /// Value of an environment variable set by this invocation
+    pub fn get_env(&self, name: &str) -> Option<&Value> {
+        self.env.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    /// Build the `Command`, with secrets in place
+    pub fn to_command(&self) -> Command {
+        let mut cmd = Command::new(&self.program);
+        cmd.args(self.args.iter().map(Value::expose));
+        for name in &self.env_remove {
+            cmd.env_remove(name);
+        }
+        for (name, value) in &self.env {
+            cmd.env(name, value.expose());
+        }
+        cmd
+    }
+
+    /// Run the command as a child sharing our stdin, stdout and stderr, and
+    /// return its exit code
+    pub fn run(&self) -> Result<i32, Box<dyn std::error::Error>> {
+        let status = self
+            .to_command()
+            .status()
+            .map_err(|e| format!("Failed to execute SSH command: {}", e))?;
+        // Killed by a signal; ssh uses the same code for its own failures
+        Ok(status.code().unwrap_or(255))
+    }
+
+    /// Run the command in place of the current process. On platforms
+    /// without `exec` this waits for the command to finish instead.
+    pub fn exec(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut cmd = self.to_command();
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let error = cmd.exec();
+            Err(format!("Failed to execute SSH command: {}", error).into())
+        }
+
+        #[cfg(not(unix))]
+        {
+            let status = cmd.status().map_err(|e| format!("Failed to execute SSH command: {}", e))?;
+            if !status.success() {
+                return Err(format!("SSH command failed with status: {}", status).into());
+            }
+            Ok(())
+        }
+    }
+
+    /// Start the command in the background, detached from our terminal, with
+    /// its output written to `log`
+    pub fn spawn_detached(&self, log: File) -> Result<Child, Box<dyn std::error::Error>> {
+        let mut cmd = self.to_command();
+        cmd.stdin(Stdio::null()).stdout(log.try_clone()?).stderr(log);
+
+        // Its own process group, so Ctrl+C in this terminal does not stop it
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            cmd.process_group(0);
+        }
+        #[cfg(windows)]
+        {
+            use std::os::windows::process::CommandExt;
+            const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+            cmd.creation_flags(CREATE_NEW_PROCESS_GROUP);
+        }
+
+        Ok(cmd.spawn().map_err(|e| format!("Failed to execute SSH command: {}", e))?)
+    }
+}
+
+/// The command line as it would be typed in a shell, with secrets redacted
+impl fmt::Display for SshInvocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut words = Vec::with_capacity(self.env.len() + self.args.len() + 1);
+        for (name, value) in &self.env {
+            words.push(format!("{}={}", name, quote(value)));
+        }
+        words.push(shell_quote(&self.program));
+        words.extend(self.args.iter().map(quote));
+        f.write_str(&words.join(" "))
+    }
+}
+
+fn quote(value: &Value) -> String {
+    match value {
+        Value::Plain(value) => shell_quote(value),
+        Value::Secret(_) => "***".to_string(),
+    }
+}
+
+pub(crate) fn shell_quote(word: &str) -> String {
+    let safe = !word.is_empty()
+        && word.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:@=,+%".contains(c));
+    if safe {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_redacts_secrets() {
+        let password = Secret::from("pa55 w0rd");
+        let invocation = SshInvocation::new("sshpass")
+            .secret_env("SSHPASS", &password)
+            .arg("-e")
+            .arg("ssh")
+            .arg("-o")
+            .arg("ProxyCommand=ssh -W %h:%p jump")
+            .secret_arg(&password);
+
+        assert_eq!(
+            invocation.to_string(),
+            "SSHPASS=*** sshpass -e ssh -o 'ProxyCommand=ssh -W %h:%p jump' ***"
+        );
+    }
+
+    #[test]
+    fn test_command_contains_secrets() {
+        let password = Secret::from("hunter2");
+        let invocation = SshInvocation::new("plink").arg("-pw").secret_arg(&password).secret_env("X", &password);
+
+        assert!(invocation.has_arg("-pw"));
+        assert!(!invocation.has_arg("hunter2"));
+
+        let cmd = invocation.to_command();
+        let args: Vec<_> = cmd.get_args().map(|arg| arg.to_string_lossy().into_owned()).collect();
+        assert_eq!(args, ["-pw", "hunter2"]);
+        assert_eq!(cmd.get_envs().next().unwrap().1.unwrap(), "hunter2");
+    }
+}
@@ -1,11 +1,167 @@
+pub mod askpass;
+#[cfg(unix)]
+pub mod expect;
+pub mod invocation;
+#[cfg(feature = "native-ssh")]
+pub mod native;
 mod platform;
+pub mod proxy;
+pub mod transfer;
 
-#[cfg(unix)]
-use platform::unix::connect_to_host as platform_connect;
+pub use invocation::SshInvocation;
+pub use transfer::Transfer;
+
+use crate::config::{Forward, MenuStep};
+use crate::utils::secret::Secret;
+
+/// How to reach a host that is behind other hosts
+#[derive(Debug, Clone, PartialEq)]
+pub enum Jump {
+    /// Hops that need no stored secrets, first hop first, as
+    /// `user@host:port`; passed to ssh as `ProxyJump`
+    Hops(Vec<String>),
+    /// Command whose stdin and stdout are connected to the host; passed to
+    /// ssh as `ProxyCommand`
+    Command(String),
+}
+
+/// Where and as whom to connect. `totp` is the TOTP secret used to answer
+/// MFA prompts.
+#[derive(Debug, Clone, Copy)]
+pub struct Target<'a> {
+    pub hostname: &'a str,
+    pub port: u16,
+    pub username: &'a str,
+    /// Private key to offer before ssh's defaults
+    pub identity_file: Option<&'a str>,
+    /// Hosts in between, if the host is not reached directly
+    pub jump: Option<&'a Jump>,
+    /// Answers for the bastion's login menu in interactive sessions
+    pub menu: &'a [MenuStep],
+    pub password: Option<&'a Secret>,
+    pub totp: Option<&'a Secret>,
+}
+
+/// An SSH backend
+pub trait Connector {
+    /// The external command an interactive session runs, without running it
+    fn invocation(&self, target: &Target) -> Result<SshInvocation, Box<dyn std::error::Error>>;
+
+    /// The external command that runs `command` on the host
+    fn command_invocation(&self, target: &Target, command: &[String]) -> Result<SshInvocation, Box<dyn std::error::Error>>;
+
+    /// The external command that copies files to or from the host
+    fn copy_invocation(&self, target: &Target, transfer: &Transfer) -> Result<SshInvocation, Box<dyn std::error::Error>>;
+
+    /// The external command that holds `forwards` open without a remote shell
+    fn tunnel_invocation(&self, target: &Target, forwards: &[Forward]) -> Result<SshInvocation, Box<dyn std::error::Error>>;
+
+    /// The external command that relays our stdin and stdout to
+    /// `destination` (`host:port`) through the host
+    fn proxy_invocation(&self, target: &Target, destination: &str) -> Result<SshInvocation, Box<dyn std::error::Error>>;
+
+    /// Make sure the external tools for `target` are installed
+    fn check_dependencies(&self, _target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Open an interactive session
+    fn connect(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+        self.invocation(target)?.exec()
+    }
+
+    /// Run `command` on the host with its output forwarded to ours, and
+    /// return its exit code
+    fn run_command(&self, target: &Target, command: &[String]) -> Result<i32, Box<dyn std::error::Error>> {
+        self.check_dependencies(target)?;
+        self.command_invocation(target, command)?.run()
+    }
+
+    /// Hold `forwards` open until interrupted
+    fn open_tunnel(&self, target: &Target, forwards: &[Forward]) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_dependencies(target)?;
+        self.tunnel_invocation(target, forwards)?.exec()
+    }
+
+    /// Relay our stdin and stdout to `destination` through the host, and
+    /// return the exit code
+    fn proxy(&self, target: &Target, destination: &str) -> Result<i32, Box<dyn std::error::Error>> {
+        self.check_dependencies(target)?;
+        self.proxy_invocation(target, destination)?.run()
+    }
+
+    /// Copy files with progress shown on the terminal, and return the exit code
+    fn copy(&self, target: &Target, transfer: &Transfer) -> Result<i32, Box<dyn std::error::Error>> {
+        self.check_dependencies(target)?;
+        self.copy_invocation(target, transfer)?.run()
+    }
+}
+
+/// The backend for this platform. With the `native-ssh` feature the
+/// built-in client is used when the external tools are not installed.
+pub fn connector(target: &Target) -> Box<dyn Connector> {
+    #[cfg(feature = "native-ssh")]
+    {
+        // On unix MFA logins go through SSH_ASKPASS and need only ssh
+        let password_auth = target.password.is_some() && (cfg!(windows) || target.totp.is_none());
+        if !crate::dependencies::external_ssh_available(password_auth) {
+            return Box::new(native::NativeConnector);
+        }
+    }
+    #[cfg(not(feature = "native-ssh"))]
+    let _ = target;
+
+    #[cfg(unix)]
+    return Box::new(platform::unix::UnixConnector);
+
+    #[cfg(windows)]
+    return Box::new(platform::windows::WindowsConnector);
+}
+
+/// Make sure the external tools for all of `targets` are installed, checking
+/// each kind of login once
+pub fn check_dependencies<'a>(targets: impl IntoIterator<Item = &'a Target<'a>>) -> Result<(), Box<dyn std::error::Error>> {
+    let mut checked: Vec<(bool, bool)> = Vec::new();
+    for target in targets {
+        let kind = (target.password.is_some(), target.totp.is_some());
+        if !checked.contains(&kind) {
+            connector(target).check_dependencies(target)?;
+            checked.push(kind);
+        }
+    }
+    Ok(())
+}
+
+/// Open an interactive session with the default backend
+pub fn connect_to_host(target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+    connector(target).connect(target)
+}
+
+/// Run a command on the host with the default backend and return its exit code
+pub fn run_command(target: &Target, command: &[String]) -> Result<i32, Box<dyn std::error::Error>> {
+    connector(target).run_command(target, command)
+}
+
+/// Copy files to or from the host with the default backend and return the exit code
+pub fn copy(target: &Target, transfer: &Transfer) -> Result<i32, Box<dyn std::error::Error>> {
+    connector(target).copy(target, transfer)
+}
+
+/// Relay our stdin and stdout to `destination` through the host with the
+/// default backend, and return the exit code
+pub fn proxy_through(target: &Target, destination: &str) -> Result<i32, Box<dyn std::error::Error>> {
+    connector(target).proxy(target, destination)
+}
 
-#[cfg(windows)]
-use platform::windows::connect_to_host as platform_connect;
+/// Hold port forwards open with the default backend until interrupted
+pub fn open_tunnel(target: &Target, forwards: &[Forward]) -> Result<(), Box<dyn std::error::Error>> {
+    connector(target).open_tunnel(target, forwards)
+}
 
-pub fn connect_to_host(hostname: &str, port: u16, username: &str, password: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
-    platform_connect(hostname, port, username, password)
-}
\ No newline at end of file
+/// The command the default backend holds port forwards open with, for
+/// running it in the background
+pub fn tunnel_invocation(target: &Target, forwards: &[Forward]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+    let connector = connector(target);
+    connector.check_dependencies(target)?;
+    connector.tunnel_invocation(target, forwards)
+}
@@ -0,0 +1,643 @@
+//! Built-in SSH client
+//!
+//! Available with the `native-ssh` feature and used by `connect_to_host`
+//! when ssh/sshpass (or plink) are not installed. Authentication tries the
+//! default identity files, then the stored password, then
+//! keyboard-interactive, where password and MFA prompts are answered the
+//! same way as through `SSH_ASKPASS`. Host keys are checked against
+//! `~/.ssh/known_hosts`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh::client::{self, AuthResult, KeyboardInteractiveAuthResponse};
+use russh::keys::{self, HashAlg, PrivateKeyWithHashAlg, PublicKeyOrCertificate};
+use russh::{Channel, ChannelMsg};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+use crate::config::Forward;
+use crate::ssh::{askpass, Connector, SshInvocation, Target, Transfer};
+use crate::utils::secret::Secret;
+
+/// Identity files tried for public key authentication, relative to `~/.ssh`
+const DEFAULT_IDENTITY_FILES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+const DEFAULT_TERM: &str = "xterm-256color";
+
+const NO_JUMP_HOSTS: &str = "ssh is not installed, and the built-in SSH client cannot connect through jump hosts";
+
+/// What to do with a host key that is not in known_hosts yet
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownHostKey {
+    /// Ask on the terminal, like ssh does
+    Prompt,
+    Accept,
+    Reject,
+}
+
+/// Credentials and host key settings for a connection
+pub struct Options<'a> {
+    pub username: &'a str,
+    pub password: Option<&'a Secret>,
+    pub totp: Option<&'a Secret>,
+    pub identity_files: Vec<PathBuf>,
+    pub known_hosts: PathBuf,
+    pub unknown_host_key: UnknownHostKey,
+}
+
+impl<'a> Options<'a> {
+    /// Options with the same defaults as OpenSSH
+    pub fn new(username: &'a str, password: Option<&'a Secret>, totp: Option<&'a Secret>) -> Self {
+        let ssh_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from(".")).join(".ssh");
+        Options {
+            username,
+            password,
+            totp,
+            identity_files: DEFAULT_IDENTITY_FILES.iter().map(|name| ssh_dir.join(name)).collect(),
+            known_hosts: ssh_dir.join("known_hosts"),
+            unknown_host_key: UnknownHostKey::Prompt,
+        }
+    }
+
+    /// Try `path` before the default keys
+    pub fn with_identity_file(mut self, path: Option<&str>) -> Self {
+        if let Some(path) = path {
+            self.identity_files.insert(0, expand_tilde(path));
+        }
+        self
+    }
+}
+
+/// Expand a leading `~/` like ssh does for `-i`
+fn expand_tilde(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// The built-in client. It runs no external command, so it has no
+/// invocation to show.
+pub struct NativeConnector;
+
+impl Connector for NativeConnector {
+    fn invocation(&self, _target: &Target) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+        Err("ssh is not installed; the built-in SSH client would be used, which runs no external command".into())
+    }
+
+    fn command_invocation(&self, target: &Target, _command: &[String]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+        self.invocation(target)
+    }
+
+    fn tunnel_invocation(&self, _target: &Target, _forwards: &[Forward]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+        Err("ssh is not installed, and the built-in SSH client cannot forward ports".into())
+    }
+
+    fn proxy_invocation(&self, _target: &Target, _destination: &str) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+        Err("ssh is not installed, and the built-in SSH client cannot relay connections".into())
+    }
+
+    fn copy_invocation(&self, _target: &Target, _transfer: &Transfer) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+        Err("scp is not installed, and the built-in SSH client cannot copy files".into())
+    }
+
+    /// Open an interactive shell on the host, then exit with the remote exit status
+    fn connect(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+        let Target { hostname, port, username, identity_file, jump, menu, password, totp } = *target;
+        if jump.is_some() {
+            return Err(NO_JUMP_HOSTS.into());
+        }
+        if !menu.is_empty() {
+            println!("The built-in client does not answer the login menu; select the asset yourself.");
+        }
+        println!("ssh not found, connecting to {}@{}:{} with the built-in client...", username, hostname, port);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let result = runtime.block_on(async {
+            let options = Options::new(username, password, totp).with_identity_file(identity_file);
+            let session = connect(hostname, port, &options).await?;
+
+            let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+            let term = std::env::var("TERM").unwrap_or_else(|_| DEFAULT_TERM.to_string());
+            let channel = open_shell(&session, &term, cols, rows).await?;
+
+            let (resize_tx, resize_rx) = mpsc::unbounded_channel();
+            watch_window_size(resize_tx)?;
+
+            crossterm::terminal::enable_raw_mode()?;
+            let status = run_session(channel, tokio::io::stdin(), tokio::io::stdout(), tokio::io::stderr(), resize_rx).await;
+            let _ = crossterm::terminal::disable_raw_mode();
+            status
+        });
+        // The stdin reader blocks a worker thread until the next key press, so
+        // do not wait for it
+        runtime.shutdown_background();
+
+        std::process::exit(result? as i32)
+    }
+
+    fn run_command(&self, target: &Target, command: &[String]) -> Result<i32, Box<dyn std::error::Error>> {
+        let Target { hostname, port, username, identity_file, jump, menu: _, password, totp } = *target;
+        if jump.is_some() {
+            return Err(NO_JUMP_HOSTS.into());
+        }
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let result = runtime.block_on(async {
+            let options = Options::new(username, password, totp).with_identity_file(identity_file);
+            let session = connect(hostname, port, &options).await?;
+            let channel = session.channel_open_session().await?;
+            // Joined like ssh does; the remote shell splits it again
+            channel.exec(false, command.join(" ")).await?;
+
+            let (_resize_tx, resize_rx) = mpsc::unbounded_channel();
+            run_session(channel, tokio::io::stdin(), tokio::io::stdout(), tokio::io::stderr(), resize_rx).await
+        });
+        runtime.shutdown_background();
+
+        Ok(result? as i32)
+    }
+}
+
+struct Client {
+    host: String,
+    port: u16,
+    known_hosts: PathBuf,
+    unknown_host_key: UnknownHostKey,
+}
+
+impl client::Handler for Client {
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    async fn check_server_key(&mut self, server_public_key: &PublicKeyOrCertificate) -> Result<bool, Self::Error> {
+        let key = server_public_key.public_key();
+        match keys::check_known_hosts_path(&self.host, self.port, &key, &self.known_hosts) {
+            Ok(true) => return Ok(true),
+            Ok(false) => {}
+            Err(keys::Error::KeyChanged { line }) => {
+                return Err(format!(
+                    "Host key for {}:{} does not match {} line {}. It may have been replaced, or someone may be intercepting the connection.",
+                    self.host, self.port, self.known_hosts.display(), line
+                ).into());
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        let fingerprint = key.fingerprint(HashAlg::Sha256);
+        let accept = match self.unknown_host_key {
+            UnknownHostKey::Accept => true,
+            UnknownHostKey::Reject => false,
+            UnknownHostKey::Prompt => {
+                let prompt = format!(
+                    "The authenticity of host '{}:{}' can't be established.\n{} key fingerprint is {}.\nAre you sure you want to continue connecting (yes/no)? ",
+                    self.host, self.port, key.algorithm(), fingerprint
+                );
+                let answer = askpass::ask_terminal(&prompt, true).map_err(|e| e.to_string())?;
+                answer.trim().eq_ignore_ascii_case("yes")
+            }
+        };
+        if !accept {
+            return Err(format!("Host key verification failed for {}:{} ({})", self.host, self.port, fingerprint).into());
+        }
+
+        keys::known_hosts::learn_known_hosts_path(&self.host, self.port, &key, &self.known_hosts)?;
+        Ok(true)
+    }
+
+    async fn auth_banner(&mut self, banner: &str, _session: &mut client::Session) -> Result<(), Self::Error> {
+        eprint!("{}", banner);
+        Ok(())
+    }
+}
+
+async fn connect(hostname: &str, port: u16, options: &Options<'_>) -> Result<client::Handle<Client>, Box<dyn std::error::Error>> {
+    let config = Arc::new(client::Config {
+        keepalive_interval: Some(Duration::from_secs(30)),
+        ..Default::default()
+    });
+    let client = Client {
+        host: hostname.to_string(),
+        port,
+        known_hosts: options.known_hosts.clone(),
+        unknown_host_key: options.unknown_host_key,
+    };
+
+    let mut session = client::connect(config, (hostname, port), client)
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?;
+    authenticate(&mut session, options).await?;
+    Ok(session)
+}
+
+async fn authenticate(session: &mut client::Handle<Client>, options: &Options<'_>) -> Result<(), Box<dyn std::error::Error>> {
+    for path in options.identity_files.iter().filter(|path| path.exists()) {
+        // Passphrase-protected keys are skipped; the password still works
+        let Ok(key) = keys::load_secret_key(path, None) else {
+            continue;
+        };
+        let hash_alg = session.best_supported_rsa_hash().await?.flatten();
+        let key = PrivateKeyWithHashAlg::new(Arc::new(key), hash_alg);
+        match session.authenticate_publickey(options.username, key).await? {
+            AuthResult::Success => return Ok(()),
+            // Key accepted, but the server wants more (usually an MFA code)
+            AuthResult::Failure { partial_success: true, .. } => break,
+            AuthResult::Failure { .. } => {}
+        }
+    }
+
+    if let Some(password) = options.password
+        && session.authenticate_password(options.username, password.expose()).await?.success()
+    {
+        return Ok(());
+    }
+
+    // Either no password was accepted or the server wants a second factor
+    let mut response = session.authenticate_keyboard_interactive_start(options.username, None).await?;
+    loop {
+        match response {
+            KeyboardInteractiveAuthResponse::Success => return Ok(()),
+            KeyboardInteractiveAuthResponse::Failure { .. } => break,
+            KeyboardInteractiveAuthResponse::InfoRequest { instructions, prompts, .. } => {
+                if !instructions.is_empty() {
+                    eprintln!("{}", instructions);
+                }
+                let mut answers = Vec::with_capacity(prompts.len());
+                for prompt in &prompts {
+                    let answer = askpass::answer_prompt(&prompt.prompt, options.password, options.totp)?;
+                    answers.push(answer.expose().to_string());
+                }
+                response = session.authenticate_keyboard_interactive_respond(answers).await?;
+            }
+        }
+    }
+
+    Err(format!("Authentication failed for {}", options.username).into())
+}
+
+async fn open_shell(session: &client::Handle<Client>, term: &str, cols: u16, rows: u16) -> Result<Channel<client::Msg>, Box<dyn std::error::Error>> {
+    let channel = session.channel_open_session().await?;
+    channel.request_pty(false, term, cols.into(), rows.into(), 0, 0, &[]).await?;
+    channel.request_shell(false).await?;
+    Ok(channel)
+}
+
+/// Copy `input` to the channel and the channel's stdout and stderr to
+/// `output` and `errors` until the remote side closes it, forwarding
+/// terminal sizes received on `resize`. Returns the remote exit status.
+async fn run_session<R, W, E>(
+    channel: Channel<client::Msg>,
+    mut input: R,
+    mut output: W,
+    mut errors: E,
+    mut resize: mpsc::UnboundedReceiver<(u16, u16)>,
+) -> Result<u32, Box<dyn std::error::Error>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    E: AsyncWrite + Unpin,
+{
+    let (mut reader, writer) = channel.split();
+    let mut buffer = [0u8; 4096];
+    let mut input_open = true;
+    let mut exit_status = None;
+
+    loop {
+        tokio::select! {
+            biased;
+
+            Some((cols, rows)) = resize.recv() => {
+                writer.window_change(cols.into(), rows.into(), 0, 0).await?;
+            }
+            message = reader.wait() => match message {
+                Some(ChannelMsg::Data { data }) => {
+                    output.write_all(&data).await?;
+                    output.flush().await?;
+                }
+                Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    errors.write_all(&data).await?;
+                    errors.flush().await?;
+                }
+                Some(ChannelMsg::ExitStatus { exit_status: status }) => exit_status = Some(status),
+                Some(_) => {}
+                None => break,
+            },
+            read = input.read(&mut buffer), if input_open => match read? {
+                0 => {
+                    input_open = false;
+                    writer.eof().await?;
+                }
+                n => writer.data(&buffer[..n]).await?,
+            },
+        }
+    }
+
+    // Same as ssh when the remote side sends no status, e.g. after a signal
+    Ok(exit_status.unwrap_or(255))
+}
+
+#[cfg(unix)]
+fn watch_window_size(resize: mpsc::UnboundedSender<(u16, u16)>) -> Result<(), Box<dyn std::error::Error>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = signal(SignalKind::window_change())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            if let Ok(size) = crossterm::terminal::size()
+                && resize.send(size).is_err()
+            {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Windows has no resize signal; the initial size is kept
+#[cfg(not(unix))]
+fn watch_window_size(_resize: mpsc::UnboundedSender<(u16, u16)>) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use russh::keys::ssh_key::private::Ed25519Keypair;
+    use russh::keys::ssh_key::LineEnding;
+    use russh::keys::{PrivateKey, PublicKey};
+    use russh::server::{self, Auth, Msg, Session};
+    use russh::{ChannelId, MethodKind, MethodSet};
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    use crate::utils::totp::Totp;
+
+    const TOTP_SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    fn random_key() -> PrivateKey {
+        PrivateKey::from(Ed25519Keypair::from_seed(&rand::random()))
+    }
+
+    /// In-process server that accepts the password "hunter2", optionally
+    /// followed by a TOTP code, or `authorized_key`
+    #[derive(Clone, Default)]
+    struct TestServer {
+        authorized_key: Option<PublicKey>,
+        require_mfa: bool,
+        password_ok: bool,
+        events: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl server::Handler for TestServer {
+        type Error = russh::Error;
+
+        async fn auth_password(&mut self, _user: &str, password: &str) -> Result<Auth, Self::Error> {
+            if password != "hunter2" {
+                return Ok(Auth::reject());
+            }
+            if !self.require_mfa {
+                return Ok(Auth::Accept);
+            }
+            self.password_ok = true;
+            Ok(Auth::Reject {
+                proceed_with_methods: Some(MethodSet::from(&[MethodKind::KeyboardInteractive][..])),
+                partial_success: true,
+            })
+        }
+
+        async fn auth_publickey(&mut self, _user: &str, public_key: &PublicKey) -> Result<Auth, Self::Error> {
+            if self.authorized_key.as_ref().map(|key| key.key_data()) == Some(public_key.key_data()) {
+                Ok(Auth::Accept)
+            } else {
+                Ok(Auth::reject())
+            }
+        }
+
+        async fn auth_keyboard_interactive<'a>(
+            &'a mut self,
+            _user: &str,
+            _submethods: &str,
+            response: Option<server::Response<'a>>,
+        ) -> Result<Auth, Self::Error> {
+            if !self.require_mfa {
+                return Ok(Auth::reject());
+            }
+            let Some(mut response) = response else {
+                return Ok(Auth::Partial {
+                    name: "".into(),
+                    instructions: "".into(),
+                    prompts: vec![("MFA code: ".into(), false)].into(),
+                });
+            };
+            let expected = Totp::parse(TOTP_SECRET).unwrap().now();
+            let code = response.next().map(|code| code.to_vec()).unwrap_or_default();
+            if self.password_ok && code == expected.as_bytes() {
+                Ok(Auth::Accept)
+            } else {
+                Ok(Auth::reject())
+            }
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            _channel: Channel<Msg>,
+            reply: server::ChannelOpenHandle,
+            _session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            reply.accept().await;
+            Ok(())
+        }
+
+        async fn pty_request(
+            &mut self,
+            _channel: ChannelId,
+            term: &str,
+            cols: u32,
+            rows: u32,
+            _pix_width: u32,
+            _pix_height: u32,
+            _modes: &[(russh::Pty, u32)],
+            _session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            self.events.lock().unwrap().push(format!("pty {} {}x{}", term, cols, rows));
+            Ok(())
+        }
+
+        async fn window_change_request(
+            &mut self,
+            _channel: ChannelId,
+            cols: u32,
+            rows: u32,
+            _pix_width: u32,
+            _pix_height: u32,
+            _session: &mut Session,
+        ) -> Result<(), Self::Error> {
+            self.events.lock().unwrap().push(format!("resize {}x{}", cols, rows));
+            Ok(())
+        }
+
+        async fn shell_request(&mut self, channel: ChannelId, session: &mut Session) -> Result<(), Self::Error> {
+            session.data(channel, &b"ready\r\n"[..])?;
+            Ok(())
+        }
+
+        async fn exec_request(&mut self, channel: ChannelId, command: &[u8], session: &mut Session) -> Result<(), Self::Error> {
+            let mut output = b"ran: ".to_vec();
+            output.extend_from_slice(command);
+            session.data(channel, output)?;
+            session.extended_data(channel, 1, &b"warning"[..])?;
+            session.exit_status_request(channel, 7)?;
+            session.eof(channel)?;
+            session.close(channel)?;
+            Ok(())
+        }
+
+        async fn data(&mut self, channel: ChannelId, data: &[u8], session: &mut Session) -> Result<(), Self::Error> {
+            session.data(channel, data.to_vec())?;
+            if data.windows(4).any(|window| window == b"exit") {
+                session.exit_status_request(channel, 3)?;
+                session.eof(channel)?;
+                session.close(channel)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Serve connections on a loopback port with the given host key
+    async fn start_server(handler: TestServer, host_key: PrivateKey) -> u16 {
+        let config = Arc::new(server::Config {
+            keys: vec![host_key],
+            auth_rejection_time: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let config = config.clone();
+                let handler = handler.clone();
+                tokio::spawn(async move {
+                    if let Ok(session) = server::run_stream(config, socket, handler).await {
+                        let _ = session.await;
+                    }
+                });
+            }
+        });
+        port
+    }
+
+    fn test_options<'a>(dir: &tempfile::TempDir, password: Option<&'a Secret>, totp: Option<&'a Secret>) -> Options<'a> {
+        Options {
+            username: "alice",
+            password,
+            totp,
+            identity_files: vec![dir.path().join("id_ed25519")],
+            known_hosts: dir.path().join("known_hosts"),
+            unknown_host_key: UnknownHostKey::Accept,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_password_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = start_server(TestServer::default(), random_key()).await;
+
+        let password = Secret::from("hunter2");
+        assert!(connect("127.0.0.1", port, &test_options(&dir, Some(&password), None)).await.is_ok());
+
+        let wrong = Secret::from("wrong");
+        let error = connect("127.0.0.1", port, &test_options(&dir, Some(&wrong), None)).await.err().unwrap();
+        assert!(error.to_string().contains("Authentication failed"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_password_then_mfa_code() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer { require_mfa: true, ..Default::default() };
+        let port = start_server(server, random_key()).await;
+
+        let password = Secret::from("hunter2");
+        let totp = Secret::from(TOTP_SECRET);
+        assert!(connect("127.0.0.1", port, &test_options(&dir, Some(&password), Some(&totp))).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_public_key_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let client_key = random_key();
+        std::fs::write(dir.path().join("id_ed25519"), client_key.to_openssh(LineEnding::LF).unwrap().as_bytes()).unwrap();
+
+        let server = TestServer { authorized_key: Some(client_key.public_key().clone()), ..Default::default() };
+        let port = start_server(server, random_key()).await;
+
+        assert!(connect("127.0.0.1", port, &test_options(&dir, None, None)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_host_key_is_learned_and_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let password = Secret::from("hunter2");
+
+        let mut options = test_options(&dir, Some(&password), None);
+        options.unknown_host_key = UnknownHostKey::Reject;
+        let port = start_server(TestServer::default(), random_key()).await;
+        assert!(connect("127.0.0.1", port, &options).await.is_err());
+
+        // Accepted once, then trusted without asking
+        options.unknown_host_key = UnknownHostKey::Accept;
+        assert!(connect("127.0.0.1", port, &options).await.is_ok());
+        options.unknown_host_key = UnknownHostKey::Reject;
+        assert!(connect("127.0.0.1", port, &options).await.is_ok());
+
+        // A different key on the same address is refused
+        let impostor = start_server(TestServer::default(), random_key()).await;
+        let known_hosts = std::fs::read_to_string(&options.known_hosts).unwrap();
+        std::fs::write(&options.known_hosts, known_hosts.replace(&format!("]:{}", port), &format!("]:{}", impostor))).unwrap();
+        let error = connect("127.0.0.1", impostor, &options).await.err().unwrap();
+        assert!(error.to_string().contains("does not match"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn test_shell_with_pty_and_resize() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::default();
+        let events = server.events.clone();
+        let port = start_server(server, random_key()).await;
+
+        let password = Secret::from("hunter2");
+        let session = connect("127.0.0.1", port, &test_options(&dir, Some(&password), None)).await.unwrap();
+        let channel = open_shell(&session, "xterm-256color", 80, 24).await.unwrap();
+
+        let (resize_tx, resize_rx) = mpsc::unbounded_channel();
+        resize_tx.send((100, 40)).unwrap();
+        let mut output = Vec::new();
+        let status = run_session(channel, &b"echo hi\nexit\n"[..], &mut output, tokio::io::sink(), resize_rx).await.unwrap();
+
+        assert_eq!(status, 3);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.starts_with("ready\r\n"), "{}", output);
+        assert!(output.contains("echo hi"), "{}", output);
+        assert_eq!(*events.lock().unwrap(), ["pty xterm-256color 80x24", "resize 100x40"]);
+    }
+
+    #[tokio::test]
+    async fn test_exec_forwards_output_and_exit_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let port = start_server(TestServer::default(), random_key()).await;
+
+        let password = Secret::from("hunter2");
+        let session = connect("127.0.0.1", port, &test_options(&dir, Some(&password), None)).await.unwrap();
+        let channel = session.channel_open_session().await.unwrap();
+        channel.exec(false, "df -h").await.unwrap();
+
+        let (_resize_tx, resize_rx) = mpsc::unbounded_channel();
+        let (mut output, mut errors) = (Vec::new(), Vec::new());
+        let status = run_session(channel, tokio::io::empty(), &mut output, &mut errors, resize_rx).await.unwrap();
+
+        assert_eq!(status, 7);
+        assert_eq!(output, b"ran: df -h");
+        assert_eq!(errors, b"warning");
+    }
+}
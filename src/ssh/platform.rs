@@ -4,69 +4,395 @@
 //! It uses conditional compilation to provide the appropriate implementation
 //! for each target platform.
 
+use crate::ssh::{Jump, SshInvocation, Target};
+
+/// `-i <identity file>` when the host has one; ssh, scp and plink all take
+/// the same option
+fn with_identity(invocation: SshInvocation, target: &Target) -> SshInvocation {
+    match target.identity_file {
+        Some(path) => invocation.arg("-i").arg(path),
+        None => invocation,
+    }
+}
+
+/// The jump hosts as an OpenSSH option, which ssh and scp both accept
+fn with_jump(invocation: SshInvocation, target: &Target) -> SshInvocation {
+    match target.jump {
+        Some(Jump::Hops(hops)) => invocation.arg("-o").arg(format!("ProxyJump={}", hops.join(","))),
+        Some(Jump::Command(command)) => invocation.arg("-o").arg(format!("ProxyCommand={}", command)),
+        None => invocation,
+    }
+}
+
 #[cfg(unix)]
 pub mod unix {
-    use std::process::Command;
-    use std::os::unix::process::CommandExt;
-
-    pub fn connect_to_host(hostname: &str, port: u16, username: &str, password: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
-        // Check dependencies before attempting connection
-        crate::dependencies::check_ssh_dependencies(password.is_some())?;
-        
-        let mut cmd;
-        if let Some(pw) = password {
-            // Use sshpass to provide the password non-interactively
-            cmd = Command::new("sshpass");
-            cmd.arg("-p").arg(pw)
-               .arg("ssh")
-               .arg("-p").arg(port.to_string())
-               .arg(format!("{}@{}", username, hostname));
-            println!("Launching SSH connection to {}@{}:{} with password...", username, hostname, port);
-        } else {
-            cmd = Command::new("ssh");
-            cmd.arg("-p").arg(port.to_string())
-               .arg(format!("{}@{}", username, hostname));
-            println!("Launching SSH connection to {}@{}:{}...", username, hostname, port);
-        }
-        // This will replace the current process with SSH or sshpass
-        let error = cmd.exec();
-        Err(format!("Failed to execute SSH command: {}", error).into())
+    use super::{with_identity, with_jump};
+    use crate::config::Forward;
+    use crate::ssh::transfer::Transfer;
+    use crate::ssh::{askpass, expect, Connector, SshInvocation, Target};
+    use crate::utils::totp::Totp;
+
+    /// OpenSSH, with sshpass or `SSH_ASKPASS` answering password prompts
+    pub struct UnixConnector;
+
+    impl UnixConnector {
+        /// `program` (ssh or scp), or sshpass running it, with the
+        /// environment needed to answer password and MFA prompts.
+        ///
+        /// Secrets are only ever passed through the environment, never in
+        /// the argument vector, which other users can read via `ps` or
+        /// `/proc/<pid>/cmdline`.
+        fn with_credentials(target: &Target, program: &str) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            let invocation = if let Some(secret) = target.totp {
+                // sshpass only answers a single password prompt, so let ssh
+                // ask this executable for the password and the MFA code instead
+                let codes = askpass::prepare_codes(&Totp::parse(secret.expose())?, crate::utils::now());
+                let mut invocation = SshInvocation::new(program)
+                    .env("SSH_ASKPASS", askpass::program_path().to_string_lossy())
+                    .env("SSH_ASKPASS_REQUIRE", "force")
+                    .secret_env(askpass::CODES_ENV, &codes);
+                if let Some(pw) = target.password {
+                    invocation = invocation.secret_env(askpass::PASSWORD_ENV, pw);
+                }
+                invocation
+            } else if let Some(pw) = target.password {
+                // Use sshpass to provide the password non-interactively; -e
+                // makes it read the password from $SSHPASS
+                SshInvocation::new("sshpass")
+                    .secret_env("SSHPASS", pw)
+                    .arg("-e")
+                    .arg(program)
+            } else {
+                SshInvocation::new(program)
+            };
+            if target.totp.is_some() {
+                return Ok(invocation);
+            }
+            // A jump host's proxy runs with the environment of the ssh that
+            // started it, which may be set up to answer another host's prompts
+            Ok(invocation
+                .env_remove("SSH_ASKPASS_REQUIRE")
+                .env_remove(askpass::PASSWORD_ENV)
+                .env_remove(askpass::CODES_ENV))
+        }
+
+        fn ssh(target: &Target) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            let invocation = Self::with_credentials(target, "ssh")?.arg("-p").arg(target.port.to_string());
+            Ok(with_jump(with_identity(invocation, target), target))
+        }
+    }
+
+    impl Connector for UnixConnector {
+        fn invocation(&self, target: &Target) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // `--` stops ssh from reading a user or host name as an option
+            Ok(Self::ssh(target)?.arg("--").arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn command_invocation(&self, target: &Target, command: &[String]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // `--` stops ssh from reading options out of the destination or
+            // the remote command
+            Ok(Self::ssh(target)?
+                .arg("--")
+                .arg(format!("{}@{}", target.username, target.hostname))
+                .args(command))
+        }
+
+        fn tunnel_invocation(&self, target: &Target, forwards: &[Forward]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // Fail instead of running without a forward whose port is taken
+            let mut invocation = Self::ssh(target)?.arg("-N").arg("-o").arg("ExitOnForwardFailure=yes");
+            for forward in forwards {
+                let (flag, value) = forward.ssh_option();
+                invocation = invocation.arg(flag).arg(value);
+            }
+            Ok(invocation.arg("--").arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn proxy_invocation(&self, target: &Target, destination: &str) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            Ok(Self::ssh(target)?
+                .arg("-W")
+                .arg(destination)
+                .arg("--")
+                .arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn copy_invocation(&self, target: &Target, transfer: &Transfer) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            let invocation = Self::with_credentials(target, "scp")?.arg("-P").arg(target.port.to_string());
+            let mut invocation = with_jump(with_identity(invocation, target), target);
+            if transfer.recursive {
+                invocation = invocation.arg("-r");
+            }
+            Ok(invocation.arg("--").args(transfer.endpoints(target)))
+        }
+
+        fn check_dependencies(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+            // sshpass is not needed when prompts are answered through SSH_ASKPASS
+            if target.totp.is_some() {
+                askpass::install_program()?;
+            }
+            crate::dependencies::check_ssh_dependencies(target.password.is_some() && target.totp.is_none())
+        }
+
+        fn connect(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+            // Check dependencies before attempting connection
+            self.check_dependencies(target)?;
+
+            let invocation = self.invocation(target)?;
+            let Target { hostname, port, username, .. } = target;
+            if target.totp.is_some() {
+                println!("Launching SSH connection to {}@{}:{} with MFA...", username, hostname, port);
+            } else if target.password.is_some() {
+                println!("Launching SSH connection to {}@{}:{} with password...", username, hostname, port);
+            } else {
+                println!("Launching SSH connection to {}@{}:{}...", username, hostname, port);
+            }
+            if !target.menu.is_empty() {
+                // ssh stays a child so its output can be watched for the menu
+                std::process::exit(expect::run(&invocation, target.menu, target.totp)?);
+            }
+            // This will replace the current process with SSH or sshpass
+            invocation.exec()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::ssh::invocation::Value;
+        use crate::ssh::transfer::Direction;
+        use crate::ssh::Jump;
+        use crate::utils::secret::Secret;
+
+        fn target<'a>(password: Option<&'a Secret>, totp: Option<&'a Secret>) -> Target<'a> {
+            Target { hostname: "10.0.0.1", port: 60022, username: "alice", identity_file: None, jump: None, menu: &[], password, totp }
+        }
+
+        #[test]
+        fn test_password_not_in_argv() {
+            let password = Secret::from("pa55-w0rd");
+            let invocation = UnixConnector.invocation(&target(Some(&password), None)).unwrap();
+
+            assert_eq!(invocation.to_string(), "SSHPASS=*** sshpass -e ssh -p 60022 -- alice@10.0.0.1");
+            assert!(invocation.args.iter().all(|arg| matches!(arg, Value::Plain(_))));
+            assert_eq!(invocation.get_env("SSHPASS"), Some(&Value::Secret(password)));
+        }
+
+        #[test]
+        fn test_mfa_secrets_not_in_argv() {
+            let password = Secret::from("pa55-w0rd");
+            let totp = Secret::from("JBSWY3DPEHPK3PXP");
+            let invocation = UnixConnector.invocation(&target(Some(&password), Some(&totp))).unwrap();
+
+            assert_eq!(invocation.program, "ssh");
+            assert!(invocation.args.iter().all(|arg| matches!(arg, Value::Plain(_))));
+            assert_eq!(invocation.get_env(askpass::PASSWORD_ENV), Some(&Value::Secret(password)));
+            let Some(Value::Secret(codes)) = invocation.get_env(askpass::CODES_ENV) else { panic!("no codes") };
+            assert!(!codes.expose().contains(totp.expose()));
+            assert!(codes.expose().contains(&Totp::parse(totp.expose()).unwrap().now()));
+        }
+
+        #[test]
+        fn test_key_auth_is_plain_ssh() {
+            let invocation = UnixConnector.invocation(&target(None, None)).unwrap();
+
+            assert_eq!(invocation.to_string(), "ssh -p 60022 -- alice@10.0.0.1");
+        }
+
+        #[test]
+        fn test_identity_file_is_passed_to_ssh_and_scp() {
+            let target = Target { identity_file: Some("~/.ssh/id_prod"), ..target(None, None) };
+            assert_eq!(UnixConnector.invocation(&target).unwrap().to_string(), "ssh -p 60022 -i '~/.ssh/id_prod' -- alice@10.0.0.1");
+
+            let transfer = Transfer { direction: Direction::Upload, local: "app.tar", remote: "/tmp", recursive: false };
+            let invocation = UnixConnector.copy_invocation(&target, &transfer).unwrap();
+            assert_eq!(invocation.to_string(), "scp -P 60022 -i '~/.ssh/id_prod' -- app.tar alice@10.0.0.1:/tmp");
+        }
+
+        #[test]
+        fn test_jump_hosts() {
+            let hops = Jump::Hops(vec!["ops@bastion:2222".to_string(), "ops@[fd00::1]:22".to_string()]);
+            let direct = Target { jump: Some(&hops), ..target(None, None) };
+            assert_eq!(
+                UnixConnector.invocation(&direct).unwrap().to_string(),
+                "ssh -p 60022 -o 'ProxyJump=ops@bastion:2222,ops@[fd00::1]:22' -- alice@10.0.0.1"
+            );
+
+            let password = Secret::from("pa55-w0rd");
+            let command = Jump::Command("ali-bastion proxy db".to_string());
+            let proxied = Target { jump: Some(&command), ..target(Some(&password), None) };
+            let invocation = UnixConnector.invocation(&proxied).unwrap();
+            assert_eq!(invocation.to_string(), "SSHPASS=*** sshpass -e ssh -p 60022 -o 'ProxyCommand=ali-bastion proxy db' -- alice@10.0.0.1");
+            assert!(invocation.env_remove.contains(&"SSH_ASKPASS_REQUIRE".to_string()));
+        }
+
+        #[test]
+        fn test_remote_command_follows_destination() {
+            let password = Secret::from("pa55-w0rd");
+            let command = ["systemctl".to_string(), "restart".to_string(), "nginx".to_string()];
+            let invocation = UnixConnector.command_invocation(&target(Some(&password), None), &command).unwrap();
+
+            assert_eq!(
+                invocation.to_string(),
+                "SSHPASS=*** sshpass -e ssh -p 60022 -- alice@10.0.0.1 systemctl restart nginx"
+            );
+        }
+
+        #[test]
+        fn test_tunnel_opens_only_forwards() {
+            let forwards = [
+                Forward::parse("db=L:15432:rds.internal:3306").unwrap(),
+                Forward::parse("socks=D:1080").unwrap(),
+            ];
+            let invocation = UnixConnector.tunnel_invocation(&target(None, None), &forwards).unwrap();
+
+            assert_eq!(
+                invocation.to_string(),
+                "ssh -p 60022 -N -o ExitOnForwardFailure=yes -L 15432:rds.internal:3306 -D 1080 -- alice@10.0.0.1"
+            );
+        }
+
+        #[test]
+        fn test_proxy_relays_through_the_host() {
+            let password = Secret::from("hunter2");
+            let invocation = UnixConnector.proxy_invocation(&target(Some(&password), None), "[fd00::5]:22").unwrap();
+
+            assert_eq!(invocation.to_string(), "SSHPASS=*** sshpass -e ssh -p 60022 -W '[fd00::5]:22' -- alice@10.0.0.1");
+        }
+
+        #[test]
+        fn test_copy_uses_scp_with_the_same_credentials() {
+
+            let password = Secret::from("pa55-w0rd");
+            let transfer = Transfer { direction: Direction::Upload, local: "dist", remote: "/srv/app", recursive: true };
+            let invocation = UnixConnector.copy_invocation(&target(Some(&password), None), &transfer).unwrap();
+
+            assert_eq!(
+                invocation.to_string(),
+                "SSHPASS=*** sshpass -e scp -P 60022 -r -- dist alice@10.0.0.1:/srv/app"
+            );
+        }
     }
 }
 
 #[cfg(windows)]
 pub mod windows {
-    use std::process::Command;
-
-    pub fn connect_to_host(hostname: &str, port: u16, username: &str, password: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
-        // Check dependencies before attempting connection
-        crate::dependencies::check_ssh_dependencies(password.is_some())?;
-        
-        if let Some(pw) = password {
-            // On Windows, we'll use PowerShell with plink (from PuTTY suite)
-            // This requires plink to be installed and available in PATH
-            let mut cmd = Command::new("plink");
-            cmd.arg("-P").arg(port.to_string())
-               .arg("-pw").arg(pw)
-               .arg(format!("{}@{}", username, hostname));
-            println!("Launching SSH connection to {}@{}:{} with password...", username, hostname, port);
-            
-            let output = cmd.output()?;
-            if !output.status.success() {
-                return Err(format!("SSH command failed with status: {}", output.status).into());
+    use super::{with_identity, with_jump};
+    use crate::config::Forward;
+    use crate::ssh::transfer::Transfer;
+    use crate::ssh::{Connector, Jump, SshInvocation, Target};
+
+    /// plink for password logins, the Windows OpenSSH client otherwise
+    pub struct WindowsConnector;
+
+    impl WindowsConnector {
+        fn ssh(target: &Target) -> SshInvocation {
+            let invocation = if let Some(pw) = target.password {
+                // On Windows, we'll use plink (from PuTTY suite), which
+                // only accepts the password on the command line
+                let invocation = SshInvocation::new("plink")
+                    .arg("-P").arg(target.port.to_string())
+                    .arg("-pw").secret_arg(pw);
+                match target.jump {
+                    Some(jump) => invocation.arg("-proxycmd").arg(plink_proxy_command(jump)),
+                    None => invocation,
+                }
+            } else {
+                // Without password, use Windows SSH client if available
+                let invocation = SshInvocation::new("ssh")
+                    .arg("-p").arg(target.port.to_string());
+                with_jump(invocation, target)
+            };
+            with_identity(invocation, target)
+        }
+    }
+
+    /// plink has no ProxyJump, so hops go through an ssh that relays to
+    /// plink's `%host:%port`
+    fn plink_proxy_command(jump: &Jump) -> String {
+        match jump {
+            // ssh takes user@host:port as a destination only in URI form
+            Jump::Hops(hops) => match hops.split_last() {
+                Some((last, [])) => format!("ssh -W %host:%port ssh://{}", last),
+                Some((last, rest)) => format!("ssh -J {} -W %host:%port ssh://{}", rest.join(","), last),
+                None => String::new(),
+            },
+            Jump::Command(command) => command.clone(),
+        }
+    }
+
+    impl Connector for WindowsConnector {
+        fn invocation(&self, target: &Target) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // `--` stops plink and ssh from reading a user or host name as an option
+            Ok(Self::ssh(target).arg("--").arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn command_invocation(&self, target: &Target, command: &[String]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            Ok(Self::ssh(target)
+                .arg("--")
+                .arg(format!("{}@{}", target.username, target.hostname))
+                .args(command))
+        }
+
+        fn tunnel_invocation(&self, target: &Target, forwards: &[Forward]) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // plink and ssh share the -N, -L, -R and -D options
+            let mut invocation = Self::ssh(target).arg("-N");
+            for forward in forwards {
+                let (flag, value) = forward.ssh_option();
+                invocation = invocation.arg(flag).arg(value);
             }
-        } else {
-            // Without password, use Windows SSH client if available
-            let mut cmd = Command::new("ssh");
-            cmd.arg("-p").arg(port.to_string())
-               .arg(format!("{}@{}", username, hostname));
-            println!("Launching SSH connection to {}@{}:{}...", username, hostname, port);
-            
-            let output = cmd.output()?;
-            if !output.status.success() {
-                return Err(format!("SSH command failed with status: {}", output.status).into());
+            Ok(invocation.arg("--").arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn proxy_invocation(&self, target: &Target, destination: &str) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            let flag = if target.password.is_some() { "-nc" } else { "-W" };
+            Ok(Self::ssh(target)
+                .arg(flag)
+                .arg(destination)
+                .arg("--")
+                .arg(format!("{}@{}", target.username, target.hostname)))
+        }
+
+        fn copy_invocation(&self, target: &Target, transfer: &Transfer) -> Result<SshInvocation, Box<dyn std::error::Error>> {
+            // pscp is plink's counterpart for copying files
+            let mut invocation = match target.password {
+                Some(pw) => {
+                    let invocation = SshInvocation::new("pscp").arg("-P").arg(target.port.to_string()).arg("-pw").secret_arg(pw);
+                    match target.jump {
+                        Some(jump) => invocation.arg("-proxycmd").arg(plink_proxy_command(jump)),
+                        None => invocation,
+                    }
+                }
+                None => with_jump(SshInvocation::new("scp").arg("-P").arg(target.port.to_string()), target),
+            };
+            invocation = with_identity(invocation, target);
+            if transfer.recursive {
+                invocation = invocation.arg("-r");
             }
+            Ok(invocation.args(transfer.endpoints(target)))
+        }
+
+        fn check_dependencies(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+            crate::dependencies::check_ssh_dependencies(target.password.is_some())
+        }
+
+        fn connect(&self, target: &Target) -> Result<(), Box<dyn std::error::Error>> {
+            // Check dependencies before attempting connection
+            self.check_dependencies(target)?;
+
+            if target.totp.is_some() {
+                println!("MFA prompts are not answered automatically on Windows. Use 'ali-bastion otp' to get the current code.");
+            }
+            if !target.menu.is_empty() {
+                println!("The login menu is not answered automatically on Windows; select the asset yourself.");
+            }
+
+            let invocation = self.invocation(target)?;
+            let Target { hostname, port, username, .. } = target;
+            if target.password.is_some() {
+                println!("Launching SSH connection to {}@{}:{} with password...", username, hostname, port);
+            } else {
+                println!("Launching SSH connection to {}@{}:{}...", username, hostname, port);
+            }
+            invocation.exec()
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,88 @@
+//! stdio relay for use as an OpenSSH `ProxyCommand`
+//!
+//! ssh talks to the proxy over its stdin and stdout, so nothing but relayed
+//! bytes may be written to stdout.
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::thread;
+
+use crate::ssh::transfer::bracket_ipv6;
+
+/// `host:port` as ssh's `-W` and plink's `-nc` expect it
+pub fn destination(hostname: &str, port: u16) -> String {
+    format!("{}:{}", bracket_ipv6(hostname), port)
+}
+
+/// Connect to `hostname:port` and relay it to our stdin and stdout
+pub fn relay_stdio(hostname: &str, port: u16) -> Result<(), Box<dyn std::error::Error>> {
+    let stream = TcpStream::connect((hostname, port)).map_err(|e| format!("Failed to connect to {}: {}", destination(hostname, port), e))?;
+    stream.set_nodelay(true)?;
+    relay(stream, io::stdin(), io::stdout())?;
+    Ok(())
+}
+
+/// Copy `input` to `stream` and `stream` to `output` until the remote end
+/// closes the connection
+pub fn relay<R, W>(stream: TcpStream, input: R, mut output: W) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let mut upstream = stream.try_clone()?;
+    // Left blocked on stdin when the remote end closes first; the process
+    // exits right after, so it is not joined
+    thread::spawn(move || {
+        let _ = copy_flushing(input, &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    copy_flushing(&stream, &mut output)
+}
+
+/// Like `io::copy`, but flushes after every chunk, as stdout is line-buffered
+fn copy_flushing<R: Read, W: Write>(mut reader: R, writer: &mut W) -> io::Result<()> {
+    let mut buffer = [0u8; 16 * 1024];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        writer.flush()?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_destination() {
+        assert_eq!(destination("10.0.0.1", 22), "10.0.0.1:22");
+        assert_eq!(destination("fd00::1", 60022), "[fd00::1]:60022");
+    }
+
+    #[test]
+    fn test_relay_copies_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            socket.read_to_end(&mut request).unwrap();
+            socket.write_all(b"SSH-2.0-Test\r\n").unwrap();
+            socket.write_all(&request).unwrap();
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut output = Vec::new();
+        relay(stream, Cursor::new(b"SSH-2.0-Client\r\n".to_vec()), &mut output).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(output, b"SSH-2.0-Test\r\nSSH-2.0-Client\r\n");
+    }
+}
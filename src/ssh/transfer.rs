@@ -0,0 +1,68 @@
+//! File transfers with scp (or pscp on Windows)
+
+use crate::ssh::Target;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Upload,
+    Download,
+}
+
+/// One `put` or `get`
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer<'a> {
+    pub direction: Direction,
+    pub local: &'a str,
+    /// Path on the host; empty means the login directory
+    pub remote: &'a str,
+    pub recursive: bool,
+}
+
+impl Transfer<'_> {
+    /// The source and destination arguments, in scp order
+    pub fn endpoints(&self, target: &Target) -> [String; 2] {
+        let remote = format!("{}@{}:{}", target.username, bracket_ipv6(target.hostname), self.remote);
+        match self.direction {
+            Direction::Upload => [self.local.to_string(), remote],
+            Direction::Download => [remote, self.local.to_string()],
+        }
+    }
+}
+
+/// Split a `<host>:<path>` argument into the host name and the remote path
+pub fn parse_remote(spec: &str) -> Option<(&str, &str)> {
+    match spec.split_once(':') {
+        Some((host, path)) if !host.is_empty() => Some((host, path)),
+        _ => None,
+    }
+}
+
+/// scp needs IPv6 addresses in brackets to tell them apart from the path
+pub(crate) fn bracket_ipv6(hostname: &str) -> String {
+    if hostname.contains(':') {
+        format!("[{}]", hostname)
+    } else {
+        hostname.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote() {
+        assert_eq!(parse_remote("prod:/var/log/app.log"), Some(("prod", "/var/log/app.log")));
+        assert_eq!(parse_remote("prod:"), Some(("prod", "")));
+        assert_eq!(parse_remote("./local.txt"), None);
+        assert_eq!(parse_remote(":/tmp"), None);
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let target = Target { hostname: "fd00::1", port: 60022, username: "alice", identity_file: None, jump: None, menu: &[], password: None, totp: None };
+        let transfer = Transfer { direction: Direction::Download, local: ".", remote: "logs", recursive: true };
+
+        assert_eq!(transfer.endpoints(&target), ["alice@[fd00::1]:logs", "."]);
+    }
+}
@@ -0,0 +1,283 @@
+//! OpenSSH client configuration
+//!
+//! `ssh-config export` writes one `Host` block per configured host to
+//! `~/.ali-bastion/ssh_config`, a file owned by ali-bastion that
+//! `~/.ssh/config` pulls in with an `Include` line. Once the file exists it
+//! is rewritten whenever hosts are added or removed.
+//!
+//! The file is plaintext, so it lists the host inventory even when the
+//! configuration itself is stored encrypted; exporting then has to be
+//! asked for explicitly, and the file is no longer rewritten on changes.
+
+pub mod parse;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{Config, HostConfig};
+
+const HEADER: &str = "# Generated by ali-bastion from its host list; edits are overwritten.\n\
+                      # Regenerate with `ali-bastion ssh-config export`.\n";
+
+pub fn get_include_path() -> PathBuf {
+    let mut include_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    include_path.push(".ali-bastion");
+    include_path.push("ssh_config");
+    include_path
+}
+
+pub fn get_user_config_path() -> PathBuf {
+    let mut config_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    config_path.push(".ssh");
+    config_path.push("config");
+    config_path
+}
+
+/// The include file's contents, and the names of hosts left out because ssh
+/// would read them as patterns or several names, or a value as several lines
+pub fn render(config: &Config) -> (String, Vec<String>) {
+    let mut hosts: Vec<&HostConfig> = config.hosts.values().collect();
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let mut content = HEADER.to_string();
+    let mut skipped = Vec::new();
+    for host in hosts {
+        // A host behind a skipped jump host would be reached directly
+        let values = [Some(&host.hostname), Some(&host.username), host.identity_file.as_ref()];
+        if !is_plain_host_name(&host.name)
+            || host.via.as_deref().is_some_and(|via| !is_plain_host_name(via))
+            || values.into_iter().flatten().any(|value| value.chars().any(char::is_control))
+        {
+            skipped.push(host.name.clone());
+            continue;
+        }
+        content.push_str(&format!("\nHost {}\n", host.name));
+        content.push_str(&format!("    HostName {}\n", quote(&host.hostname)));
+        content.push_str(&format!("    Port {}\n", host.port));
+        content.push_str(&format!("    User {}\n", quote(&host.username)));
+        if let Some(identity_file) = &host.identity_file {
+            content.push_str(&format!("    IdentityFile {}\n", quote(identity_file)));
+        }
+        if let Some(via) = &host.via {
+            content.push_str(&format!("    ProxyJump {}\n", via));
+        }
+    }
+    (content, skipped)
+}
+
+fn is_plain_host_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || "*?!,\"#".contains(c))
+}
+
+/// `value` as a single ssh config argument: double-quoted when it contains
+/// whitespace, quotes, backslashes or `#`, with `"` and `\` escaped inside
+fn quote(value: &str) -> String {
+    if value.chars().any(|c| c.is_whitespace() || "\"'\\#".contains(c)) {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write `content` to `path` unless it already holds exactly that. Returns
+/// whether the file was written.
+pub fn write_if_changed(path: &Path, content: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    if fs::read_to_string(path).is_ok_and(|existing| existing == content) {
+        return Ok(false);
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)?;
+    Ok(true)
+}
+
+/// Rewrite the include file if it has been exported before. Returns false
+/// if it was left as it is because the configuration is stored encrypted.
+pub fn refresh(config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    refresh_file(&get_include_path(), config)
+}
+
+fn refresh_file(path: &Path, config: &Config) -> Result<bool, Box<dyn std::error::Error>> {
+    if !path.exists() {
+        return Ok(true);
+    }
+    // New hosts would leak into the plaintext file
+    if config.is_encrypted() {
+        return Ok(false);
+    }
+    write_if_changed(path, &render(config).0)?;
+    Ok(true)
+}
+
+/// How `include` is written in `~/.ssh/config`: relative to the home
+/// directory where possible, like the rest of that file usually is
+pub fn include_reference(include: &Path) -> String {
+    match dirs::home_dir().and_then(|home| include.strip_prefix(home).ok().map(Path::to_path_buf)) {
+        Some(relative) => format!("~/{}", relative.display()),
+        None => include.display().to_string(),
+    }
+}
+
+/// Whether the ssh config at `ssh_config` has an `Include` line for `include`
+pub fn is_included(ssh_config: &Path, include: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    let content = match fs::read_to_string(ssh_config) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let names = [include_reference(include), include.display().to_string()];
+    Ok(content.lines().any(|line| {
+        let mut words = line.split_whitespace();
+        words.next().is_some_and(|keyword| keyword.eq_ignore_ascii_case("include"))
+            && words.any(|word| names.iter().any(|name| name == word.trim_matches('"')))
+    }))
+}
+
+/// Add an `Include` line for `include` to the top of `ssh_config`, unless it
+/// is already there. Returns whether the file was changed.
+pub fn install_include(ssh_config: &Path, include: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+    if is_included(ssh_config, include)? {
+        return Ok(false);
+    }
+    let existing = match fs::read_to_string(ssh_config) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    // An Include after a Host line would only apply within that block, so
+    // it goes first
+    let mut content = format!("Include {}\n", include_reference(include));
+    if !existing.is_empty() {
+        content.push('\n');
+        content.push_str(&existing);
+    }
+
+    if let Some(parent) = ssh_config.parent() {
+        create_ssh_dir(parent)?;
+    }
+    fs::write(ssh_config, content)?;
+    Ok(true)
+}
+
+/// ssh refuses configuration in directories others can write to
+fn create_ssh_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if path.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn host(name: &str, hostname: &str) -> HostConfig {
+        HostConfig {
+            name: name.to_string(),
+            hostname: hostname.to_string(),
+            port: 60022,
+            username: "alice".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let mut config = Config::new();
+        config.add_host(HostConfig { identity_file: Some("~/.ssh/id prod".to_string()), ..host("prod", "10.0.0.1") });
+        config.add_host(HostConfig { via: Some("prod".to_string()), ..host("db", "10.0.0.2") });
+        config.add_host(host("web *", "10.0.0.3"));
+
+        let (content, skipped) = render(&config);
+        assert_eq!(
+            content,
+            format!(
+                "{}\nHost db\n    HostName 10.0.0.2\n    Port 60022\n    User alice\n    ProxyJump prod\n\
+                 \nHost prod\n    HostName 10.0.0.1\n    Port 60022\n    User alice\n    IdentityFile \"~/.ssh/id prod\"\n",
+                HEADER
+            )
+        );
+        assert_eq!(skipped, ["web *"]);
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("alice"), "alice");
+        assert_eq!(quote("~/.ssh/id prod"), "\"~/.ssh/id prod\"");
+        assert_eq!(quote("a\" b"), r#""a\" b""#);
+        assert_eq!(quote(r"C:\keys\id"), r#""C:\\keys\\id""#);
+        assert_eq!(quote("ops#1"), "\"ops#1\"");
+
+        let mut config = Config::new();
+        config.add_host(host("evil", "10.0.0.1\n    ProxyCommand touch /tmp/x"));
+        assert_eq!(render(&config).1, ["evil"]);
+    }
+
+    #[test]
+    fn test_refresh_leaves_file_of_encrypted_config() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ssh_config");
+        let mut config = Config::new();
+        config.add_host(host("prod", "10.0.0.1"));
+
+        assert!(refresh_file(&path, &config).unwrap());
+        assert!(!path.exists());
+
+        fs::write(&path, HEADER).unwrap();
+        assert!(refresh_file(&path, &config).unwrap());
+        assert!(fs::read_to_string(&path).unwrap().contains("Host prod"));
+
+        config.enable_encryption([7; 32]);
+        config.add_host(host("secret", "10.0.0.2"));
+        assert!(!refresh_file(&path, &config).unwrap());
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret"));
+    }
+
+    #[test]
+    fn test_write_if_changed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("nested").join("ssh_config");
+
+        assert!(write_if_changed(&path, "Host a\n").unwrap());
+        assert!(!write_if_changed(&path, "Host a\n").unwrap());
+        assert!(write_if_changed(&path, "Host b\n").unwrap());
+    }
+
+    #[test]
+    fn test_install_include_once_before_host_blocks() {
+        let dir = TempDir::new().unwrap();
+        let ssh_config = dir.path().join(".ssh").join("config");
+        let include = dir.path().join("ssh_config");
+
+        assert!(install_include(&ssh_config, &include).unwrap());
+        fs::write(&ssh_config, format!("{}Host github.com\n    User git\n", fs::read_to_string(&ssh_config).unwrap())).unwrap();
+        assert!(!install_include(&ssh_config, &include).unwrap());
+
+        let content = fs::read_to_string(&ssh_config).unwrap();
+        assert_eq!(content, format!("Include {}\nHost github.com\n    User git\n", include.display()));
+    }
+
+    #[test]
+    fn test_install_include_keeps_existing_config() {
+        let dir = TempDir::new().unwrap();
+        let ssh_config = dir.path().join("config");
+        let include = dir.path().join("ssh_config");
+        fs::write(&ssh_config, "Host *\n    ServerAliveInterval 30\n").unwrap();
+
+        assert!(install_include(&ssh_config, &include).unwrap());
+        assert_eq!(
+            fs::read_to_string(&ssh_config).unwrap(),
+            format!("Include {}\n\nHost *\n    ServerAliveInterval 30\n", include.display())
+        );
+        assert!(is_included(&ssh_config, &include).unwrap());
+    }
+}
@@ -0,0 +1,568 @@
+//! Reading OpenSSH client configuration
+//!
+//! Only what is needed to turn `Host` entries into hosts is understood:
+//! `Host` blocks with wildcard and negated patterns, `Include` with globs,
+//! and the HostName, Port, User, IdentityFile and ProxyJump options. As in
+//! ssh, the first value found for an option wins, so `Host *` defaults at
+//! the end of the file only fill in what earlier blocks left unset. `Match`
+//! blocks are ignored.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use crate::config::{Config, HostConfig};
+
+/// How deeply `Include` may nest, as in ssh
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+/// A host named in a `Host` line, with the options ssh would use for it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SshHost {
+    pub alias: String,
+    pub hostname: Option<String>,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub identity_file: Option<String>,
+    pub proxy_jump: Option<String>,
+}
+
+impl SshHost {
+    /// The host as ssh would reach it, with ssh's defaults filled in
+    pub fn to_host_config(&self, default_user: &str) -> HostConfig {
+        HostConfig {
+            name: self.alias.clone(),
+            hostname: self.hostname.clone().unwrap_or_else(|| self.alias.clone()),
+            port: self.port.unwrap_or(22),
+            username: self.user.clone().unwrap_or_else(|| default_user.to_string()),
+            identity_file: self.identity_file.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// One hop of a `ProxyJump` value
+#[derive(Debug, Clone, PartialEq)]
+pub struct JumpHop {
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+}
+
+/// The hops of a `ProxyJump` value, first hop first. Each is
+/// `[user@]host[:port]`, optionally as an `ssh://` URI; `None` if a hop
+/// cannot be parsed.
+pub fn parse_proxy_jump(value: &str) -> Option<Vec<JumpHop>> {
+    value.split(',').map(|hop| parse_jump_hop(hop.trim())).collect()
+}
+
+fn parse_jump_hop(hop: &str) -> Option<JumpHop> {
+    let hop = hop.strip_prefix("ssh://").unwrap_or(hop);
+    let (user, address) = match hop.rsplit_once('@') {
+        Some((user, address)) => (Some(user.to_string()), address),
+        None => (None, hop),
+    };
+    let (host, port) = if let Some(rest) = address.strip_prefix('[') {
+        // [IPv6]:port
+        let (host, rest) = rest.split_once(']')?;
+        match rest.strip_prefix(':') {
+            Some(port) => (host, Some(port.parse().ok()?)),
+            None if rest.is_empty() => (host, None),
+            None => return None,
+        }
+    } else {
+        match address.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port.parse().ok()?)),
+            _ => (address, None),
+        }
+    };
+    if host.is_empty() || user.as_deref() == Some("") {
+        return None;
+    }
+    Some(JumpHop { user, host: host.to_string(), port })
+}
+
+/// The host `hop` refers to: the host its alias was imported as (`names`)
+/// or a host of that name, as long as any user and port given agree, or
+/// else the first host with that address
+pub fn resolve_jump_hop(config: &Config, names: &HashMap<&str, String>, hop: &JumpHop) -> Option<String> {
+    let user_matches = |host: &HostConfig| hop.user.as_ref().is_none_or(|user| *user == host.username);
+    let by_alias = names.get(hop.host.as_str()).and_then(|name| config.get_host(name)).or_else(|| config.get_host(&hop.host));
+    if let Some(host) = by_alias
+        && user_matches(host)
+        && hop.port.is_none_or(|port| port == host.port)
+    {
+        return Some(host.name.clone());
+    }
+    let mut hosts: Vec<&HostConfig> = config
+        .hosts
+        .values()
+        .filter(|host| host.hostname == hop.host && host.port == hop.port.unwrap_or(22) && user_matches(host))
+        .collect();
+    hosts.sort_by(|a, b| a.name.cmp(&b.name));
+    hosts.first().map(|host| host.name.clone())
+}
+
+/// Set the jump host of every imported host (`names` maps aliases to the
+/// names they were imported as) from its `ProxyJump`. Jump hosts may be
+/// defined after the hosts behind them, so this runs once everything is
+/// in. Returns the hosts that have to be left out again, with the reason:
+/// connecting to them directly would bypass their jump hosts. Leaving one
+/// out can strand others that go through it, so those are returned too.
+pub fn link_jump_hosts(config: &mut Config, hosts: &[SshHost], names: &HashMap<&str, String>) -> Vec<(String, String)> {
+    let mut left_out = Vec::new();
+    let mut chains = HashMap::new();
+    for ssh_host in hosts {
+        let (Some(name), Some(jump)) = (names.get(ssh_host.alias.as_str()), &ssh_host.proxy_jump) else {
+            continue;
+        };
+        if jump.eq_ignore_ascii_case("none") {
+            continue;
+        }
+        let hops = parse_proxy_jump(jump)
+            .and_then(|hops| hops.iter().map(|hop| resolve_jump_hop(config, names, hop)).collect::<Option<Vec<_>>>());
+        let Some(hops) = hops else {
+            left_out.push((name.clone(), format!("it is reached through {} (ProxyJump), which does not name configured hosts", jump)));
+            continue;
+        };
+        if let Some(host) = config.hosts.get_mut(name) {
+            host.via = hops.last().cloned();
+        }
+        chains.insert(name.as_str(), hops);
+    }
+
+    let mut remaining: Vec<&String> = names.values().filter(|name| !left_out.iter().any(|(out, _)| out == *name)).collect();
+    remaining.sort();
+    let mut removed: Vec<String> = left_out.iter().map(|(name, _)| name.clone()).collect();
+    loop {
+        for name in &removed {
+            config.remove_host(name);
+        }
+        removed.clear();
+        for name in &remaining {
+            let reason = match config.jump_chain(name) {
+                Err(e) => e.to_string(),
+                Ok(chain) => {
+                    let Some(hops) = chains.get(name.as_str()) else {
+                        continue;
+                    };
+                    let chain: Vec<&str> = chain.iter().map(|host| host.name.as_str()).collect();
+                    if chain.ends_with(&hops.iter().map(String::as_str).collect::<Vec<_>>()) {
+                        continue;
+                    }
+                    // ssh reaches each hop through the previous one only for this host
+                    format!("its ProxyJump goes through {}, but '{}' is not reached that way", hops.join(", "), hops[hops.len() - 1])
+                }
+            };
+            removed.push(name.to_string());
+            left_out.push((name.to_string(), reason));
+        }
+        if removed.is_empty() {
+            return left_out;
+        }
+        remaining.retain(|name| !removed.contains(name));
+    }
+}
+
+/// A `Host` (or `Match`, with no patterns) line and the options below it
+struct Block {
+    patterns: Option<Vec<String>>,
+    options: Vec<(String, String)>,
+}
+
+/// Parse the config at `path`. Relative `Include` paths are looked up in
+/// `ssh_dir`, and `exclude` (the file `ssh-config export` writes) is never
+/// read, so exported hosts are not imported back.
+pub fn parse_file(path: &Path, ssh_dir: &Path, exclude: Option<&Path>) -> Result<Vec<SshHost>, Box<dyn std::error::Error>> {
+    let content = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut blocks = vec![Block { patterns: Some(vec!["*".to_string()]), options: Vec::new() }];
+    let exclude = exclude.and_then(|path| path.canonicalize().ok());
+    read_lines(&content, path, ssh_dir, exclude.as_deref(), 0, &mut blocks)?;
+    Ok(resolve(&blocks))
+}
+
+fn read_lines(
+    content: &str,
+    path: &Path,
+    ssh_dir: &Path,
+    exclude: Option<&Path>,
+    depth: usize,
+    blocks: &mut Vec<Block>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (index, line) in content.lines().enumerate() {
+        let Some((keyword, args)) = split_line(line) else {
+            continue;
+        };
+        let location = || format!("{}:{}", path.display(), index + 1);
+
+        match keyword.as_str() {
+            "host" => blocks.push(Block { patterns: Some(args), options: Vec::new() }),
+            "match" => blocks.push(Block { patterns: None, options: Vec::new() }),
+            "include" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(format!("{}: Include nested too deeply", location()).into());
+                }
+                let context = blocks.last().map(|block| block.patterns.clone()).unwrap_or_default();
+                let before = blocks.len();
+                for pattern in &args {
+                    for file in expand_include(pattern, ssh_dir) {
+                        if exclude.is_some_and(|exclude| file.canonicalize().is_ok_and(|file| file == exclude)) {
+                            continue;
+                        }
+                        let included = fs::read_to_string(&file).map_err(|e| format!("Failed to read {}: {}", file.display(), e))?;
+                        read_lines(&included, &file, ssh_dir, exclude, depth + 1, blocks)?;
+                    }
+                }
+                // Host lines in an included file end at its end
+                if blocks.len() != before {
+                    blocks.push(Block { patterns: context, options: Vec::new() });
+                }
+            }
+            "hostname" | "port" | "user" | "identityfile" | "proxyjump" => {
+                let Some(value) = args.into_iter().next() else {
+                    return Err(format!("{}: {} needs a value", location(), keyword).into());
+                };
+                if keyword == "port" && value.parse::<u16>().is_err() {
+                    return Err(format!("{}: invalid port '{}'", location(), value).into());
+                }
+                if let Some(block) = blocks.last_mut() {
+                    block.options.push((keyword, value));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Split a line into its lowercased keyword and arguments. Arguments may be
+/// double-quoted, and the keyword may be followed by `=`.
+fn split_line(line: &str) -> Option<(String, Vec<String>)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=').unwrap_or(line.len());
+    let keyword = line[..end].to_ascii_lowercase();
+    let rest = line[end..].trim_start();
+    let rest = rest.strip_prefix('=').unwrap_or(rest);
+
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    let mut in_word = false;
+    for c in rest.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !quoted => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        args.push(current);
+    }
+    Some((keyword, args))
+}
+
+/// Every alias from a `Host` line with the options that apply to it
+fn resolve(blocks: &[Block]) -> Vec<SshHost> {
+    let mut aliases: Vec<&str> = Vec::new();
+    for patterns in blocks.iter().filter_map(|block| block.patterns.as_ref()) {
+        for pattern in patterns {
+            let literal = !pattern.contains(['*', '?', '!']);
+            if literal && !aliases.contains(&pattern.as_str()) {
+                aliases.push(pattern);
+            }
+        }
+    }
+
+    aliases
+        .into_iter()
+        .map(|alias| {
+            let mut host = SshHost { alias: alias.to_string(), ..Default::default() };
+            let applicable = blocks.iter().filter(|block| block.patterns.as_ref().is_some_and(|patterns| host_matches(patterns, alias)));
+            for (keyword, value) in applicable.flat_map(|block| &block.options) {
+                let slot = match keyword.as_str() {
+                    "hostname" => {
+                        // %h in HostName stands for the alias
+                        host.hostname = host.hostname.take().or_else(|| Some(value.replace("%h", alias)));
+                        continue;
+                    }
+                    "user" => &mut host.user,
+                    "identityfile" => &mut host.identity_file,
+                    "proxyjump" => &mut host.proxy_jump,
+                    "port" => {
+                        host.port = host.port.or(value.parse().ok());
+                        continue;
+                    }
+                    _ => continue,
+                };
+                if slot.is_none() {
+                    *slot = Some(value.clone());
+                }
+            }
+            host
+        })
+        .collect()
+}
+
+/// Whether a `Host` line applies to `alias`: some pattern matches and no
+/// negated one does
+fn host_matches(patterns: &[String], alias: &str) -> bool {
+    let alias = alias.to_ascii_lowercase();
+    let mut matched = false;
+    for pattern in patterns {
+        let pattern = pattern.to_ascii_lowercase();
+        match pattern.strip_prefix('!') {
+            Some(negated) if wildcard_match(negated, &alias) => return false,
+            Some(_) => {}
+            None => matched |= wildcard_match(&pattern, &alias),
+        }
+    }
+    matched
+}
+
+/// Match `text` against a pattern where `*` is any run of characters and
+/// `?` any one character
+pub(crate) fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The files an `Include` argument names, in sorted order like ssh's glob
+fn expand_include(pattern: &str, ssh_dir: &Path) -> Vec<PathBuf> {
+    let path = match (pattern.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest),
+        _ => ssh_dir.join(pattern),
+    };
+
+    let mut candidates = vec![PathBuf::new()];
+    for component in path.components() {
+        let name = component.as_os_str().to_string_lossy();
+        let wildcard = matches!(component, Component::Normal(_)) && name.contains(['*', '?']);
+        if !wildcard {
+            candidates.iter_mut().for_each(|candidate| candidate.push(component));
+            continue;
+        }
+
+        let mut matches = Vec::new();
+        for candidate in &candidates {
+            let Ok(entries) = fs::read_dir(candidate) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let entry_name = entry.file_name().to_string_lossy().into_owned();
+                // Like a shell glob, `*` does not match hidden files
+                if entry_name.starts_with('.') && !name.starts_with('.') {
+                    continue;
+                }
+                if wildcard_match(&name, &entry_name) {
+                    matches.push(candidate.join(entry_name));
+                }
+            }
+        }
+        candidates = matches;
+    }
+
+    candidates.retain(|candidate| candidate.is_file());
+    candidates.sort();
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn parse(files: &[(&str, &str)]) -> Vec<SshHost> {
+        let dir = TempDir::new().unwrap();
+        for (name, content) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        parse_file(&dir.path().join(files[0].0), dir.path(), None).unwrap()
+    }
+
+    #[test]
+    fn test_wildcard_defaults_fill_in_unset_options() {
+        let hosts = parse(&[(
+            "config",
+            "# Work\n\
+             Host prod staging\n    \
+                 HostName %h.example.com\n    \
+                 User deploy\n\
+             Host staging\n    \
+                 HostName 10.0.0.9\n    \
+                 Port 2222\n\
+             Host *.internal !db.internal\n    \
+                 User ops\n\
+             Host *\n    \
+                 User = fallback\n    \
+                 IdentityFile \"~/.ssh/id work\"\n",
+        )]);
+
+        assert_eq!(hosts.iter().map(|host| host.alias.as_str()).collect::<Vec<_>>(), ["prod", "staging"]);
+        assert_eq!(
+            hosts[0],
+            SshHost {
+                alias: "prod".to_string(),
+                hostname: Some("prod.example.com".to_string()),
+                port: None,
+                user: Some("deploy".to_string()),
+                identity_file: Some("~/.ssh/id work".to_string()),
+                proxy_jump: None,
+            }
+        );
+        // The first HostName wins, the Port comes from the later block
+        assert_eq!(hosts[1].hostname.as_deref(), Some("staging.example.com"));
+        assert_eq!(hosts[1].port, Some(2222));
+    }
+
+    #[test]
+    fn test_include_with_glob_and_restored_context() {
+        let hosts = parse(&[
+            ("config", "Host jump\n    Include conf.d/*.conf\n    User admin\nHost *\n    Port 60022\n"),
+            ("conf.d/a.conf", "HostName jump.example.com\nHost app\n    ProxyJump jump\n"),
+            ("conf.d/b.conf", "Host db\n    HostName 10.0.0.5\n"),
+            ("conf.d/.hidden.conf", "Host hidden\n"),
+        ]);
+
+        assert_eq!(hosts.iter().map(|host| host.alias.as_str()).collect::<Vec<_>>(), ["jump", "app", "db"]);
+        assert_eq!(hosts[0].hostname.as_deref(), Some("jump.example.com"));
+        assert_eq!(hosts[0].user.as_deref(), Some("admin"));
+        assert_eq!(hosts[1].user, None);
+        assert_eq!(hosts[1].proxy_jump.as_deref(), Some("jump"));
+        assert_eq!(hosts[2].port, Some(60022));
+    }
+
+    #[test]
+    fn test_match_blocks_and_exclude_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        let exported = dir.path().join("exported");
+        fs::write(&exported, "Host prod\n").unwrap();
+        fs::write(
+            dir.path().join("config"),
+            format!("Include {}\nMatch host web\n    User root\nHost web\n", exported.display()),
+        )
+        .unwrap();
+
+        let hosts = parse_file(&dir.path().join("config"), dir.path(), Some(&exported)).unwrap();
+        assert_eq!(hosts, [SshHost { alias: "web".to_string(), ..Default::default() }]);
+    }
+
+    #[test]
+    fn test_invalid_port_is_reported_with_location() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("config");
+        fs::write(&path, "Host web\n    Port ssh\n").unwrap();
+
+        let error = parse_file(&path, dir.path(), None).unwrap_err();
+        assert!(error.to_string().ends_with("config:2: invalid port 'ssh'"));
+    }
+
+    #[test]
+    fn test_wildcard_match() {
+        assert!(wildcard_match("*.internal", "db.internal"));
+        assert!(wildcard_match("web-?", "web-1"));
+        assert!(wildcard_match("*a*b", "xaxxab"));
+        assert!(!wildcard_match("web-?", "web-10"));
+        assert!(!wildcard_match("*.internal", "internal"));
+    }
+
+    #[test]
+    fn test_parse_proxy_jump() {
+        let hop = |user: Option<&str>, host: &str, port| JumpHop { user: user.map(String::from), host: host.to_string(), port };
+
+        assert_eq!(parse_proxy_jump("jump"), Some(vec![hop(None, "jump", None)]));
+        assert_eq!(
+            parse_proxy_jump("admin@jump.example.com:2222, ssh://ops@[fe80::1]:22,db"),
+            Some(vec![
+                hop(Some("admin"), "jump.example.com", Some(2222)),
+                hop(Some("ops"), "fe80::1", Some(22)),
+                hop(None, "db", None),
+            ])
+        );
+        assert_eq!(parse_proxy_jump("fe80::1"), Some(vec![hop(None, "fe80::1", None)]));
+        assert_eq!(parse_proxy_jump("jump:ssh"), None);
+        assert_eq!(parse_proxy_jump("jump,,db"), None);
+        assert_eq!(parse_proxy_jump("@jump"), None);
+    }
+
+    #[test]
+    fn test_link_jump_hosts() {
+        let mut config = Config::new();
+        config.add_host(HostConfig { name: "gw".to_string(), hostname: "gw.example.com".to_string(), port: 2222, username: "ops".to_string(), ..Default::default() });
+        let hosts = parse(&[(
+            "config",
+            "Host app
+    ProxyJump ops@gw.example.com:2222,edge
+             Host edge
+    ProxyJump gw
+             Host db
+    ProxyJump app
+             Host web
+    ProxyJump root@gw
+             Host cache
+    ProxyJump edge,gw
+             Host api
+    ProxyJump web
+",
+        )]);
+        let mut names = HashMap::new();
+        for host in &hosts {
+            names.insert(host.alias.as_str(), host.alias.clone());
+            config.add_host(host.to_host_config("alice"));
+        }
+
+        let left_out = link_jump_hosts(&mut config, &hosts, &names);
+
+        // web names gw with another user, api goes through web, and cache
+        // has gw reached through edge
+        let left_out: Vec<&str> = left_out.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(left_out, ["web", "api", "cache"]);
+        let mut kept: Vec<&String> = config.hosts.keys().collect();
+        kept.sort();
+        assert_eq!(kept, ["app", "db", "edge", "gw"]);
+        let chain: Vec<&str> = config.jump_chain("db").unwrap().iter().map(|host| host.name.as_str()).collect();
+        assert_eq!(chain, ["gw", "edge", "app"]);
+    }
+
+    #[test]
+    fn test_to_host_config_uses_ssh_defaults() {
+        let host = SshHost { alias: "web".to_string(), ..Default::default() }.to_host_config("alice");
+
+        assert_eq!((host.hostname.as_str(), host.port, host.username.as_str()), ("web", 22, "alice"));
+    }
+}
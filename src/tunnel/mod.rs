@@ -0,0 +1,449 @@
+//! Background tunnels
+//!
+//! `tunnel --detach` starts ssh in its own process group and records it in
+//! `~/.ali-bastion/run/<id>.json`, next to `<id>.log` holding ssh's output.
+//! A record whose process group is gone, or whose pid now belongs to
+//! another process, is stale and removed the next time the records are
+//! read.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::process::Child;
+#[cfg(not(target_os = "linux"))]
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::Forward;
+use crate::utils::now;
+
+/// How often a starting tunnel is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a tunnel with only remote forwards must stay up to count as started
+const REMOTE_GRACE: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TunnelRecord {
+    pub id: u32,
+    pub host: String,
+    /// Process group leader: ssh, or sshpass running it
+    pub pid: u32,
+    pub forwards: Vec<Forward>,
+    /// Seconds since the Unix epoch
+    pub started: u64,
+    /// When the process group leader started, as the system reports it;
+    /// tells the tunnel apart from a later process that reuses its pid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub process_start: Option<String>,
+}
+
+impl TunnelRecord {
+    pub fn new(id: u32, host: &str, pid: u32, forwards: Vec<Forward>) -> Self {
+        TunnelRecord { id, host: host.to_string(), pid, forwards, started: now(), process_start: process_start(pid) }
+    }
+}
+
+pub fn get_run_dir() -> PathBuf {
+    let mut run_dir = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    run_dir.push(".ali-bastion");
+    run_dir.push("run");
+    run_dir
+}
+
+fn record_path(run_dir: &Path, id: u32) -> PathBuf {
+    run_dir.join(format!("{}.json", id))
+}
+
+pub fn log_path(run_dir: &Path, id: u32) -> PathBuf {
+    run_dir.join(format!("{}.log", id))
+}
+
+pub fn save(run_dir: &Path, record: &TunnelRecord) -> Result<(), Box<dyn std::error::Error>> {
+    fs::create_dir_all(run_dir)?;
+    fs::write(record_path(run_dir, record.id), serde_json::to_string_pretty(record)?)?;
+    Ok(())
+}
+
+/// Delete a tunnel's record and log
+pub fn remove(run_dir: &Path, id: u32) -> Result<(), Box<dyn std::error::Error>> {
+    for path in [record_path(run_dir, id), log_path(run_dir, id)] {
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Records of running tunnels sorted by id, after removing stale ones. The
+/// removed records are returned as well.
+pub fn load(run_dir: &Path) -> Result<(Vec<TunnelRecord>, Vec<TunnelRecord>), Box<dyn std::error::Error>> {
+    let mut running = Vec::new();
+    let mut stale = Vec::new();
+    let entries = match fs::read_dir(run_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((running, stale)),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        // Unreadable records are left alone rather than guessed at
+        let Ok(record) = serde_json::from_str::<TunnelRecord>(&fs::read_to_string(&path)?) else {
+            continue;
+        };
+        if is_running(&record) {
+            running.push(record);
+        } else {
+            remove(run_dir, record.id)?;
+            stale.push(record);
+        }
+    }
+
+    running.sort_by_key(|record| record.id);
+    stale.sort_by_key(|record| record.id);
+    Ok((running, stale))
+}
+
+/// Lowest id above all running tunnels
+pub fn next_id(records: &[TunnelRecord]) -> u32 {
+    records.iter().map(|record| record.id).max().unwrap_or(0) + 1
+}
+
+/// Claim the first free id from `next_id` on by creating its record file,
+/// so tunnels started at the same time never share an id. `load` skips the
+/// empty record until `save` fills it in.
+pub fn reserve_id(run_dir: &Path, records: &[TunnelRecord]) -> Result<u32, Box<dyn std::error::Error>> {
+    fs::create_dir_all(run_dir)?;
+    let mut id = next_id(records);
+    loop {
+        match fs::OpenOptions::new().write(true).create_new(true).open(record_path(run_dir, id)) {
+            Ok(_) => return Ok(id),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => id += 1,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// A running tunnel already holding a port one of `forwards` on `host` needs
+pub fn find_conflict<'a>(records: &'a [TunnelRecord], host: &str, forwards: &[Forward]) -> Option<(&'a TunnelRecord, &'a Forward)> {
+    records.iter().find_map(|record| {
+        record
+            .forwards
+            .iter()
+            .find(|open| forwards.iter().any(|forward| forward.conflicts_with(open, record.host == host)))
+            .map(|open| (record, open))
+    })
+}
+
+/// Table of running tunnels, one row per tunnel
+pub fn format_list(records: &[TunnelRecord], now: u64) -> String {
+    let width = records.iter().map(|record| record.host.len()).chain(["HOST".len()]).max().unwrap_or(0);
+    let mut list = format!("{:>3}  {:<width$}  {:>7}  {:>7}  FORWARDS\n", "ID", "HOST", "PID", "UPTIME");
+    for record in records {
+        let forwards: Vec<String> = record.forwards.iter().map(|forward| forward.to_string()).collect();
+        list.push_str(&format!(
+            "{:>3}  {:<width$}  {:>7}  {:>7}  {}\n",
+            record.id,
+            record.host,
+            record.pid,
+            format_uptime(now.saturating_sub(record.started)),
+            forwards.join(", ")
+        ));
+    }
+    list
+}
+
+fn format_uptime(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+        _ => format!("{}d{:02}h", secs / 86400, secs % 86400 / 3600),
+    }
+}
+
+/// Fail if the port of a local forward is already taken. A listener that is
+/// there before the tunnel starts would pass for it in `wait_until_ready`.
+pub fn check_ports_free(forwards: &[Forward]) -> Result<(), Box<dyn std::error::Error>> {
+    for forward in forwards.iter().filter(|forward| forward.listens_locally()) {
+        // ssh listens on loopback unless told otherwise
+        let address = match forward.bind_address.as_deref() {
+            None | Some("") | Some("localhost") => "127.0.0.1",
+            Some("*") => "0.0.0.0",
+            Some(address) => address.trim_start_matches('[').trim_end_matches(']'),
+        };
+        if let Err(e) = TcpListener::bind((address, forward.port)) {
+            return Err(format!("Cannot listen for {}: {}", forward, e).into());
+        }
+    }
+    Ok(())
+}
+
+/// Wait until every local forward accepts connections. Fails if the tunnel
+/// exits or is not up within `timeout`; the caller stops it in that case.
+/// The ports must have been free before, see `check_ports_free`.
+pub fn wait_until_ready(child: &mut Child, forwards: &[Forward], timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
+    let start = Instant::now();
+    let local: Vec<&Forward> = forwards.iter().filter(|forward| forward.listens_locally()).collect();
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(format!("ssh exited with {}", status).into());
+        }
+        let ready = if local.is_empty() {
+            start.elapsed() >= REMOTE_GRACE
+        } else {
+            local.iter().all(|forward| accepts_connections(forward))
+        };
+        if ready {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(format!("Tunnel was not up after {} seconds", timeout.as_secs()).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn accepts_connections(forward: &Forward) -> bool {
+    // A wildcard bind address is reachable through loopback
+    let address = match forward.bind_address.as_deref() {
+        None | Some("") | Some("*") | Some("0.0.0.0") => "127.0.0.1",
+        Some(address) => address.trim_start_matches('[').trim_end_matches(']'),
+    };
+    let Ok(addresses) = (address, forward.port).to_socket_addrs() else {
+        return false;
+    };
+    addresses.into_iter().any(|address| TcpStream::connect_timeout(&address, POLL_INTERVAL).is_ok())
+}
+
+/// Start time of process `pid` in clock ticks since boot, from
+/// `/proc/<pid>/stat`
+#[cfg(target_os = "linux")]
+pub fn process_start(pid: u32) -> Option<String> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name in parentheses may contain spaces; starttime is the
+    // 22nd field, the 20th after it
+    let (_, fields) = stat.rsplit_once(')')?;
+    fields.split_whitespace().nth(19).map(str::to_string)
+}
+
+/// Start time of process `pid` as `ps` prints it
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn process_start(pid: u32) -> Option<String> {
+    let output = Command::new("ps").args(["-o", "lstart=", "-p", &pid.to_string()]).stderr(Stdio::null()).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string()).filter(|start| !start.is_empty())
+}
+
+#[cfg(windows)]
+pub fn process_start(_pid: u32) -> Option<String> {
+    None
+}
+
+/// Whether the tunnel's process group still exists and is the one started
+/// for it
+#[cfg(unix)]
+pub fn is_running(record: &TunnelRecord) -> bool {
+    if !signal_group(record.pid, 0) {
+        return false;
+    }
+    // A group outliving its leader keeps the pid from being reused, so only
+    // a live leader can be an impostor
+    match (&record.process_start, process_start(record.pid)) {
+        (Some(recorded), Some(current)) => *recorded == current,
+        _ => true,
+    }
+}
+
+/// Stop the tunnel's process group, unless its pid now belongs to another
+/// process
+#[cfg(unix)]
+pub fn stop(record: &TunnelRecord) -> bool {
+    is_running(record) && stop_group(record.pid)
+}
+
+/// Stop the process group led by `pid`, a child of ours
+#[cfg(unix)]
+pub fn stop_group(pid: u32) -> bool {
+    signal_group(pid, libc::SIGTERM)
+}
+
+#[cfg(unix)]
+fn signal_group(pid: u32, signal: libc::c_int) -> bool {
+    // Zero or a negative pid_t would address our own group or every process
+    match libc::pid_t::try_from(pid) {
+        Ok(pid) if pid > 0 => unsafe { libc::kill(-pid, signal) == 0 },
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+pub fn is_running(record: &TunnelRecord) -> bool {
+    let pid = record.pid;
+    Command::new("tasklist")
+        .args(["/FI", &format!("PID eq {}", pid), "/NH"])
+        .stderr(Stdio::null())
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).split_whitespace().any(|word| word == pid.to_string()))
+        .unwrap_or(false)
+}
+
+#[cfg(windows)]
+pub fn stop(record: &TunnelRecord) -> bool {
+    stop_group(record.pid)
+}
+
+#[cfg(windows)]
+pub fn stop_group(pid: u32) -> bool {
+    Command::new("taskkill")
+        .args(["/PID", &pid.to_string(), "/T", "/F"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[cfg(unix)]
+    fn spawn_group(command: &str) -> Child {
+        use std::os::unix::process::CommandExt;
+        Command::new("sh").arg("-c").arg(command).process_group(0).spawn().unwrap()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_removes_stale_records() {
+        let dir = TempDir::new().unwrap();
+        let forwards = vec![Forward::parse("db=L:15432:rds.internal:3306").unwrap()];
+
+        let mut running = spawn_group("exec sleep 30");
+        let mut exited = spawn_group("true");
+        exited.wait().unwrap();
+        save(dir.path(), &TunnelRecord::new(1, "prod", running.id(), forwards.clone())).unwrap();
+        save(dir.path(), &TunnelRecord::new(2, "prod", exited.id(), forwards)).unwrap();
+        fs::write(log_path(dir.path(), 2), "ssh output").unwrap();
+
+        let (live, stale) = load(dir.path()).unwrap();
+        assert_eq!(live.iter().map(|record| record.id).collect::<Vec<_>>(), [1]);
+        assert_eq!(stale.iter().map(|record| record.id).collect::<Vec<_>>(), [2]);
+        assert!(!log_path(dir.path(), 2).exists());
+        assert_eq!(next_id(&live), 2);
+
+        assert!(stop(&live[0]));
+        running.wait().unwrap();
+        assert!(!is_running(&live[0]));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reused_pid_is_not_signalled() {
+        let mut unrelated = spawn_group("exec sleep 30");
+        let mut record = TunnelRecord::new(1, "prod", unrelated.id(), Vec::new());
+        assert!(record.process_start.is_some());
+        assert!(is_running(&record));
+
+        // As if the tunnel had died and its pid gone to `unrelated`
+        record.process_start = Some("0".to_string());
+        assert!(!is_running(&record));
+        assert!(!stop(&record));
+        assert!(unrelated.try_wait().unwrap().is_none());
+
+        stop_group(unrelated.id());
+        unrelated.wait().unwrap();
+    }
+
+    #[test]
+    fn test_reserve_id() {
+        let dir = TempDir::new().unwrap();
+        let running = [TunnelRecord::new(2, "prod", 1, Vec::new())];
+
+        let first = reserve_id(dir.path(), &running).unwrap();
+        let second = reserve_id(dir.path(), &running).unwrap();
+        assert_eq!((first, second), (3, 4));
+        assert_eq!(load(dir.path()).unwrap(), (Vec::new(), Vec::new()));
+
+        remove(dir.path(), first).unwrap();
+        assert_eq!(reserve_id(dir.path(), &running).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_check_ports_free() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let forwards = [
+            Forward::parse("web=R:8080:localhost:80").unwrap(),
+            Forward::parse(&format!("db=L:{}:rds.internal:3306", port)).unwrap(),
+        ];
+
+        let error = check_ports_free(&forwards).unwrap_err();
+        assert!(error.to_string().starts_with(&format!("Cannot listen for db: localhost:{} -> rds.internal:3306: ", port)));
+
+        drop(listener);
+        assert!(check_ports_free(&forwards).is_ok());
+    }
+
+    #[test]
+    fn test_find_conflict() {
+        let records = [TunnelRecord::new(
+            1,
+            "prod",
+            1,
+            vec![
+                Forward::parse("db=L:15432:rds.internal:3306").unwrap(),
+                Forward::parse("web=R:8080:localhost:80").unwrap(),
+            ],
+        )];
+
+        let same_port = [Forward::parse("other=D:15432").unwrap()];
+        let (record, open) = find_conflict(&records, "staging", &same_port).unwrap();
+        assert_eq!((record.id, open.name.as_str()), (1, "db"));
+
+        let remote = [Forward::parse("web=R:8080:localhost:80").unwrap()];
+        assert!(find_conflict(&records, "staging", &remote).is_none());
+        assert!(find_conflict(&records, "prod", &remote).is_some());
+    }
+
+    #[test]
+    fn test_format_list() {
+        let mut record = TunnelRecord::new(2, "prod", 4242, vec![Forward::parse("db=L:15432:rds.internal:3306").unwrap()]);
+        record.started = 1_000;
+
+        assert_eq!(
+            format_list(&[record], 1_000 + 3_900),
+            " ID  HOST      PID   UPTIME  FORWARDS\n  \
+             2  prod     4242    1h05m  db: localhost:15432 -> rds.internal:3306\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_until_ready() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let forwards = [Forward::parse(&format!("db=L:{}:rds.internal:3306", port)).unwrap()];
+
+        let mut child = spawn_group("exec sleep 30");
+        assert!(wait_until_ready(&mut child, &forwards, Duration::from_secs(5)).is_ok());
+        stop_group(child.id());
+        child.wait().unwrap();
+
+        drop(listener);
+        let mut failing = spawn_group("sleep 0.5; exit 255");
+        let error = wait_until_ready(&mut failing, &forwards, Duration::from_secs(5)).unwrap_err();
+        assert!(error.to_string().contains("255"));
+    }
+}
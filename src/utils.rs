@@ -1 +1,12 @@
-pub mod encryption;
\ No newline at end of file
+pub mod encryption;
+pub mod keystore;
+pub mod password_command;
+pub mod secret;
+pub mod totp;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
\ No newline at end of file
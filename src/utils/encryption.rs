@@ -1,78 +1,225 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
 use base64::{engine::general_purpose, Engine as _};
 
-// Simple XOR encryption for demonstration purposes
-// DO NOT use this in production - it's not secure
-pub fn encrypt_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
-    let key = get_or_create_key();
-    let mut result = Vec::new();
-    
-    for (i, byte) in password.bytes().enumerate() {
-        result.push(byte ^ key[i % key.len()]);
+use super::keystore;
+use super::secret::Secret;
+
+/// 256-bit key used to encrypt stored passwords
+pub type Key = [u8; 32];
+
+/// Prefix marking values produced by the current (AES-256-GCM) scheme.
+/// Values without a known prefix are treated as legacy XOR ciphertext.
+const CIPHERTEXT_PREFIX: &str = "v2:";
+
+/// AES-GCM nonce length in bytes (96 bits)
+const NONCE_LEN: usize = 12;
+
+/// Encrypt a password with AES-256-GCM using a fresh random nonce.
+///
+/// The result has the form `v2:<base64(nonce || ciphertext || tag)>`.
+pub fn encrypt_password(password: &str, key: &Key) -> Result<String, Box<dyn std::error::Error>> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+
+    let ciphertext = cipher
+        .encrypt(&nonce, password.as_bytes())
+        .map_err(|_| "Failed to encrypt password")?;
+
+    let mut data = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(&ciphertext);
+
+    Ok(format!("{}{}", CIPHERTEXT_PREFIX, general_purpose::STANDARD.encode(&data)))
+}
+
+/// Decrypt a stored password, accepting both the current `v2:` format and
+/// legacy XOR-obscured values written by older versions.
+pub fn decrypt_password(encrypted_password: &str, key: &Key) -> Result<Secret, Box<dyn std::error::Error>> {
+    match encrypted_password.strip_prefix(CIPHERTEXT_PREFIX) {
+        Some(encoded) => decrypt_v2(encoded, key),
+        None => decrypt_legacy(encrypted_password),
     }
-    
-    Ok(general_purpose::STANDARD.encode(&result))
 }
 
-pub fn decrypt_password(encrypted_password: &str) -> Result<String, Box<dyn std::error::Error>> {
+/// Encrypt a secret, keeping the ciphertext wrapped as well
+pub fn encrypt_secret(secret: &Secret, key: &Key) -> Result<Secret, Box<dyn std::error::Error>> {
+    encrypt_password(secret.expose(), key).map(Secret::new)
+}
+
+/// Decrypt a secret stored by `encrypt_secret`
+pub fn decrypt_secret(encrypted: &Secret, key: &Key) -> Result<Secret, Box<dyn std::error::Error>> {
+    decrypt_password(encrypted.expose(), key)
+}
+
+/// Returns true if the value was written by the legacy XOR scheme and
+/// should be re-encrypted with `encrypt_password`.
+pub fn is_legacy_ciphertext(encrypted_password: &str) -> bool {
+    !encrypted_password.starts_with(CIPHERTEXT_PREFIX)
+}
+
+fn decrypt_v2(encoded: &str, key: &Key) -> Result<Secret, Box<dyn std::error::Error>> {
+    let data = general_purpose::STANDARD.decode(encoded)?;
+    if data.len() < NONCE_LEN {
+        return Err("Encrypted password is truncated".into());
+    }
+
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(key.into());
+    let plaintext = cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| "Failed to decrypt password: authentication failed")?;
+
+    Ok(Secret::new(String::from_utf8(plaintext)?))
+}
+
+fn decrypt_legacy(encrypted_password: &str) -> Result<Secret, Box<dyn std::error::Error>> {
     let data = general_purpose::STANDARD.decode(encrypted_password)?;
-    let key = get_or_create_key();
     let mut result = Vec::new();
     
     for (i, byte) in data.iter().enumerate() {
-        result.push(byte ^ key[i % key.len()]);
+        result.push(byte ^ LEGACY_XOR_KEY[i % LEGACY_XOR_KEY.len()]);
     }
     
-    Ok(String::from_utf8(result)?)
+    Ok(Secret::new(String::from_utf8(result)?))
+}
+
+/// Fixed key used by the legacy XOR scheme. Only kept so that old
+/// configuration files can still be read and migrated.
+const LEGACY_XOR_KEY: [u8; 16] = [
+    0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
+];
+
+/// Obtain the vault key, asking a running agent first and prompting for
+/// the master passphrase otherwise.
+///
+/// On first use a random key is generated and stored in the key file,
+/// protected by a newly chosen passphrase.
+pub fn get_or_create_key() -> Result<Key, Box<dyn std::error::Error>> {
+    if let Some(key) = key_from_agent() {
+        return Ok(key);
+    }
+
+    let key_path = keystore::get_key_path();
+
+    if !key_path.exists() {
+        let passphrase = keystore::prompt_new_passphrase()?;
+        return keystore::create_key_file(&key_path, &passphrase, keystore::KdfParams::default());
+    }
+
+    let passphrase = keystore::prompt_passphrase("Master passphrase: ")?;
+    let key = keystore::unlock_key_file(&key_path, &passphrase)?;
+    offer_key_to_agent(&key);
+    Ok(key)
+}
+
+/// The key held by a running, unlocked agent
+#[cfg(unix)]
+pub fn key_from_agent() -> Option<Key> {
+    match crate::agent::request_key(&crate::agent::get_socket_path()) {
+        Ok(crate::agent::AgentStatus::Unlocked(key)) => Some(key),
+        _ => None,
+    }
 }
 
-fn get_or_create_key() -> [u8; 16] {
-    // In a real implementation, you would securely store and retrieve the key
-    // For this example, we'll use a fixed key
-    // DO NOT use this in production - implement proper key management
-    [
-        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
-        0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F,
-    ]
+/// The key held by a running, unlocked agent
+#[cfg(not(unix))]
+pub fn key_from_agent() -> Option<Key> {
+    None
 }
 
+/// Hand the key to a running agent after the passphrase was typed. An agent
+/// the user locked refuses it; nothing happens if no agent is running.
+#[cfg(unix)]
+pub fn offer_key_to_agent(key: &Key) {
+    if let Err(e) = crate::agent::store_key(&crate::agent::get_socket_path(), key) {
+        eprintln!("Warning: Failed to hand the key to the agent: {}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn offer_key_to_agent(_key: &Key) {}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const TEST_KEY: Key = [0x42; 32];
+
+    fn encrypt_legacy(password: &str) -> String {
+        let data: Vec<u8> = password
+            .bytes()
+            .enumerate()
+            .map(|(i, byte)| byte ^ LEGACY_XOR_KEY[i % LEGACY_XOR_KEY.len()])
+            .collect();
+        general_purpose::STANDARD.encode(data)
+    }
+
     #[test]
     fn test_encrypt_decrypt() {
         let original_password = "my_secret_password";
         
-        let encrypted = encrypt_password(original_password).expect("Encryption should succeed");
-        let decrypted = decrypt_password(&encrypted).expect("Decryption should succeed");
+        let encrypted = encrypt_password(original_password, &TEST_KEY).expect("Encryption should succeed");
+        let decrypted = decrypt_password(&encrypted, &TEST_KEY).expect("Decryption should succeed");
         
-        assert_eq!(original_password, decrypted);
+        assert_eq!(original_password, decrypted.expose());
     }
 
     #[test]
     fn test_encrypt_different_output() {
         let password = "test_password";
         
-        let encrypted1 = encrypt_password(password).expect("Encryption should succeed");
-        let encrypted2 = encrypt_password(password).expect("Encryption should succeed");
+        let encrypted1 = encrypt_password(password, &TEST_KEY).expect("Encryption should succeed");
+        let encrypted2 = encrypt_password(password, &TEST_KEY).expect("Encryption should succeed");
         
-        // Same input should produce same output with the same key
-        assert_eq!(encrypted1, encrypted2);
+        // A fresh nonce is used for every value
+        assert_ne!(encrypted1, encrypted2);
+        assert!(encrypted1.starts_with(CIPHERTEXT_PREFIX));
+        assert!(!is_legacy_ciphertext(&encrypted1));
     }
 
     #[test]
     fn test_encrypt_empty_password() {
         let password = "";
         
-        let encrypted = encrypt_password(password).expect("Encryption should succeed");
-        let decrypted = decrypt_password(&encrypted).expect("Decryption should succeed");
+        let encrypted = encrypt_password(password, &TEST_KEY).expect("Encryption should succeed");
+        let decrypted = decrypt_password(&encrypted, &TEST_KEY).expect("Decryption should succeed");
         
-        assert_eq!(password, decrypted);
+        assert_eq!(password, decrypted.expose());
     }
 
     #[test]
     fn test_decrypt_invalid_base64() {
-        let result = decrypt_password("invalid_base64!");
+        let result = decrypt_password("invalid_base64!", &TEST_KEY);
         assert!(result.is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decrypt_tampered_ciphertext() {
+        let encrypted = encrypt_password("my_secret_password", &TEST_KEY).expect("Encryption should succeed");
+        let mut data = general_purpose::STANDARD
+            .decode(&encrypted[CIPHERTEXT_PREFIX.len()..])
+            .unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 0x01;
+        let tampered = format!("{}{}", CIPHERTEXT_PREFIX, general_purpose::STANDARD.encode(data));
+
+        assert!(decrypt_password(&tampered, &TEST_KEY).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_with_wrong_key() {
+        let encrypted = encrypt_password("my_secret_password", &TEST_KEY).expect("Encryption should succeed");
+
+        assert!(decrypt_password(&encrypted, &[0x24; 32]).is_err());
+    }
+
+    #[test]
+    fn test_decrypt_legacy_ciphertext() {
+        let legacy = encrypt_legacy("old_password");
+
+        assert!(is_legacy_ciphertext(&legacy));
+        assert_eq!(decrypt_password(&legacy, &TEST_KEY).unwrap().expose(), "old_password");
+    }
+}
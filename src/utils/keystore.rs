@@ -0,0 +1,283 @@
+//! Key file and master passphrase handling for the password vault
+//!
+//! Every installation has its own random vault key. It is stored in
+//! `~/.ali-bastion/key`, wrapped (AES-256-GCM) under a key derived from the
+//! user's master passphrase with Argon2id. The salt and cost parameters are
+//! stored next to the wrapped key; a wrong passphrase fails authentication
+//! when unwrapping, before any stored password is touched.
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose, Engine as _};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::encryption::{decrypt_password, encrypt_password, Key};
+
+const SALT_LEN: usize = 16;
+
+/// Argon2id cost parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// Memory cost in KiB
+    pub m_cost: u32,
+    /// Number of iterations
+    pub t_cost: u32,
+    /// Degree of parallelism
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        // OWASP recommended minimum for Argon2id
+        KdfParams {
+            m_cost: 19 * 1024,
+            t_cost: 2,
+            p_cost: 1,
+        }
+    }
+}
+
+/// On-disk contents of the key file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeyFile {
+    pub kdf: String,
+    pub salt: String,
+    #[serde(flatten)]
+    pub params: KdfParams,
+    /// Vault key encrypted under the passphrase-derived key
+    pub wrapped_key: String,
+}
+
+pub fn get_key_path() -> PathBuf {
+    let mut key_path = dirs::home_dir().unwrap_or_else(|| PathBuf::from("."));
+    key_path.push(".ali-bastion");
+    key_path.push("key");
+    key_path
+}
+
+/// Derive the vault key from a passphrase with Argon2id
+pub fn derive_key(passphrase: &str, salt: &[u8], params: KdfParams) -> Result<Key, Box<dyn std::error::Error>> {
+    let params = Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32))
+        .map_err(|e| format!("Invalid key derivation parameters: {}", e))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+
+    let mut key = [0u8; 32];
+    argon2
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {}", e))?;
+    Ok(key)
+}
+
+/// Generate a new random vault key
+pub fn generate_key() -> Key {
+    let mut key = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Create a new key file holding a freshly generated vault key protected by
+/// `passphrase`, and return the vault key
+pub fn create_key_file(path: &Path, passphrase: &str, params: KdfParams) -> Result<Key, Box<dyn std::error::Error>> {
+    let key = generate_key();
+    write_key_file(path, passphrase, &key, params)?;
+    Ok(key)
+}
+
+/// Wrap `key` under `passphrase` and write it to `path` with owner-only
+/// permissions, replacing any existing file
+pub fn write_key_file(path: &Path, passphrase: &str, key: &Key, params: KdfParams) -> Result<(), Box<dyn std::error::Error>> {
+    let mut salt = [0u8; SALT_LEN];
+    rand::rngs::OsRng.fill_bytes(&mut salt);
+
+    let wrapping_key = derive_key(passphrase, &salt, params)?;
+    let key_file = KeyFile {
+        kdf: "argon2id".to_string(),
+        salt: general_purpose::STANDARD.encode(salt),
+        params,
+        wrapped_key: encrypt_password(&general_purpose::STANDARD.encode(key), &wrapping_key)?,
+    };
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so an existing key is never left half-written
+    let tmp_path = path.with_extension("tmp");
+    let mut file = create_private_file(&tmp_path)?;
+    file.write_all(serde_json::to_string_pretty(&key_file)?.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Wrap `key` under `passphrase` with the cost parameters of the key file
+/// at `current` and write it to `path`, so rekeying keeps tuned parameters
+pub fn write_rekeyed_key_file(current: &Path, path: &Path, passphrase: &str, key: &Key) -> Result<(), Box<dyn std::error::Error>> {
+    let key_file: KeyFile = serde_json::from_str(&fs::read_to_string(current)?)?;
+    write_key_file(path, passphrase, key, key_file.params)
+}
+
+/// Unwrap the vault key stored in the key file, failing if the passphrase
+/// does not match or the file is accessible by other users
+pub fn unlock_key_file(path: &Path, passphrase: &str) -> Result<Key, Box<dyn std::error::Error>> {
+    check_permissions(path)?;
+
+    let content = fs::read_to_string(path)?;
+    let key_file: KeyFile = serde_json::from_str(&content)?;
+
+    if key_file.kdf != "argon2id" {
+        return Err(format!("Unsupported key derivation function '{}'", key_file.kdf).into());
+    }
+
+    let salt = general_purpose::STANDARD.decode(&key_file.salt)?;
+    let wrapping_key = derive_key(passphrase, &salt, key_file.params)?;
+
+    let encoded = decrypt_password(&key_file.wrapped_key, &wrapping_key)
+        .map_err(|_| "Incorrect master passphrase")?;
+    let key: Key = general_purpose::STANDARD
+        .decode(encoded.expose())?
+        .try_into()
+        .map_err(|_| "Key file contains a key of the wrong length")?;
+    Ok(key)
+}
+
+#[cfg(unix)]
+pub(crate) fn create_private_file(path: &Path) -> Result<fs::File, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    // Remove leftovers so the mode below applies to a brand new file
+    let _ = fs::remove_file(path);
+    Ok(fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn create_private_file(path: &Path) -> Result<fs::File, Box<dyn std::error::Error>> {
+    Ok(fs::File::create(path)?)
+}
+
+/// Refuse key files that can be read or modified by the group or others
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Err(format!(
+            "Key file {} is accessible by other users (mode {:o}). Run 'chmod 600 {}' and try again.",
+            path.display(), mode, path.display()
+        ).into());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Ok(())
+}
+
+/// Read a passphrase from the terminal without echo
+pub fn prompt_passphrase(prompt: &str) -> Result<String, Box<dyn std::error::Error>> {
+    Ok(rpassword::prompt_password(prompt)?)
+}
+
+/// Ask for a new master passphrase twice and make sure both entries match
+pub fn prompt_new_passphrase() -> Result<String, Box<dyn std::error::Error>> {
+    eprintln!("No master passphrase is set up yet. Choose one to protect stored passwords.");
+    let passphrase = prompt_passphrase("New master passphrase: ")?;
+    if passphrase.is_empty() {
+        return Err("Master passphrase must not be empty".into());
+    }
+
+    let confirmation = prompt_passphrase("Confirm master passphrase: ")?;
+    if passphrase != confirmation {
+        return Err("Passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keep the tests fast; the cost parameters are not under test here
+    const TEST_PARAMS: KdfParams = KdfParams { m_cost: 256, t_cost: 1, p_cost: 1 };
+
+    #[test]
+    fn test_create_and_unlock_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        let created = create_key_file(&path, "correct horse", TEST_PARAMS).unwrap();
+        let unlocked = unlock_key_file(&path, "correct horse").unwrap();
+
+        assert_eq!(created, unlocked);
+    }
+
+    #[test]
+    fn test_unlock_with_wrong_passphrase() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        create_key_file(&path, "correct horse", TEST_PARAMS).unwrap();
+        let result = unlock_key_file(&path, "battery staple");
+
+        assert_eq!(result.unwrap_err().to_string(), "Incorrect master passphrase");
+    }
+
+    #[test]
+    fn test_rewrap_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        let old_key = create_key_file(&path, "correct horse", TEST_PARAMS).unwrap();
+        let new_key = generate_key();
+        write_key_file(&path, "correct horse", &new_key, TEST_PARAMS).unwrap();
+
+        assert_ne!(old_key, new_key);
+        assert_eq!(unlock_key_file(&path, "correct horse").unwrap(), new_key);
+    }
+
+    #[test]
+    fn test_rekeyed_key_file_keeps_params() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+        let staged_path = dir.path().join("key.new");
+        let params = KdfParams { m_cost: 512, t_cost: 3, p_cost: 2 };
+
+        create_key_file(&path, "correct horse", params).unwrap();
+        let new_key = generate_key();
+        write_rekeyed_key_file(&path, &staged_path, "correct horse", &new_key).unwrap();
+
+        let key_file: KeyFile = serde_json::from_str(&fs::read_to_string(&staged_path).unwrap()).unwrap();
+        assert_eq!(key_file.params, params);
+        assert_eq!(unlock_key_file(&staged_path, "correct horse").unwrap(), new_key);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_key_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("key");
+
+        create_key_file(&path, "correct horse", TEST_PARAMS).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let result = unlock_key_file(&path, "correct horse");
+        assert!(result.unwrap_err().to_string().contains("accessible by other users"));
+    }
+
+    #[test]
+    fn test_derive_key_depends_on_salt() {
+        let key1 = derive_key("passphrase", b"salt-one-16bytes", TEST_PARAMS).unwrap();
+        let key2 = derive_key("passphrase", b"salt-two-16bytes", TEST_PARAMS).unwrap();
+
+        assert_ne!(key1, key2);
+    }
+}
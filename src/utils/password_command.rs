@@ -0,0 +1,229 @@
+//! External password commands
+//!
+//! Instead of storing a password, a host can name a command (for example
+//! `pass show aliyun/prod`) whose first line of output is the password.
+//!
+//! On Unix the command runs in a process group of its own, so that a
+//! timeout also ends whatever it started. While it runs, that group holds
+//! the terminal, so the command can still prompt on it.
+
+use std::io::Read;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::secret::Secret;
+
+/// Default time a password command may take, including any passphrase
+/// prompt from tools like gpg
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Run `command` through the shell and return the first line of its stdout
+pub fn run_password_command(command: &str, timeout: Duration) -> Result<Secret, Box<dyn std::error::Error>> {
+    let mut child = shell_command(command)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("Failed to run password command '{}': {}", command, e))?;
+    #[cfg(unix)]
+    let foreground = Foreground::give(child.id() as libc::pid_t);
+
+    // Drain the pipes on separate threads so a chatty command cannot block
+    let mut stdout = child.stdout.take().ok_or("Failed to capture password command output")?;
+    let mut stderr = child.stderr.take().ok_or("Failed to capture password command output")?;
+    let stdout_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stdout.read_to_end(&mut buffer);
+        buffer
+    });
+    let stderr_reader = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        let _ = stderr.read_to_end(&mut buffer);
+        buffer
+    });
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if start.elapsed() >= timeout {
+            kill(&mut child);
+            let _ = child.wait();
+            return Err(format!(
+                "Password command '{}' timed out after {} seconds",
+                command, timeout.as_secs()
+            ).into());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+
+    #[cfg(unix)]
+    drop(foreground);
+
+    let stdout = stdout_reader.join().unwrap_or_default();
+    let stderr = stderr_reader.join().unwrap_or_default();
+
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let mut message = format!("Password command '{}' failed with {}", command, status);
+        if !stderr.trim().is_empty() {
+            message.push_str(&format!(": {}", stderr.trim()));
+        }
+        return Err(message.into());
+    }
+
+    let stdout = Secret::new(
+        String::from_utf8(stdout).map_err(|_| format!("Password command '{}' printed invalid UTF-8", command))?,
+    );
+    match stdout.expose().lines().next() {
+        Some(line) if !line.is_empty() => Ok(Secret::from(line)),
+        _ => Err(format!("Password command '{}' printed no password", command).into()),
+    }
+}
+
+#[cfg(unix)]
+fn shell_command(command: &str) -> Command {
+    use std::os::unix::process::CommandExt;
+
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command).process_group(0);
+    cmd
+}
+
+/// Kill the command and everything it started
+#[cfg(unix)]
+fn kill(child: &mut Child) {
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill(child: &mut Child) {
+    let _ = child.kill();
+}
+
+/// The terminal handed to the command's process group while it runs, as a
+/// shell does for a foreground job, and taken back when dropped
+#[cfg(unix)]
+struct Foreground {
+    tty: std::fs::File,
+    previous: libc::pid_t,
+}
+
+#[cfg(unix)]
+impl Foreground {
+    /// Does nothing without a terminal or when we are not in its foreground
+    fn give(group: libc::pid_t) -> Option<Foreground> {
+        use std::os::unix::io::AsRawFd;
+
+        let tty = std::fs::OpenOptions::new().read(true).write(true).open("/dev/tty").ok()?;
+        unsafe {
+            let previous = libc::tcgetpgrp(tty.as_raw_fd());
+            if previous != libc::getpgrp() || libc::tcsetpgrp(tty.as_raw_fd(), group) != 0 {
+                return None;
+            }
+            // It may have tried to read before it held the terminal
+            libc::kill(-group, libc::SIGCONT);
+            Some(Foreground { tty, previous })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Foreground {
+    fn drop(&mut self) {
+        use std::os::unix::io::AsRawFd;
+
+        // Taking the terminal back from the background raises SIGTTOU
+        unsafe {
+            let handler = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(self.tty.as_raw_fd(), self.previous);
+            libc::signal(libc::SIGTTOU, handler);
+        }
+    }
+}
+
+#[cfg(windows)]
+fn shell_command(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    fn write_script(dir: &Path, body: &str) -> PathBuf {
+        let path = dir.join("secret.sh");
+        fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_first_line_is_the_password() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = write_script(dir.path(), "echo 's3cret pass'\necho 'login: alice'");
+
+        let password = run_password_command(script.to_str().unwrap(), DEFAULT_TIMEOUT).unwrap();
+        assert_eq!(password.expose(), "s3cret pass");
+    }
+
+    #[test]
+    fn test_non_zero_exit_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = write_script(dir.path(), "echo 'entry not found' >&2\nexit 3");
+
+        let error = run_password_command(script.to_str().unwrap(), DEFAULT_TIMEOUT).unwrap_err().to_string();
+        assert!(error.contains("exit status: 3"), "{}", error);
+        assert!(error.contains("entry not found"), "{}", error);
+    }
+
+    #[test]
+    fn test_empty_output_is_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = write_script(dir.path(), "true");
+
+        assert!(run_password_command(script.to_str().unwrap(), DEFAULT_TIMEOUT).is_err());
+    }
+
+    #[test]
+    fn test_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let script = write_script(dir.path(), "sleep 5\necho late");
+
+        let start = Instant::now();
+        let error = run_password_command(script.to_str().unwrap(), Duration::from_millis(200)).unwrap_err();
+        assert!(error.to_string().contains("timed out"));
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_timeout_kills_what_the_command_started() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let script = write_script(dir.path(), &format!("sleep 30 &\necho $! > '{}'\nwait", pid_file.display()));
+
+        assert!(run_password_command(script.to_str().unwrap(), Duration::from_millis(300)).is_err());
+        let pid = fs::read_to_string(&pid_file).unwrap().trim().to_string();
+        // Gone, or a zombie waiting to be reaped, once the signal has landed
+        let dead = || {
+            let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap_or_default();
+            stat.is_empty() || stat.rsplit(") ").next().unwrap().starts_with('Z')
+        };
+        let start = Instant::now();
+        while !dead() && start.elapsed() < Duration::from_secs(2) {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert!(dead());
+    }
+}
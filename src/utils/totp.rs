@@ -0,0 +1,213 @@
+//! Time-based one-time passwords (RFC 6238)
+//!
+//! Secrets are accepted either as a bare base32 string or as an
+//! `otpauth://totp/...` URI as exported by authenticator apps.
+
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    pub secret: Vec<u8>,
+    pub algorithm: Algorithm,
+    pub digits: u32,
+    pub period: u64,
+}
+
+impl Totp {
+    /// Parse a base32 secret or an `otpauth://` URI
+    pub fn parse(value: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let value = value.trim();
+        if value.starts_with("otpauth://") {
+            Self::from_uri(value)
+        } else {
+            Self::from_secret(value)
+        }
+    }
+
+    /// Build a generator with the common defaults (SHA1, 6 digits, 30 seconds)
+    pub fn from_secret(secret: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Totp {
+            secret: decode_base32(secret)?,
+            algorithm: Algorithm::Sha1,
+            digits: 6,
+            period: 30,
+        })
+    }
+
+    /// Parse an `otpauth://totp/<label>?secret=...` URI
+    pub fn from_uri(uri: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let rest = uri.strip_prefix("otpauth://").ok_or("Not an otpauth:// URI")?;
+        let (kind, rest) = rest.split_once('/').ok_or("Malformed otpauth:// URI")?;
+        if !kind.eq_ignore_ascii_case("totp") {
+            return Err(format!("Unsupported OTP type '{}', only totp is supported", kind).into());
+        }
+
+        let query = rest.split_once('?').map(|(_, query)| query).unwrap_or("");
+        let mut secret = None;
+        let mut algorithm = Algorithm::Sha1;
+        let mut digits = 6;
+        let mut period = 30;
+
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match name.to_ascii_lowercase().as_str() {
+                "secret" => secret = Some(decode_base32(value)?),
+                "algorithm" => {
+                    algorithm = match value.to_ascii_uppercase().as_str() {
+                        "SHA1" => Algorithm::Sha1,
+                        "SHA256" => Algorithm::Sha256,
+                        "SHA512" => Algorithm::Sha512,
+                        other => return Err(format!("Unsupported OTP algorithm '{}'", other).into()),
+                    }
+                }
+                "digits" => digits = value.parse().map_err(|_| format!("Invalid digits '{}'", value))?,
+                "period" => period = value.parse().map_err(|_| format!("Invalid period '{}'", value))?,
+                _ => {}
+            }
+        }
+
+        if !(6..=10).contains(&digits) {
+            return Err(format!("Unsupported number of digits: {}", digits).into());
+        }
+        if period == 0 {
+            return Err("OTP period must be greater than zero".into());
+        }
+
+        Ok(Totp {
+            secret: secret.ok_or("otpauth:// URI has no secret")?,
+            algorithm,
+            digits,
+            period,
+        })
+    }
+
+    /// Generate the code for the given Unix time
+    pub fn generate(&self, unix_time: u64) -> String {
+        let counter = (unix_time / self.period).to_be_bytes();
+        let digest = match self.algorithm {
+            Algorithm::Sha1 => hmac_digest::<Hmac<Sha1>>(&self.secret, &counter),
+            Algorithm::Sha256 => hmac_digest::<Hmac<Sha256>>(&self.secret, &counter),
+            Algorithm::Sha512 => hmac_digest::<Hmac<Sha512>>(&self.secret, &counter),
+        };
+
+        // Dynamic truncation (RFC 4226 section 5.3)
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+        let code = binary as u64 % 10u64.pow(self.digits);
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+
+    /// Generate the code for the current time
+    pub fn now(&self) -> String {
+        self.generate(crate::utils::now())
+    }
+
+    /// Seconds until the current code expires
+    pub fn seconds_remaining(&self) -> u64 {
+        self.period - crate::utils::now() % self.period
+    }
+}
+
+fn hmac_digest<M: Mac + hmac::digest::KeyInit>(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = <M as hmac::digest::KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Decode an RFC 4648 base32 string, ignoring case, spaces and padding
+pub fn decode_base32(input: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut output = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in input.chars().filter(|c| !c.is_whitespace() && *c != '=' && *c != '-') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u32 - 'A' as u32,
+            c @ '2'..='7' => c as u32 - '2' as u32 + 26,
+            _ => return Err(format!("Invalid base32 character '{}' in TOTP secret", c).into()),
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            output.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    if output.is_empty() {
+        return Err("TOTP secret is empty".into());
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rfc_totp(secret: &[u8], algorithm: Algorithm) -> Totp {
+        Totp { secret: secret.to_vec(), algorithm, digits: 8, period: 30 }
+    }
+
+    #[test]
+    fn test_rfc6238_vectors() {
+        let sha1 = rfc_totp(b"12345678901234567890", Algorithm::Sha1);
+        let sha256 = rfc_totp(b"12345678901234567890123456789012", Algorithm::Sha256);
+        let sha512 = rfc_totp(
+            b"1234567890123456789012345678901234567890123456789012345678901234",
+            Algorithm::Sha512,
+        );
+
+        assert_eq!(sha1.generate(59), "94287082");
+        assert_eq!(sha1.generate(1111111109), "07081804");
+        assert_eq!(sha1.generate(20000000000), "65353130");
+        assert_eq!(sha256.generate(59), "46119246");
+        assert_eq!(sha512.generate(59), "90693936");
+    }
+
+    #[test]
+    fn test_decode_base32() {
+        assert_eq!(decode_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap(), b"12345678901234567890");
+        assert_eq!(decode_base32("gezd gnbv gy3t qojq gezd gnbv gy3t qojq").unwrap(), b"12345678901234567890");
+        assert!(decode_base32("not base32!").is_err());
+    }
+
+    #[test]
+    fn test_parse_otpauth_uri() {
+        let totp = Totp::parse(
+            "otpauth://totp/Aliyun:alice?secret=GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ&issuer=Aliyun&digits=8&period=30&algorithm=SHA1",
+        ).unwrap();
+
+        assert_eq!(totp, rfc_totp(b"12345678901234567890", Algorithm::Sha1));
+        assert_eq!(totp.generate(59), "94287082");
+    }
+
+    #[test]
+    fn test_parse_bare_secret_uses_defaults() {
+        let totp = Totp::parse("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ").unwrap();
+
+        assert_eq!(totp.digits, 6);
+        assert_eq!(totp.period, 30);
+        assert_eq!(totp.generate(59), "287082");
+    }
+
+    #[test]
+    fn test_parse_rejects_hotp_and_missing_secret() {
+        assert!(Totp::parse("otpauth://hotp/x?secret=GEZDGNBV&counter=1").is_err());
+        assert!(Totp::parse("otpauth://totp/x?issuer=Aliyun").is_err());
+    }
+}
@@ -0,0 +1,80 @@
+//! `ali-bastion proxy` runs as an ssh ProxyCommand: its stdout is the
+//! connection, so it must carry nothing but relayed bytes.
+
+#![cfg(unix)]
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+fn write_config(home: &Path, hosts: serde_json::Value) {
+    let dir = home.join(".ali-bastion");
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("config.json"), serde_json::json!({ "hosts": hosts }).to_string()).unwrap();
+}
+
+/// Run `proxy <name>` with `input` on stdin, detached from any terminal so
+/// passphrase prompts fail instead of waiting
+fn run_proxy(home: &Path, name: &str, input: &[u8]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_ali-bastion"));
+    command
+        .arg("proxy")
+        .arg(name)
+        .env("HOME", home)
+        .env_remove("SSH_ASKPASS")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    unsafe {
+        command.pre_exec(|| {
+            libc::setsid();
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_proxy_stdout_carries_only_relayed_bytes() {
+    // Echoes what it reads until the client stops sending
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        stream.write_all(&received).unwrap();
+    });
+
+    let home = tempfile::tempdir().unwrap();
+    write_config(home.path(), serde_json::json!({
+        "target": {"name": "target", "hostname": "127.0.0.1", "port": port, "username": "root"}
+    }));
+
+    let output = run_proxy(home.path(), "target", b"SSH-2.0-test\r\n");
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(output.stdout, b"SSH-2.0-test\r\n");
+}
+
+#[test]
+fn test_proxy_reports_credential_problems_on_stderr() {
+    // The hop has a stored password, but no vault key exists: setting one up
+    // prints a notice and asks for a passphrase, which fails without a tty
+    let home = tempfile::tempdir().unwrap();
+    write_config(home.path(), serde_json::json!({
+        "hop": {"name": "hop", "hostname": "192.0.2.1", "port": 22, "username": "root", "password": "v2:AAAA"},
+        "target": {"name": "target", "hostname": "10.0.0.1", "port": 22, "username": "root", "via": "hop"}
+    }));
+
+    let output = run_proxy(home.path(), "target", b"");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty(), "stdout: {:?}", String::from_utf8_lossy(&output.stdout));
+    assert!(stderr.contains("No master passphrase is set up yet"), "{}", stderr);
+}